@@ -0,0 +1,158 @@
+use crate::{
+    cache_keys::{ConfigKey, StorageKey},
+    storage::ActivityCounts,
+    EMBED_COLOUR, FOOTER_TEXT,
+};
+use anyhow::Result as AnyResult;
+use chrono::Utc;
+use chrono_tz::Tz;
+use serenity::{
+    http::Http,
+    model::{channel::Message, id::ChannelId},
+    prelude::{Context, RwLock, TypeMap},
+};
+use std::{cmp::Reverse, sync::Arc};
+
+/// What an activity report says if its task doesn't give a template.
+pub const DEFAULT_TEMPLATE: &str =
+    "{total} messages since {since}.\n\n**Busiest channels**\n{channels}\n\n**Most active**\n{users}";
+
+/// Counts a message towards the activity report, unless a bot sent it or it's somewhere that
+/// doesn't count.
+pub async fn record_message(ctx: &Context, message: &Message) -> AnyResult<()> {
+    if message.author.bot || message.guild_id.is_none() {
+        return Ok(());
+    }
+    let storage = {
+        let context_data = ctx.data.read().await;
+        let excluded = &context_data
+            .get::<ConfigKey>()
+            .unwrap()
+            .activity_excluded_channels;
+        if excluded.contains(&message.channel_id) {
+            return Ok(());
+        }
+        context_data.get::<StorageKey>().unwrap().clone()
+    };
+    storage.record_activity(message.channel_id, message.author.id, &message.author.name)
+}
+
+/// Posts how many messages were sent since the last report, and starts counting again. If the
+/// report can't be posted, the counts are kept for the next one.
+pub async fn post_report(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    send_to: ChannelId,
+    template: &str,
+    top: usize,
+) -> AnyResult<()> {
+    let (storage, tz) = {
+        let context_data = data.read().await;
+        (
+            context_data.get::<StorageKey>().unwrap().clone(),
+            context_data.get::<ConfigKey>().unwrap().time_zone,
+        )
+    };
+    let counts = storage.take_activity()?;
+    let report = render_report(template, &counts, top, tz);
+    let posted = send_report(http, send_to, report).await;
+    if posted.is_err() {
+        println!("AC | Failed to post activity report. Keeping the counts.");
+        storage.restore_activity(&counts)?;
+    } else {
+        println!("AC | Posted activity report.");
+    }
+    posted
+}
+
+async fn send_report(http: &Http, send_to: ChannelId, report: String) -> AnyResult<()> {
+    let icon_url = http.get_current_user().await?.avatar_url();
+    let _ = send_to
+        .send_message(http, |m| {
+            m.embed(|e| {
+                e.title("Activity report")
+                    .description(report)
+                    .colour(EMBED_COLOUR)
+                    .timestamp(Utc::now().to_rfc3339())
+                    .footer(|footer| {
+                        footer.text(FOOTER_TEXT);
+                        if let Some(icon_url) = icon_url {
+                            footer.icon_url(icon_url);
+                        }
+                        footer
+                    })
+            })
+        })
+        .await?;
+    Ok(())
+}
+
+/// Fills in the template's placeholders: `{total}`, `{since}`, and the `top` busiest
+/// `{channels}` and most active `{users}`.
+fn render_report(template: &str, counts: &ActivityCounts, top: usize, tz: Tz) -> String {
+    let total = counts.channels.iter().map(|(_, count)| count).sum::<u64>();
+    let since = counts.since.map_or_else(
+        || "the bot started counting".to_string(),
+        |since| {
+            since
+                .with_timezone(&tz)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        },
+    );
+    let mut channels = counts.channels.clone();
+    channels.sort_by_key(|&(channel, count)| (Reverse(count), channel));
+    let channels = ranked_lines(
+        channels
+            .into_iter()
+            .map(|(channel, count)| (format!("<#{channel}>"), count)),
+        top,
+    );
+    let mut users = counts.users.clone();
+    users.sort_by_key(|&(_, user, count)| (Reverse(count), user));
+    let users = ranked_lines(users.into_iter().map(|(name, _, count)| (name, count)), top);
+    template
+        .replace("{total}", &total.to_string())
+        .replace("{since}", &since)
+        .replace("{channels}", &channels)
+        .replace("{users}", &users)
+}
+
+fn ranked_lines(counts: impl Iterator<Item = (String, u64)>, top: usize) -> String {
+    let lines = counts
+        .take(top)
+        .enumerate()
+        .map(|(i, (name, count))| format!("**{}.** {name} - {count}", i + 1))
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        "Nobody.".to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::render_report;
+    use crate::storage::ActivityCounts;
+    use chrono::{TimeZone, Utc};
+    use chrono_tz::Tz;
+
+    #[test]
+    fn test_render_report() {
+        let counts = ActivityCounts {
+            since: Utc.with_ymd_and_hms(2022, 3, 1, 12, 0, 0).single(),
+            channels: vec![(1.into(), 4), (2.into(), 9), (3.into(), 1)],
+            users: vec![("alice".into(), 10.into(), 6), ("bob".into(), 20.into(), 8)],
+        };
+        assert_eq!(
+            render_report(
+                "{total} since {since}\n{channels}\n{users}",
+                &counts,
+                2,
+                Tz::UTC
+            ),
+            "14 since 2022-03-01 12:00\n**1.** <#2> - 9\n**2.** <#1> - 4\n**1.** bob - 8\n**2.** alice - 6"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::misc::escape_formatting;
+use crate::{invocation::Invocation, misc::escape_formatting};
 use rand::{seq::SliceRandom, thread_rng};
 use serenity::{
     framework::standard::{macros::command, CommandResult},
@@ -9,20 +9,25 @@ use std::time::Instant;
 
 #[command]
 pub async fn anagram(ctx: &Context, message: &Message) -> CommandResult {
+    run_anagram(ctx, &message.into()).await
+}
+
+pub async fn run_anagram(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("Received anagram command.");
     let start = Instant::now();
-    let unscrambled = message.content.trim_start_matches("=>anagram").trim();
+    let unscrambled = invocation.text("=>anagram", "text");
+    let unscrambled = unscrambled.as_str();
     println!("    Trimmed '=>anagram' from message.");
     if unscrambled.is_empty() {
-        let _ = message
-            .reply(
+        invocation
+            .reply_ephemeral(
                 &ctx.http,
                 "The fuck am I supposed to be scrambling, peasant?",
             )
             .await?;
     } else if unscrambled.len() == 1 {
-        let _ = message
-            .reply(
+        invocation
+            .reply_ephemeral(
                 &ctx.http,
                 "I am in awe of your incompetence if you need help scrambling that.",
             )
@@ -42,20 +47,17 @@ pub async fn anagram(ctx: &Context, message: &Message) -> CommandResult {
             );
             println!("    Formatted message.");
             if msg.len() < 2000 {
-                let _ = message
-                    .channel_id
-                    .send_message(&ctx.http, |m| m.content(&msg))
-                    .await?;
+                invocation.say(&ctx.http, &msg).await?;
             } else {
                 let reply = format!(
                     "That's {} characters too many. This incident will be recorded on your record.",
                     msg.len() - 2000
                 );
-                let _ = message.reply(&ctx.http, &reply).await?;
+                invocation.reply_ephemeral(&ctx.http, &reply).await?;
             }
         } else {
-            let _ = message
-                .reply(
+            invocation
+                .reply_ephemeral(
                     &ctx.http,
                     "I am in awe of your incompetence if you need help scrambling that.",
                 )
@@ -0,0 +1,381 @@
+use crate::{
+    cache_keys::{ConfigKey, ShardManagerKey, StorageKey},
+    config::Config,
+    tasks::template::{self, TemplateContext},
+};
+use anyhow::Result as AnyResult;
+use chrono::{Duration, Utc};
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    json::{JsonMap, Value},
+    model::{gateway::Activity, user::OnlineStatus},
+    prelude::{RwLock, TypeMap},
+    utils::read_image,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+/// Discord rejects avatars and banners bigger than this.
+const MAX_IMAGE_BYTES: u64 = 10 * 1024 * 1024;
+/// Discord only lets a bot change its avatar a couple of times in a short while. Going over that
+/// gets the request rejected, so changes beyond it are put off until the next time.
+pub const AVATAR_CHANGES_PER_WINDOW: usize = 2;
+pub fn avatar_change_window() -> Duration {
+    Duration::hours(1)
+}
+
+/// A look for the bot. Anything left out stays as it is.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Theme {
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// Image in the files directory.
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// Image in the files directory.
+    #[serde(default)]
+    pub banner: Option<String>,
+    #[serde(default)]
+    pub status: Option<Status>,
+    #[serde(default)]
+    pub activity: Option<ActivityKind>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Status {
+    Online,
+    Idle,
+    DoNotDisturb,
+    Invisible,
+}
+
+impl From<Status> for OnlineStatus {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Online => OnlineStatus::Online,
+            Status::Idle => OnlineStatus::Idle,
+            Status::DoNotDisturb => OnlineStatus::DoNotDisturb,
+            Status::Invisible => OnlineStatus::Invisible,
+        }
+    }
+}
+
+/// What the bot shows itself doing, and the text for it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum ActivityKind {
+    Playing(String),
+    Listening(String),
+    Watching(String),
+    Competing(String),
+}
+
+impl ActivityKind {
+    /// The text, which can have placeholders.
+    pub fn text(&self) -> &str {
+        match self {
+            ActivityKind::Playing(text)
+            | ActivityKind::Listening(text)
+            | ActivityKind::Watching(text)
+            | ActivityKind::Competing(text) => text,
+        }
+    }
+
+    /// The activity with its placeholders filled in.
+    pub fn to_activity(&self, context: &TemplateContext) -> Activity {
+        let text = template::render(self.text(), context);
+        match self {
+            ActivityKind::Playing(_) => Activity::playing(text),
+            ActivityKind::Listening(_) => Activity::listening(text),
+            ActivityKind::Watching(_) => Activity::watching(text),
+            ActivityKind::Competing(_) => Activity::competing(text),
+        }
+    }
+}
+
+/// The online status and activity the bot shows.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Presence {
+    #[serde(default = "default_status")]
+    pub status: Status,
+    #[serde(default)]
+    pub activity: Option<ActivityKind>,
+}
+
+fn default_status() -> Status {
+    Status::Online
+}
+
+impl Presence {
+    pub fn validate(&self) -> AnyResult<()> {
+        self.activity
+            .as_ref()
+            .map_or(Ok(()), |activity| template::validate(activity.text()))
+    }
+
+    /// The presence as Discord takes it, with placeholders filled in.
+    pub async fn resolve(
+        &self,
+        data: &Arc<RwLock<TypeMap>>,
+        http: &Http,
+    ) -> AnyResult<(Option<Activity>, OnlineStatus)> {
+        let activity = match &self.activity {
+            Some(activity) => {
+                let context = TemplateContext::gather(
+                    data,
+                    http,
+                    template::uses_member_count(activity.text()),
+                )
+                .await?;
+                Some(activity.to_activity(&context))
+            }
+            None => None,
+        };
+        Ok((activity, self.status.into()))
+    }
+
+    /// Sets the presence on every shard.
+    pub async fn apply(&self, data: &Arc<RwLock<TypeMap>>, http: &Http) -> AnyResult<()> {
+        let (activity, status) = self.resolve(data, http).await?;
+        println!(
+            "AP | Setting presence to {status:?}{}.",
+            activity
+                .as_ref()
+                .map_or(String::new(), |activity| format!(", '{}'", activity.name))
+        );
+        set_presence(data, activity, status).await;
+        Ok(())
+    }
+}
+
+/// Checks that a file in the files directory is an image Discord will take: that it's there,
+/// isn't too big and decodes as a PNG, JPEG, GIF or WebP image.
+pub fn check_image(files_dir: &str, filename: &str) -> AnyResult<()> {
+    let invalid = |why: String| -> AnyResult<()> {
+        Err(IoError::new(IoErrorKind::InvalidInput, why.as_str()).into())
+    };
+    let path = Path::new(files_dir).join(filename);
+    let size = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return invalid(format!("'{filename}' isn't in the files directory.")),
+    };
+    if size > MAX_IMAGE_BYTES {
+        return invalid(format!(
+            "'{filename}' is {size} bytes, but images can be at most {MAX_IMAGE_BYTES}."
+        ));
+    }
+    let reader = ImageReader::open(&path)?.with_guessed_format()?;
+    match reader.format() {
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP) => {}
+        _ => {
+            return invalid(format!(
+                "'{filename}' isn't a PNG, JPEG, GIF or WebP image."
+            ))
+        }
+    }
+    if let Err(err) = reader.decode() {
+        return invalid(format!("'{filename}' is damaged: {err}"));
+    }
+    Ok(())
+}
+
+/// Checks the default icon and presence and every theme, so that mistakes show up at startup
+/// rather than when a task tries to use them.
+pub fn validate_config(config: &Config) -> AnyResult<()> {
+    check_image(&config.files_dir, &config.icon_filename)?;
+    if let Some(presence) = &config.presence {
+        presence.validate()?;
+    }
+    for (name, theme) in &config.themes {
+        if let Some(activity) = &theme.activity {
+            template::validate(activity.text()).map_err(|why| {
+                IoError::new(IoErrorKind::InvalidInput, format!("Theme '{name}': {why}"))
+            })?;
+        }
+        for image in theme.avatar.iter().chain(theme.banner.iter()) {
+            check_image(&config.files_dir, image).map_err(|why| {
+                IoError::new(IoErrorKind::InvalidInput, format!("Theme '{name}': {why}"))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every theme named exists in the config.
+pub fn check_themes(themes: &BTreeMap<String, Theme>, names: &[String]) -> AnyResult<()> {
+    if let Some(missing) = names.iter().find(|name| !themes.contains_key(*name)) {
+        return Err(IoError::new(
+            IoErrorKind::InvalidInput,
+            format!("There's no theme called '{missing}' in the config.").as_str(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Changes the bot to look like a theme from the config.
+pub async fn apply_theme(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    name: &str,
+) -> AnyResult<AvatarChange> {
+    let theme = data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .themes
+        .get(name)
+        .cloned()
+        .ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::NotFound,
+                format!("There's no theme called '{name}' in the config.").as_str(),
+            )
+        })?;
+    println!("AP | Applying theme '{name}'.");
+    apply(data, http, &theme).await
+}
+
+/// Changes whatever the theme sets. Returns what came of changing the avatar, which is
+/// `Unchanged` for themes that don't set one.
+pub async fn apply(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    theme: &Theme,
+) -> AnyResult<AvatarChange> {
+    let (guild_id, files_dir) = {
+        let context_data = data.read().await;
+        let config = context_data.get::<ConfigKey>().unwrap();
+        (config.guild_id, config.files_dir.clone())
+    };
+    if let Some(nickname) = &theme.nickname {
+        http.edit_nickname(guild_id.into(), Some(nickname)).await?;
+    }
+    let avatar_change = match &theme.avatar {
+        Some(avatar) => set_avatar(data, http, avatar).await?,
+        None => AvatarChange::Unchanged,
+    };
+    if let Some(banner) = &theme.banner {
+        let mut map = JsonMap::new();
+        let _ = map.insert(
+            "banner".into(),
+            Value::String(read_image(Path::new(&files_dir).join(banner))?),
+        );
+        let _ = http.edit_profile(&map).await?;
+    }
+    if theme.status.is_some() || theme.activity.is_some() {
+        Presence {
+            status: theme.status.unwrap_or(Status::Online),
+            activity: theme.activity.clone(),
+        }
+        .apply(data, http)
+        .await?;
+    }
+    Ok(avatar_change)
+}
+
+async fn set_presence(
+    data: &Arc<RwLock<TypeMap>>,
+    activity: Option<Activity>,
+    status: OnlineStatus,
+) {
+    let shard_manager = data.read().await.get::<ShardManagerKey>().cloned();
+    if let Some(shard_manager) = shard_manager {
+        for runner in shard_manager.lock().await.runners.lock().await.values() {
+            runner.runner_tx.set_presence(activity.clone(), status);
+        }
+    }
+}
+
+/// What came of trying to change the avatar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AvatarChange {
+    Changed,
+    /// It was already that image.
+    Unchanged,
+    /// Discord wouldn't allow another change yet, so it has to be tried again later.
+    Deferred,
+}
+
+/// Changes the avatar to an image from the files directory, unless it's already that image or
+/// Discord wouldn't allow another change yet.
+pub async fn set_avatar(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    filename: &str,
+) -> AnyResult<AvatarChange> {
+    let (storage, files_dir) = {
+        let context_data = data.read().await;
+        (
+            context_data.get::<StorageKey>().unwrap().clone(),
+            context_data.get::<ConfigKey>().unwrap().files_dir.clone(),
+        )
+    };
+    if storage.current_avatar()?.as_deref() == Some(filename) {
+        println!("AP | Avatar is already '{filename}'.");
+        return Ok(AvatarChange::Unchanged);
+    }
+    let now = Utc::now();
+    let recent_changes = storage
+        .avatar_changes()?
+        .into_iter()
+        .filter(|&changed| now - changed < avatar_change_window())
+        .collect::<Vec<_>>();
+    if recent_changes.len() >= AVATAR_CHANGES_PER_WINDOW {
+        println!(
+            "AP | Avatar changed {} times in the last {} minutes. Not changing it to '{filename}' \
+             yet.",
+            recent_changes.len(),
+            avatar_change_window().num_minutes()
+        );
+        return Ok(AvatarChange::Deferred);
+    }
+    check_image(&files_dir, filename)?;
+    let avatar_b64 = read_image(Path::new(&files_dir).join(filename))?;
+    http.get_current_user()
+        .await?
+        .edit(http, |user| user.avatar(Some(&avatar_b64)))
+        .await?;
+    println!("AP | Changed avatar to '{filename}'.");
+    let mut changes = recent_changes;
+    changes.push(now);
+    storage.set_avatar_changes(&changes)?;
+    storage.set_current_avatar(filename)?;
+    Ok(AvatarChange::Changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_image, ActivityKind, Presence, Status};
+    use crate::misc::TestDir;
+    use image::RgbImage;
+    use std::fs;
+
+    #[test]
+    fn test_validation() {
+        let dir = TestDir::new("appearance");
+        RgbImage::new(2, 2).save(dir.file("icon.png")).unwrap();
+        let png = fs::read(dir.file("icon.png")).unwrap();
+        // Starts like a PNG, but the image data is cut off.
+        fs::write(dir.file("cut_off.png"), &png[..png.len() / 2]).unwrap();
+        fs::write(dir.file("icon.txt"), b"hello").unwrap();
+        let files_dir = dir.path();
+        assert!(check_image(files_dir, "icon.png").is_ok());
+        assert!(check_image(files_dir, "cut_off.png").is_err());
+        assert!(check_image(files_dir, "icon.txt").is_err());
+        assert!(check_image(files_dir, "missing.png").is_err());
+        let presence = |text: &str| Presence {
+            status: Status::Idle,
+            activity: Some(ActivityKind::Watching(text.into())),
+        };
+        assert!(presence("{gulag_count} prisoners").validate().is_ok());
+        assert!(presence("{prisoners}").validate().is_err());
+    }
+}
@@ -1,9 +1,16 @@
-use crate::{Config, TaskType};
-use crossbeam_channel::Sender as CbSender;
+use crate::{
+    leaderboard::{Leaderboard, LeaderboardId},
+    scheduler::TaskSender,
+    storage::Storage,
+    tasks::{TaskId, TaskType},
+    Config,
+};
 use serenity::{
+    client::bridge::gateway::ShardManager,
     model::{guild::Role, id::UserId},
     prelude::*,
 };
+use std::{collections::BTreeMap, sync::Arc};
 
 pub struct AdminRolesKey;
 
@@ -41,20 +48,44 @@ impl TypeMapKey for NitroRoleKey {
     type Value = Role;
 }
 
+pub struct LeaderboardsKey;
+
+impl TypeMapKey for LeaderboardsKey {
+    type Value = BTreeMap<LeaderboardId, Leaderboard>;
+}
+
 pub struct ReadyKey;
 
 impl TypeMapKey for ReadyKey {
     type Value = bool;
 }
 
+pub struct StorageKey;
+
+impl TypeMapKey for StorageKey {
+    type Value = Storage;
+}
+
 pub struct TasksKey;
 
 impl TypeMapKey for TasksKey {
-    type Value = Vec<TaskType>;
+    type Value = BTreeMap<TaskId, TaskType>;
+}
+
+pub struct ShardManagerKey;
+
+impl TypeMapKey for ShardManagerKey {
+    type Value = Arc<Mutex<ShardManager>>;
+}
+
+pub struct ShuttingDownKey;
+
+impl TypeMapKey for ShuttingDownKey {
+    type Value = bool;
 }
 
 pub struct TaskSenderKey;
 
 impl TypeMapKey for TaskSenderKey {
-    type Value = CbSender<TaskType>;
+    type Value = TaskSender;
 }
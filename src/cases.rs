@@ -0,0 +1,242 @@
+use crate::{
+    cache_keys::StorageKey,
+    escalation::is_permanent,
+    invocation::Invocation,
+    misc::{insufficient_perms, is_administrator, ClapResult},
+    EMBED_COLOUR, FOOTER_TEXT,
+};
+use chrono::{DateTime, Utc};
+use clap::{error::ErrorKind, ColorChoice, Parser};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    builder::CreateEmbed,
+    framework::standard::{macros::command, CommandResult},
+    model::{channel::Message, id::UserId},
+    prelude::Context,
+};
+use std::time::Instant;
+
+// Keeps the history within what fits in an embed.
+const MAX_LISTED_CASES: usize = 20;
+
+/// The record of a gulag sentence. Unlike the sentence itself it's kept after the prisoner is
+/// released, so that moderators can look back at what happened.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Case {
+    pub user: (String, UserId),
+    /// Who handed out the sentence.
+    pub moderator: (String, UserId),
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub issued_at: DateTime<Utc>,
+    /// When the sentence ends, or ended if it was cut short.
+    pub end: DateTime<Utc>,
+    #[serde(default)]
+    pub released_at: Option<DateTime<Utc>>,
+    /// Who ended the sentence early, if anyone did.
+    #[serde(default)]
+    pub released_by: Option<(String, UserId)>,
+}
+
+impl Case {
+    pub fn new(
+        user: (String, UserId),
+        moderator: (String, UserId),
+        reason: Option<String>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        Case {
+            user,
+            moderator,
+            reason,
+            issued_at: Utc::now(),
+            end,
+            released_at: None,
+            released_by: None,
+        }
+    }
+
+    fn status(&self) -> String {
+        match (&self.released_at, &self.released_by) {
+            (Some(released_at), Some((name, id))) => format!(
+                "Released early by {name} (ID: {id}) <t:{}:R>",
+                released_at.timestamp()
+            ),
+            (Some(released_at), None) => format!("Released <t:{}:R>", released_at.timestamp()),
+            (None, _) if is_permanent(self.end) => "Serving permanently".into(),
+            (None, _) => format!(
+                "Serving until <t:{end}> (<t:{end}:R>)",
+                end = self.end.timestamp()
+            ),
+        }
+    }
+
+    pub fn embed(&self, number: u64) -> CreateEmbed {
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(format!("Case {number}"))
+            .colour(EMBED_COLOUR)
+            .field(
+                "Prisoner",
+                format!("{} (ID: {})", self.user.0, self.user.1),
+                true,
+            )
+            .field(
+                "Moderator",
+                format!("{} (ID: {})", self.moderator.0, self.moderator.1),
+                true,
+            )
+            .field(
+                "Reason",
+                self.reason.as_deref().unwrap_or("None given"),
+                false,
+            )
+            .field(
+                "Sentenced",
+                format!("<t:{}>", self.issued_at.timestamp()),
+                true,
+            )
+            .field("Status", self.status(), true)
+            .footer(|f| f.text(FOOTER_TEXT));
+        embed
+    }
+
+    pub fn list_fmt(&self, number: u64) -> String {
+        format!(
+            "**{number}.** <t:{}:d> | {} | {}",
+            self.issued_at.timestamp(),
+            self.reason.as_deref().unwrap_or("No reason given"),
+            self.status(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "History", color(ColorChoice::Never), no_binary_name(true))]
+pub(crate) struct HistoryApp {
+    /// User to show the gulag history of
+    #[arg(short = 'u', long = "user", name = "user")]
+    user: UserId,
+}
+
+fn try_get_history_info(args: Vec<String>) -> ClapResult<HistoryApp> {
+    println!("CS | Parsing history command use from {args:?}");
+    HistoryApp::try_parse_from(args)
+}
+
+#[command]
+pub async fn case(ctx: &Context, message: &Message) -> CommandResult {
+    run_case(ctx, &message.into()).await
+}
+
+pub async fn run_case(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("CS | Start handling case command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let text = invocation.text("=>case", "number");
+        let number = match text.trim_start_matches('#').parse::<u64>() {
+            Ok(number) => number,
+            Err(_) => {
+                println!("CS | '{text}' isn't a case number.");
+                invocation
+                    .reply_ephemeral(&ctx.http, "Give the number of a case, like `=>case 12`.")
+                    .await?;
+                println!("CS | Elapsed: {:?}", start.elapsed());
+                return Ok(());
+            }
+        };
+        let case = ctx
+            .data
+            .read()
+            .await
+            .get::<StorageKey>()
+            .unwrap()
+            .case(number)?;
+        match case {
+            Some(case) => {
+                println!("CS | Sending case {number}.");
+                invocation.send_embed(&ctx.http, case.embed(number)).await?;
+            }
+            None => {
+                println!("CS | No case {number}.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("There's no case {number}."))
+                    .await?;
+            }
+        }
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("CS | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[command]
+pub async fn history(ctx: &Context, message: &Message) -> CommandResult {
+    run_history(ctx, &message.into()).await
+}
+
+pub async fn run_history(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("CS | Start handling history command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let HistoryApp { user } = match try_get_history_info(invocation.args("=>history")) {
+            Ok(args) => args,
+            Err(err) if err.kind() == ErrorKind::DisplayHelp => {
+                println!("CS | User requested help.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("```{err}```"))
+                    .await?;
+                println!("CS | Elapsed: {:?}", start.elapsed());
+                return Ok(());
+            }
+            Err(err) => {
+                println!("CS | Failed to parse user input. Sending error back.");
+                invocation
+                    .reply_ephemeral(
+                        &ctx.http,
+                        format!("Error parsing command. Details:\n```{err}```"),
+                    )
+                    .await?;
+                println!("CS | Elapsed: {:?}", start.elapsed());
+                return Err(err.into());
+            }
+        };
+        let cases = ctx
+            .data
+            .read()
+            .await
+            .get::<StorageKey>()
+            .unwrap()
+            .cases_for(user)?;
+        println!("CS | Found {} cases for user {user}.", cases.len());
+        let shown = &cases[cases.len().saturating_sub(MAX_LISTED_CASES)..];
+        let mut msg = shown
+            .iter()
+            .map(|(number, case)| case.list_fmt(*number))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if cases.is_empty() {
+            msg.push_str("No cases on record.");
+        } else if shown.len() < cases.len() {
+            msg.insert_str(
+                0,
+                &format!("{} older cases not shown.\n", cases.len() - shown.len()),
+            );
+        }
+        let name = cases
+            .last()
+            .map_or_else(|| user.to_string(), |(_, case)| case.user.0.clone());
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(format!("Gulag history of {name}"))
+            .colour(EMBED_COLOUR)
+            .description(msg)
+            .footer(|f| f.text(FOOTER_TEXT));
+        invocation.send_embed(&ctx.http, embed).await?;
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("CS | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
@@ -1,10 +1,21 @@
+use crate::{
+    appearance::{Presence, Theme},
+    escalation::EscalationPolicy,
+    hall_of_fame::HallOfFame,
+};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use serenity::model::id::{GuildId, RoleId};
-use std::default::Default;
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use std::{collections::BTreeMap, default::Default};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub tasks_file: String,
+    #[serde(default = "default_database_path")]
+    pub database_path: String,
+    /// IANA name of the time zone that schedules are evaluated in unless a task says otherwise.
+    #[serde(default = "default_time_zone")]
+    pub time_zone: Tz,
     pub bot_id: String,
     pub files_dir: String,
     pub icon_filename: String,
@@ -16,12 +27,47 @@ pub struct Config {
     pub prisoner_role_id: RoleId,
     pub nitro_role_name: String,
     pub nitro_role_id: RoleId,
+    /// Where to repost messages that get a lot of reactions, if anywhere.
+    #[serde(default)]
+    pub hall_of_fame: Option<HallOfFame>,
+    /// Channels whose messages don't count towards activity reports.
+    #[serde(default)]
+    pub activity_excluded_channels: Vec<ChannelId>,
+    /// Looks the bot can be given by name, for appearance tasks.
+    #[serde(default)]
+    pub themes: BTreeMap<String, Theme>,
+    /// What the bot shows as its status when it starts.
+    #[serde(default)]
+    pub presence: Option<Presence>,
+    /// Where to post when someone is sent to or released from gulag, if anywhere.
+    #[serde(default)]
+    pub mod_log_channel: Option<ChannelId>,
+    /// Minutes added to the sentence of a prisoner who leaves and rejoins the server.
+    #[serde(default)]
+    pub rejoin_penalty_mins: i64,
+    /// Whether removing the prisoner role from someone by hand releases them, rather than the
+    /// role being given back.
+    #[serde(default)]
+    pub release_on_prisoner_role_removal: bool,
+    /// Sentence lengths the gulag command falls back on when it isn't given one.
+    #[serde(default)]
+    pub escalation: Option<EscalationPolicy>,
+}
+
+fn default_database_path() -> String {
+    "velvet_db".into()
+}
+
+fn default_time_zone() -> Tz {
+    Tz::UTC
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             tasks_file: String::new(),
+            database_path: default_database_path(),
+            time_zone: default_time_zone(),
             bot_id: String::new(),
             files_dir: "files".into(),
             icon_filename: "default.png".into(),
@@ -33,6 +79,14 @@ impl Default for Config {
             prisoner_role_id: 0.into(),
             nitro_role_name: String::new(),
             nitro_role_id: 0.into(),
+            hall_of_fame: None,
+            activity_excluded_channels: Vec::new(),
+            themes: BTreeMap::new(),
+            presence: None,
+            mod_log_channel: None,
+            rejoin_penalty_mins: 0,
+            release_on_prisoner_role_removal: false,
+            escalation: None,
         }
     }
 }
@@ -1,9 +1,10 @@
 use crate::{
     cache_keys::TasksKey,
+    invocation::Invocation,
     misc::{insufficient_perms, is_administrator},
-    tasks::TaskType,
 };
 use serenity::{
+    builder::CreateEmbed,
     framework::standard::{macros::command, CommandResult},
     model::prelude::*,
     prelude::*,
@@ -14,12 +15,16 @@ use std::time::Instant;
 #[command]
 #[aliases("current-gulags")]
 pub async fn current_gulags(ctx: &Context, message: &Message) -> CommandResult {
+    run_current_gulags(ctx, &message.into()).await
+}
+
+pub async fn run_current_gulags(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("CG | Start handling current-gulags command.");
     let start = Instant::now();
     println!("CG | Grabbing read 'lock' on context data.");
     let context_data = ctx.data.read().await;
     println!("CG | Checking permissions.");
-    if is_administrator(&ctx.http, context_data, message).await? {
+    if is_administrator(&ctx.http, context_data, invocation).await? {
         println!("CG | User has sufficient permissions.");
         let mut msg = String::new();
         ctx.data
@@ -28,10 +33,9 @@ pub async fn current_gulags(ctx: &Context, message: &Message) -> CommandResult {
             .get::<TasksKey>()
             .unwrap()
             .iter()
-            .filter_map(TaskType::gulag_ref)
-            .enumerate()
-            .for_each(|(i, gulag)| {
-                msg.push_str(&format!("{i}: {gulag}"));
+            .filter_map(|(id, task)| task.gulag_ref().map(|gulag| (id, gulag)))
+            .for_each(|(id, gulag)| {
+                msg.push_str(&format!("{id}: {gulag}"));
                 msg.push('\n');
             });
         let msg = if msg.is_empty() {
@@ -43,23 +47,19 @@ pub async fn current_gulags(ctx: &Context, message: &Message) -> CommandResult {
         println!("CG | Formatted sentences:\n{}", msg);
         let icon_url = ctx.http.get_current_user().await?.avatar_url().unwrap();
         println!("CG | Retrieved icon URL.");
-        let _ = message
-            .channel_id
-            .send_message(&ctx.http, |m| {
-                m.embed(|e| {
-                    e.title("Prisoner List")
-                        .colour(Colour::from_rgb(243, 44, 115))
-                        .field("Report from the tundra", msg, false)
-                        .footer(|f| {
-                            f.text("Your friendly, neighbourhood gulag officer, Officer Velvet")
-                                .icon_url(icon_url)
-                        })
-                })
-            })
-            .await?;
+        let mut embed = CreateEmbed::default();
+        embed
+            .title("Prisoner List")
+            .colour(Colour::from_rgb(243, 44, 115))
+            .field("Report from the tundra", msg, false)
+            .footer(|f| {
+                f.text("Your friendly, neighbourhood gulag officer, Officer Velvet")
+                    .icon_url(icon_url)
+            });
+        invocation.send_embed(&ctx.http, embed).await?;
         println!("CG | Sent gulags list.");
     } else {
-        insufficient_perms(ctx, message).await?;
+        insufficient_perms(ctx, invocation).await?;
     }
     println!("CG | Elapsed: {:?}", start.elapsed());
     Ok(())
@@ -0,0 +1,186 @@
+use crate::{
+    cache_keys::{ConfigKey, StorageKey},
+    invocation::Invocation,
+    misc::{fmt_local_and_utc, insufficient_perms, is_administrator, ClapResult},
+    tasks::{TaskId, TaskType},
+};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{error::ErrorKind, ColorChoice, Parser};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    framework::standard::{macros::command, CommandResult},
+    model::channel::Message,
+    prelude::Context,
+};
+use std::time::Instant;
+
+/// A task that kept failing to act after being retried, kept so that admins can look into it and
+/// run it again once whatever was wrong has been fixed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeadLetter {
+    /// ID the task had in the task list.
+    pub task_id: TaskId,
+    /// The task as it was before the failed attempts.
+    pub task: TaskType,
+    /// Error from the latest attempt.
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    pub fn list_fmt(&self, tz: Tz) -> String {
+        format!(
+            "Task {} | Failed {} times, last at {} | {}\n    {}",
+            self.task_id,
+            self.attempts,
+            fmt_local_and_utc(self.failed_at, tz),
+            self.error,
+            self.task.list_fmt(tz).trim(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Rerun Dead Letter",
+    color(ColorChoice::Never),
+    no_binary_name(true)
+)]
+pub(crate) struct RerunDeadLetterApp {
+    /// ID of the entry in the dead letter list
+    #[arg(long = "id", name = "id")]
+    pub id: u64,
+    /// Remove the entry without running it
+    #[arg(long = "discard")]
+    pub discard: bool,
+}
+
+#[command]
+#[aliases("dead-letters")]
+pub async fn dead_letters(ctx: &Context, message: &Message) -> CommandResult {
+    run_dead_letters(ctx, &message.into()).await
+}
+
+pub async fn run_dead_letters(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("DL | Start handling dead letters command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let context_data = ctx.data.read().await;
+        let tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+        let dead_letters = context_data
+            .get::<StorageKey>()
+            .unwrap()
+            .load_dead_letters()?;
+        drop(context_data);
+        let msg = if dead_letters.is_empty() {
+            "No tasks have failed for good.".into()
+        } else {
+            let mut msg = "Tasks that failed for good:\n```".to_string();
+            for (id, dead_letter) in &dead_letters {
+                msg.push_str(&format!("\n{id}: {}", dead_letter.list_fmt(tz)));
+            }
+            msg.push_str("\n```");
+            msg
+        };
+        println!("DL | Sending {} dead letters.", dead_letters.len());
+        invocation.say(&ctx.http, msg).await?;
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("DL | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+fn try_get_rerun_info(args: Vec<String>) -> ClapResult<RerunDeadLetterApp> {
+    println!("RD | Parsing rerun dead letter command use from {args:?}");
+    RerunDeadLetterApp::try_parse_from(args)
+}
+
+#[command]
+#[aliases("rerun-dead-letter")]
+pub async fn rerun_dead_letter(ctx: &Context, message: &Message) -> CommandResult {
+    run_rerun_dead_letter(ctx, &message.into()).await
+}
+
+pub async fn run_rerun_dead_letter(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("RD | Start handling rerun dead letter command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let RerunDeadLetterApp { id, discard } =
+            match try_get_rerun_info(invocation.args("=>rerun_dead_letter")) {
+                Ok(args) => args,
+                Err(err) if err.kind() == ErrorKind::DisplayHelp => {
+                    println!("RD | User requested help.");
+                    invocation
+                        .reply_ephemeral(&ctx.http, format!("```{err}```"))
+                        .await?;
+                    println!("RD | Elapsed: {:?}", start.elapsed());
+                    return Ok(());
+                }
+                Err(err) => {
+                    println!("RD | Failed to parse user input. Sending error back.");
+                    invocation
+                        .reply_ephemeral(
+                            &ctx.http,
+                            format!("Error parsing command. Details:\n```{err}```"),
+                        )
+                        .await?;
+                    println!("RD | Elapsed: {:?}", start.elapsed());
+                    return Err(err.into());
+                }
+            };
+        let storage = ctx.data.read().await.get::<StorageKey>().unwrap().clone();
+        let mut dead_letter = match storage.load_dead_letters()?.remove(&id) {
+            Some(dead_letter) => dead_letter,
+            None => {
+                println!("RD | No dead letter with ID {id}.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("There's no dead letter with ID {id}."))
+                    .await?;
+                println!("RD | Elapsed: {:?}", start.elapsed());
+                return Ok(());
+            }
+        };
+        if discard {
+            storage.remove_dead_letter(id)?;
+            println!("RD | Discarded dead letter {id}.");
+            invocation
+                .reply(&ctx.http, format!("Discarded dead letter {id}."))
+                .await?;
+        } else {
+            // Act on a copy. The task list has already moved on from the run that failed, so this
+            // only makes up for that one run.
+            invocation.defer(&ctx.http).await?;
+            let mut task = dead_letter.task.clone();
+            match task.act(&ctx.data, &ctx.http).await {
+                Ok(()) => {
+                    storage.remove_dead_letter(id)?;
+                    println!("RD | Reran dead letter {id}.");
+                    invocation
+                        .reply(&ctx.http, format!("Reran dead letter {id} successfully."))
+                        .await?;
+                }
+                Err(err) => {
+                    println!("RD | Rerunning dead letter {id} failed: {err}");
+                    dead_letter.error = err.to_string();
+                    dead_letter.attempts += 1;
+                    dead_letter.failed_at = Utc::now();
+                    storage.update_dead_letter(id, &dead_letter)?;
+                    invocation
+                        .reply_ephemeral(
+                            &ctx.http,
+                            format!("Rerunning dead letter {id} failed again:\n```{err}```"),
+                        )
+                        .await?;
+                }
+            }
+        }
+        storage.flush().await?;
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("RD | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
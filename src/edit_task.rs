@@ -0,0 +1,485 @@
+use crate::{
+    cache_keys::{ConfigKey, StorageKey, TasksKey},
+    invocation::Invocation,
+    misc::{insufficient_perms, is_administrator, ClapResult, CreateTimePeriod},
+    scheduler::task_changed,
+    tasks::{
+        cron_task::CronTask, date_conditional_task::DateCondition, misfire::MisfirePolicy,
+        task::Task, TaskId, TaskType,
+    },
+};
+use anyhow::Result as AnyResult;
+use chrono_tz::Tz;
+use clap::{error::ErrorKind, ArgAction, ColorChoice, Parser, Subcommand};
+use serenity::{
+    framework::standard::{macros::command, CommandResult},
+    model::channel::Message,
+    prelude::Context,
+};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    time::Instant,
+};
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "Task ID", color(ColorChoice::Never), no_binary_name(true))]
+pub(crate) struct TaskIdApp {
+    /// ID of the task, as shown by `list_tasks`
+    #[arg(short = 'i', long = "id", name = "id")]
+    id: TaskId,
+}
+
+#[derive(Parser)]
+#[command(name = "Edit Task", color(ColorChoice::Never), no_binary_name(true))]
+pub(crate) struct EditTask {
+    #[command(subcommand)]
+    edit: EditTaskType,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub(crate) enum EditTaskType {
+    /// Change how often a periodic task acts
+    #[command(name = "period")]
+    Period(EditPeriod),
+    /// Change when a date conditional task acts
+    #[command(name = "condition")]
+    Condition(EditCondition),
+    /// Change the cron expression of a cron task
+    #[command(name = "schedule")]
+    Schedule(EditSchedule),
+    /// Change what a task does about runs missed while offline
+    #[command(name = "misfire")]
+    Misfire(EditMisfire),
+    /// Replace what a task does with new task JSON
+    #[command(name = "task")]
+    Task(TaskIdApp),
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Edit Period",
+    color(ColorChoice::Never),
+    no_binary_name(true),
+    disable_help_flag(true)
+)]
+pub(crate) struct EditPeriod {
+    /// ID of the task, as shown by `list_tasks`
+    #[arg(short = 'i', long = "id", name = "id")]
+    id: TaskId,
+    #[command(flatten)]
+    period: CreateTimePeriod,
+    // `-h` is taken by `--hours`.
+    /// Print help
+    #[arg(long = "help", action = ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Edit Condition",
+    color(ColorChoice::Never),
+    no_binary_name(true)
+)]
+pub(crate) struct EditCondition {
+    /// ID of the task, as shown by `list_tasks`
+    #[arg(short = 'i', long = "id", name = "id")]
+    id: TaskId,
+    #[command(flatten)]
+    condition: DateCondition,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Edit Schedule",
+    color(ColorChoice::Never),
+    no_binary_name(true)
+)]
+pub(crate) struct EditSchedule {
+    /// ID of the task, as shown by `list_tasks`
+    #[arg(short = 'i', long = "id", name = "id")]
+    id: TaskId,
+    /// Cron expression: [second] minute hour day-of-month month day-of-week
+    #[arg(short = 'c', long = "schedule", name = "schedule", num_args(1..=6), required(true))]
+    schedule: Vec<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "Edit Misfire", color(ColorChoice::Never), no_binary_name(true))]
+pub(crate) struct EditMisfire {
+    /// ID of the task, as shown by `list_tasks`
+    #[arg(short = 'i', long = "id", name = "id")]
+    id: TaskId,
+    /// skip, once, all, or grace:<time> to run once if the last one was missed by at most that
+    /// long (e.g. grace:30m)
+    #[arg(long = "misfire", name = "misfire_policy")]
+    misfire_policy: MisfirePolicy,
+}
+
+impl EditTaskType {
+    fn id(&self) -> TaskId {
+        match self {
+            EditTaskType::Period(EditPeriod { id, .. })
+            | EditTaskType::Condition(EditCondition { id, .. })
+            | EditTaskType::Schedule(EditSchedule { id, .. })
+            | EditTaskType::Misfire(EditMisfire { id, .. })
+            | EditTaskType::Task(TaskIdApp { id }) => *id,
+        }
+    }
+
+    /// Makes the edit. `task` is the replacement for a task edit, already checked.
+    fn apply(self, task_type: &mut TaskType, task: Option<Task>, default_tz: Tz) -> AnyResult<()> {
+        match (self, task_type) {
+            (EditTaskType::Period(EditPeriod { period, .. }), TaskType::PeriodicTask(pt)) => {
+                let diff = period
+                    .to_duration(pt.time_zone.unwrap_or(default_tz))
+                    .num_seconds();
+                // A period that doesn't move forward would never catch up with the present.
+                if diff <= 0 {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidInput,
+                        "The period has to be positive.",
+                    )
+                    .into());
+                }
+                pt.diff = diff;
+                Ok(())
+            }
+            (
+                EditTaskType::Condition(EditCondition { condition, .. }),
+                TaskType::DateConditionalTask(dct),
+            ) => {
+                dct.condition = condition;
+                Ok(())
+            }
+            (EditTaskType::Schedule(EditSchedule { schedule, .. }), TaskType::CronTask(ct)) => {
+                let paused = ct.paused;
+                let misfire_policy = ct.misfire_policy;
+                *ct = CronTask::new(
+                    ct.task.clone(),
+                    schedule.join(" ").parse()?,
+                    ct.time_zone,
+                    default_tz,
+                )?;
+                ct.paused = paused;
+                ct.misfire_policy = misfire_policy;
+                Ok(())
+            }
+            (EditTaskType::Misfire(EditMisfire { misfire_policy, .. }), task_type)
+                if !task_type.is_gulag() =>
+            {
+                *task_type.misfire_policy_mut().unwrap() = misfire_policy;
+                Ok(())
+            }
+            (EditTaskType::Task(_), task_type) if !task_type.is_gulag() => {
+                let task = task.ok_or_else(|| {
+                    IoError::new(IoErrorKind::InvalidInput, "No task JSON was given.")
+                })?;
+                *task_type.task_mut().unwrap() = task;
+                Ok(())
+            }
+            (edit, task_type) => Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "Can't edit the {} of that kind of task: {}",
+                    match edit {
+                        EditTaskType::Period(_) => "period",
+                        EditTaskType::Condition(_) => "condition",
+                        EditTaskType::Schedule(_) => "schedule",
+                        EditTaskType::Misfire(_) => "misfire policy",
+                        EditTaskType::Task(_) => "task",
+                    },
+                    task_type.list_fmt(default_tz).trim(),
+                )
+                .as_str(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Runs `update` on the task with the given ID and saves the result. Returns the updated task's
+/// list entry, or `None` if there's no task with that ID. `update` also gets the configured time
+/// zone.
+async fn update_task<F>(ctx: &Context, id: TaskId, update: F) -> AnyResult<Option<String>>
+where
+    F: FnOnce(&mut TaskType, Tz) -> AnyResult<()>,
+{
+    let mut context_data = ctx.data.write().await;
+    let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+    let tasks = context_data.get_mut::<TasksKey>().unwrap();
+    let task = match tasks.get_mut(&id) {
+        Some(task) => task,
+        None => return Ok(None),
+    };
+    update(task, default_tz)?;
+    let task = task.clone();
+    context_data
+        .get::<StorageKey>()
+        .unwrap()
+        .update_task(id, &task)?;
+    task_changed(&context_data, id);
+    Ok(Some(task.list_fmt(default_tz)))
+}
+
+async fn reply_parse_error(
+    ctx: &Context,
+    invocation: &Invocation<'_>,
+    err: clap::Error,
+) -> CommandResult {
+    if err.kind() == ErrorKind::DisplayHelp {
+        println!("ET | User requested help.");
+        invocation
+            .reply_ephemeral(&ctx.http, format!("```{err}```"))
+            .await?;
+        Ok(())
+    } else {
+        println!("ET | Failed to parse user input. Sending error back.");
+        invocation
+            .reply_ephemeral(
+                &ctx.http,
+                format!("Error parsing command. Details:\n```{err}```"),
+            )
+            .await?;
+        Err(err.into())
+    }
+}
+
+fn try_get_task_id(args: Vec<String>) -> ClapResult<TaskId> {
+    TaskIdApp::try_parse_from(args).map(|app| app.id)
+}
+
+#[command]
+#[aliases("edit-task")]
+pub async fn edit_task(ctx: &Context, message: &Message) -> CommandResult {
+    run_edit_task(ctx, &message.into()).await
+}
+
+pub async fn run_edit_task(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("ET | Start handling edit task command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let (args, json) = invocation.args_and_json("=>edit_task");
+        println!("ET | Parsing input: {args:?}");
+        let edit = match EditTask::try_parse_from(args) {
+            Ok(EditTask { edit }) => edit,
+            Err(err) => {
+                let result = reply_parse_error(ctx, invocation, err).await;
+                println!("ET | Elapsed: {:?}", start.elapsed());
+                return result;
+            }
+        };
+        let id = edit.id();
+        println!("ET | Editing task {id}: {edit:?}");
+        let task = match (&edit, json.as_deref()) {
+            (EditTaskType::Task(_), Some(json)) => {
+                let context_data = ctx.data.read().await;
+                let config = context_data.get::<ConfigKey>().unwrap();
+                let checked = serde_json::from_str::<Task>(json)
+                    .map_err(Into::into)
+                    .and_then(|task| task.check(config).map(|()| task));
+                drop(context_data);
+                match checked {
+                    Ok(task) => Some(task),
+                    Err(err) => {
+                        println!("ET | Replacement task failed validation.");
+                        invocation
+                            .reply_ephemeral(&ctx.http, format!("Invalid task: {err}"))
+                            .await?;
+                        println!("ET | Elapsed: {:?}", start.elapsed());
+                        return Err(err.into());
+                    }
+                }
+            }
+            _ => None,
+        };
+        match update_task(ctx, id, |task_type, tz| edit.apply(task_type, task, tz)).await {
+            Ok(Some(list_entry)) => {
+                println!("ET | Updated task {id}.");
+                invocation
+                    .reply(
+                        &ctx.http,
+                        format!("Updated task:\n```{id}: {list_entry}```"),
+                    )
+                    .await?;
+            }
+            Ok(None) => {
+                println!("ET | No task with ID {id}.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("There's no task with ID {id}."))
+                    .await?;
+            }
+            Err(err) => {
+                println!("ET | Failed to edit task {id}: {err}");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("Couldn't edit that task:\n```{err}```"))
+                    .await?;
+                println!("ET | Elapsed: {:?}", start.elapsed());
+                return Err(err.into());
+            }
+        }
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("ET | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[command]
+#[aliases("delete-task")]
+pub async fn delete_task(ctx: &Context, message: &Message) -> CommandResult {
+    run_delete_task(ctx, &message.into()).await
+}
+
+pub async fn run_delete_task(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("DT | Start handling delete task command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let id = match try_get_task_id(invocation.args("=>delete_task")) {
+            Ok(id) => id,
+            Err(err) => {
+                let result = reply_parse_error(ctx, invocation, err).await;
+                println!("DT | Elapsed: {:?}", start.elapsed());
+                return result;
+            }
+        };
+        let mut context_data = ctx.data.write().await;
+        let tasks = context_data.get_mut::<TasksKey>().unwrap();
+        match tasks.get(&id).map(TaskType::is_gulag) {
+            Some(true) => {
+                println!("DT | Task {id} is a gulag sentence. Refusing to delete it.");
+                drop(context_data);
+                invocation
+                    .reply_ephemeral(
+                        &ctx.http,
+                        "That's a gulag sentence. Use `release` to end it instead.",
+                    )
+                    .await?;
+            }
+            Some(false) => {
+                let task = tasks.remove(&id).unwrap();
+                context_data.get::<StorageKey>().unwrap().remove_task(id)?;
+                task_changed(&context_data, id);
+                let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+                drop(context_data);
+                println!("DT | Deleted task {id}.");
+                invocation
+                    .reply(
+                        &ctx.http,
+                        format!("Deleted task:\n```{id}: {}```", task.list_fmt(default_tz)),
+                    )
+                    .await?;
+            }
+            None => {
+                drop(context_data);
+                println!("DT | No task with ID {id}.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("There's no task with ID {id}."))
+                    .await?;
+            }
+        }
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("DT | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[command]
+#[aliases("pause-task")]
+pub async fn pause_task(ctx: &Context, message: &Message) -> CommandResult {
+    run_set_paused(ctx, &message.into(), "=>pause_task", true).await
+}
+
+#[command]
+#[aliases("resume-task")]
+pub async fn resume_task(ctx: &Context, message: &Message) -> CommandResult {
+    run_set_paused(ctx, &message.into(), "=>resume_task", false).await
+}
+
+pub async fn run_set_paused(
+    ctx: &Context,
+    invocation: &Invocation<'_>,
+    prefix: &str,
+    paused: bool,
+) -> CommandResult {
+    println!("PA | Start handling {prefix} command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let id = match try_get_task_id(invocation.args(prefix)) {
+            Ok(id) => id,
+            Err(err) => {
+                let result = reply_parse_error(ctx, invocation, err).await;
+                println!("PA | Elapsed: {:?}", start.elapsed());
+                return result;
+            }
+        };
+        match update_task(ctx, id, |task, tz| task.set_paused(paused, tz)).await {
+            Ok(Some(list_entry)) => {
+                println!("PA | Set paused to {paused} for task {id}.");
+                invocation
+                    .reply(&ctx.http, format!("```{id}: {list_entry}```"))
+                    .await?;
+            }
+            Ok(None) => {
+                println!("PA | No task with ID {id}.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("There's no task with ID {id}."))
+                    .await?;
+            }
+            Err(err) => {
+                println!("PA | Failed to update task {id}: {err}");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("{err}"))
+                    .await?;
+                println!("PA | Elapsed: {:?}", start.elapsed());
+                return Err(err.into());
+            }
+        }
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("PA | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::EditTask;
+    use crate::tasks::{misfire::MisfirePolicy, periodic_task::PeriodicTask, task::Task, TaskType};
+    use chrono::Utc;
+    use chrono_tz::Tz;
+    use clap::Parser;
+
+    #[test]
+    fn test_edit_period_only_applies_to_periodic_tasks() {
+        let mut task = TaskType::PeriodicTask(PeriodicTask {
+            task: Task::ResetAppearance,
+            diff: 60,
+            last_sent: Utc::now().naive_utc(),
+            time_zone: None,
+            misfire_policy: MisfirePolicy::default(),
+            paused: false,
+            runs: 0,
+            max_runs: None,
+            until: None,
+        });
+        let EditTask { edit } =
+            EditTask::try_parse_from(["period", "--id", "3", "-d", "1"]).unwrap();
+        assert_eq!(edit.id(), 3);
+        edit.clone().apply(&mut task, None, Tz::UTC).unwrap();
+        match &task {
+            TaskType::PeriodicTask(pt) => assert_eq!(pt.diff, 86400),
+            _ => unreachable!(),
+        }
+        let EditTask { edit } =
+            EditTask::try_parse_from(["period", "--id", "3", "-s", "0"]).unwrap();
+        assert!(edit.apply(&mut task, None, Tz::UTC).is_err());
+        match &task {
+            TaskType::PeriodicTask(pt) => assert_eq!(pt.diff, 86400),
+            _ => unreachable!(),
+        }
+        let EditTask { edit } =
+            EditTask::try_parse_from(["condition", "--id", "3", "--time", "09:00:00"]).unwrap();
+        assert!(edit.apply(&mut task, None, Tz::UTC).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use crate::cases::Case;
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    str::FromStr,
+};
+
+/// How long a sentence from the escalation policy lasts. Written like `90m`, `12h`, `3d`, `2w` or
+/// `permanent` in the config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SentenceLength {
+    For(Duration),
+    Permanent,
+}
+
+impl SentenceLength {
+    pub fn end(self, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            SentenceLength::For(duration) => now + duration,
+            SentenceLength::Permanent => permanent_end(),
+        }
+    }
+}
+
+impl FromStr for SentenceLength {
+    type Err = IoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("permanent") {
+            return Ok(SentenceLength::Permanent);
+        }
+        let invalid = || {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                format!(
+                    "'{s}' isn't a sentence length. Use a number followed by m, h, d or w, or \
+                     'permanent'."
+                )
+                .as_str(),
+            )
+        };
+        let (count, unit) = s.split_at(s.len().saturating_sub(1));
+        let count = count.parse::<i64>().map_err(|_| invalid())?;
+        let duration = match unit {
+            "m" => Duration::minutes(count),
+            "h" => Duration::hours(count),
+            "d" => Duration::days(count),
+            "w" => Duration::weeks(count),
+            _ => return Err(invalid()),
+        };
+        if duration <= Duration::zero() {
+            return Err(invalid());
+        }
+        Ok(SentenceLength::For(duration))
+    }
+}
+
+impl TryFrom<String> for SentenceLength {
+    type Error = IoError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SentenceLength> for String {
+    fn from(length: SentenceLength) -> Self {
+        length.to_string()
+    }
+}
+
+impl Display for SentenceLength {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SentenceLength::Permanent => write!(f, "permanent"),
+            SentenceLength::For(duration) => {
+                let minutes = duration.num_minutes();
+                if minutes % (60 * 24 * 7) == 0 {
+                    write!(f, "{}w", minutes / (60 * 24 * 7))
+                } else if minutes % (60 * 24) == 0 {
+                    write!(f, "{}d", minutes / (60 * 24))
+                } else if minutes % 60 == 0 {
+                    write!(f, "{}h", minutes / 60)
+                } else {
+                    write!(f, "{minutes}m")
+                }
+            }
+        }
+    }
+}
+
+/// Permanent sentences end so far in the future that they never come up.
+pub fn permanent_end() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap()
+}
+
+pub fn is_permanent(end: DateTime<Utc>) -> bool {
+    end >= permanent_end()
+}
+
+/// Sentence lengths that get longer the more often someone has been sent to gulag, used when the
+/// gulag command isn't given a length.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EscalationPolicy {
+    /// Length of a first sentence, a second sentence and so on. The last one is used for every
+    /// sentence after that.
+    pub steps: Vec<SentenceLength>,
+    /// Sentences handed out longer ago than this many days don't count. All of them count if not
+    /// given.
+    #[serde(default)]
+    pub decay_days: Option<i64>,
+}
+
+impl EscalationPolicy {
+    pub fn validate(&self) -> AnyResult<()> {
+        if self.steps.is_empty() {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "The escalation policy needs at least one sentence length.",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// The sentence for someone with the given history, and how many of their earlier sentences
+    /// counted towards it. Only sentences that are over count, so a sentence still being served
+    /// isn't held against the same person twice.
+    pub fn sentence_for(&self, history: &[Case], now: DateTime<Utc>) -> (SentenceLength, usize) {
+        let counted = history
+            .iter()
+            .filter(|case| case.released_at.is_some())
+            .filter(|case| {
+                self.decay_days
+                    .is_none_or(|days| now - case.issued_at < Duration::days(days))
+            })
+            .count();
+        let step = counted.min(self.steps.len().saturating_sub(1));
+        (self.steps[step], counted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EscalationPolicy, SentenceLength};
+    use crate::cases::Case;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_sentence_for() {
+        let policy = serde_json::from_str::<EscalationPolicy>(
+            r#"{ "steps": ["1h", "1d", "1w", "permanent"], "decay_days": 30 }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.steps[1], SentenceLength::For(Duration::days(1)));
+        assert_eq!(
+            serde_json::to_string(&policy.steps).unwrap(),
+            r#"["1h","1d","1w","permanent"]"#
+        );
+        assert!("0d".parse::<SentenceLength>().is_err());
+        assert!("3 days".parse::<SentenceLength>().is_err());
+        let now = Utc::now();
+        let case = |days_ago| {
+            let mut case = Case::new(
+                ("someone".into(), 1.into()),
+                ("moderator".into(), 2.into()),
+                None,
+                now,
+            );
+            case.issued_at = now - Duration::days(days_ago);
+            case.released_at = Some(case.issued_at);
+            case
+        };
+        assert_eq!(policy.sentence_for(&[], now).0, policy.steps[0]);
+        // The old one has decayed.
+        let history = [case(100), case(20), case(2)];
+        assert_eq!(policy.sentence_for(&history, now), (policy.steps[2], 2));
+        // Neither does one that's still being served.
+        let mut serving = case(0);
+        serving.released_at = None;
+        let history = [case(2), serving];
+        assert_eq!(policy.sentence_for(&history, now), (policy.steps[1], 1));
+        let history = [case(4), case(3), case(2), case(1), case(0)];
+        assert_eq!(
+            policy.sentence_for(&history, now).0,
+            SentenceLength::Permanent
+        );
+    }
+}
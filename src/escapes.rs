@@ -0,0 +1,268 @@
+use crate::{
+    cache_keys::{ConfigKey, HigherRolesKey, StorageKey, TasksKey},
+    mod_log::{ModLogEntry, ModLogKind},
+    scheduler::task_changed,
+    storage::Storage,
+};
+use anyhow::Result as AnyResult;
+use chrono::{Duration, Utc};
+use serenity::{
+    model::{
+        guild::Member,
+        id::{ChannelId, GuildId, RoleId},
+        user::User,
+    },
+    prelude::Context,
+};
+
+// Prisoners shouldn't be able to get out of gulag early. Leaving the server drops the prisoner
+// role, so anyone who comes back while their sentence is still running is put straight back, and
+// roles changed by hand during a sentence are changed back.
+
+/// Notes a prisoner leaving the server. Their sentence keeps running and their saved roles are
+/// kept, so that they're jailed again if they come back before it ends.
+pub async fn member_left(ctx: &Context, guild_id: GuildId, user: &User) -> AnyResult<()> {
+    let context_data = ctx.data.read().await;
+    let config = context_data.get::<ConfigKey>().unwrap();
+    if guild_id != config.guild_id {
+        return Ok(());
+    }
+    let mod_log_channel = config.mod_log_channel;
+    let gulag = context_data
+        .get::<TasksKey>()
+        .unwrap()
+        .values()
+        .filter_map(|task| task.gulag_ref())
+        .find(|gulag| gulag.user.1 == user.id)
+        .cloned();
+    drop(context_data);
+    if let Some(gulag) = gulag {
+        println!(
+            "ES | Prisoner '{}' (ID: {}) left the server.",
+            gulag.user.0, gulag.user.1
+        );
+        let mut entry = ModLogEntry::new(ModLogKind::Left, gulag.user);
+        entry.case = gulag.case;
+        entry.end = Some(gulag.end);
+        entry.post(&ctx.http, mod_log_channel).await;
+    }
+    Ok(())
+}
+
+/// Puts a prisoner who left and came back before their sentence ended back in gulag, adding the
+/// configured penalty to the sentence. Anyone who comes back after it ended gets their roles back.
+pub async fn member_joined(ctx: &Context, member: &Member) -> AnyResult<()> {
+    let mut context_data = ctx.data.write().await;
+    let config = context_data.get::<ConfigKey>().unwrap();
+    if member.guild_id != config.guild_id {
+        return Ok(());
+    }
+    let prisoner_role_id = config.prisoner_role_id;
+    let penalty = Duration::minutes(config.rejoin_penalty_mins.max(0));
+    let mod_log_channel = config.mod_log_channel;
+    let now = Utc::now();
+    let found = context_data
+        .get_mut::<TasksKey>()
+        .unwrap()
+        .iter_mut()
+        .find_map(|(&id, task)| match task.gulag_mut() {
+            // An overdue sentence is about to be released anyway.
+            Some(gulag) if gulag.user.1 == member.user.id && gulag.end > now => {
+                gulag.end += penalty;
+                Some((id, task.clone()))
+            }
+            _ => None,
+        });
+    let (id, task) = match found {
+        Some(found) => found,
+        None => {
+            let storage = context_data.get::<StorageKey>().unwrap().clone();
+            drop(context_data);
+            return restore_owed_roles(ctx, member, &storage, mod_log_channel).await;
+        }
+    };
+    let gulag = task.gulag_ref().unwrap();
+    println!(
+        "ES | Prisoner '{}' (ID: {}) rejoined. Sending them back until {}.",
+        gulag.user.0, gulag.user.1, gulag.end
+    );
+    let storage = context_data.get::<StorageKey>().unwrap();
+    storage.update_task(id, &task)?;
+    if let Some(case) = gulag.case {
+        storage.update_case(case, |case| case.end = gulag.end)?;
+    }
+    task_changed(&context_data, id);
+    drop(context_data);
+    let mut entry = ModLogEntry::new(ModLogKind::Rejailed, gulag.user.clone());
+    entry.case = gulag.case;
+    entry.end = Some(gulag.end);
+    if penalty > Duration::zero() {
+        entry.reason = Some(format!(
+            "Sentence extended by {} minutes for leaving.",
+            penalty.num_minutes()
+        ));
+    }
+    let jailed = ctx
+        .http
+        .add_member_role(
+            member.guild_id.into(),
+            member.user.id.into(),
+            prisoner_role_id.into(),
+            Some("Left and rejoined while in gulag."),
+        )
+        .await;
+    if let Err(err) = &jailed {
+        entry.failure = Some(err.to_string());
+    }
+    entry.post(&ctx.http, mod_log_channel).await;
+    Ok(jailed?)
+}
+
+/// Gives back the roles of someone whose sentence ended while they weren't in the server.
+async fn restore_owed_roles(
+    ctx: &Context,
+    member: &Member,
+    storage: &Storage,
+    mod_log_channel: Option<ChannelId>,
+) -> AnyResult<()> {
+    let (case, roles) = match storage.take_owed_roles(member.user.id)? {
+        Some(owed) => owed,
+        None => return Ok(()),
+    };
+    println!(
+        "ES | Released prisoner '{}' (ID: {}) rejoined. Giving their roles back.",
+        member.user.name, member.user.id
+    );
+    let mut entry = ModLogEntry::new(
+        ModLogKind::Returned,
+        (member.user.name.clone(), member.user.id),
+    );
+    entry.case = case;
+    entry.roles = roles.clone();
+    let role_ids = roles
+        .iter()
+        .map(|&(_, role_id)| role_id)
+        .collect::<Vec<_>>();
+    let restored = member.clone().add_roles(&ctx.http, &role_ids).await;
+    if let Err(err) = &restored {
+        // Keep them owed so that the next time they join is another chance.
+        storage.owe_roles(member.user.id, case, &roles)?;
+        entry.failure = Some(err.to_string());
+    }
+    entry.post(&ctx.http, mod_log_channel).await;
+    restored?;
+    Ok(())
+}
+
+/// Undoes role changes made to a prisoner by anyone else: roles they were given are taken away
+/// again, and a removed prisoner role is given back. If the config says so, removing the prisoner
+/// role ends the sentence instead.
+pub async fn member_updated(ctx: &Context, old: Option<&Member>, new: &Member) -> AnyResult<()> {
+    let mut context_data = ctx.data.write().await;
+    let config = context_data.get::<ConfigKey>().unwrap();
+    if new.guild_id != config.guild_id {
+        return Ok(());
+    }
+    let prisoner_role_id = config.prisoner_role_id;
+    let release_on_removal = config.release_on_prisoner_role_removal;
+    let mod_log_channel = config.mod_log_channel;
+    // The same roles the gulag command leaves alone.
+    let mut kept_roles = vec![prisoner_role_id, config.nitro_role_id];
+    kept_roles.extend(config.admin_roles.iter().map(|&(_, role_id)| role_id));
+    kept_roles.extend(
+        context_data
+            .get::<HigherRolesKey>()
+            .unwrap()
+            .iter()
+            .map(|role| role.id),
+    );
+    let now = Utc::now();
+    let found = context_data
+        .get::<TasksKey>()
+        .unwrap()
+        .iter()
+        .find_map(|(&id, task)| match task.gulag_ref() {
+            // Ended sentences are being released, which changes roles too.
+            Some(gulag) if gulag.user.1 == new.user.id && gulag.end > now => {
+                Some((id, gulag.clone()))
+            }
+            _ => None,
+        });
+    let (id, gulag) = match found {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    // Only changes that were seen happening count. Without the old member there's no telling
+    // whether the prisoner role was just removed or hasn't been added yet by the gulag command.
+    let prisoner_role_removed = !new.roles.contains(&prisoner_role_id)
+        && old.is_some_and(|old| old.roles.contains(&prisoner_role_id));
+    if prisoner_role_removed && release_on_removal {
+        println!(
+            "ES | Prisoner role removed from '{}' (ID: {}) by hand. Releasing them.",
+            gulag.user.0, gulag.user.1
+        );
+        let tasks = context_data.get_mut::<TasksKey>().unwrap();
+        let task = tasks.get_mut(&id).unwrap();
+        task.gulag_mut().unwrap().end = now;
+        let task = task.clone();
+        let storage = context_data.get::<StorageKey>().unwrap();
+        storage.update_task(id, &task)?;
+        if let Some(case) = gulag.case {
+            storage.update_case(case, |case| case.end = now)?;
+        }
+        // Removing this from the task list is handled by the scheduler.
+        task_changed(&context_data, id);
+        drop(context_data);
+        let mut entry = ModLogEntry::new(ModLogKind::RoleRemovedByHand, gulag.user);
+        entry.case = gulag.case;
+        entry.post(&ctx.http, mod_log_channel).await;
+        return Ok(());
+    }
+    drop(context_data);
+    let added_roles = new
+        .roles
+        .iter()
+        .filter(|role_id| !kept_roles.contains(role_id))
+        .filter(|role_id| old.is_none_or(|old| !old.roles.contains(role_id)))
+        .copied()
+        .collect::<Vec<RoleId>>();
+    if !prisoner_role_removed && added_roles.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "ES | Roles of prisoner '{}' (ID: {}) were changed by hand. Changing them back.",
+        gulag.user.0, gulag.user.1
+    );
+    let mut entry = ModLogEntry::new(ModLogKind::Tampered, gulag.user.clone());
+    entry.case = gulag.case;
+    entry.roles = added_roles
+        .iter()
+        .map(|&role_id| (role_id.to_string(), role_id))
+        .collect();
+    if prisoner_role_removed {
+        entry.reason = Some("The prisoner role was removed.".into());
+    }
+    let reverted = async {
+        let mut member = new.clone();
+        if !added_roles.is_empty() {
+            member.remove_roles(&ctx.http, &added_roles).await?;
+        }
+        if prisoner_role_removed {
+            ctx.http
+                .add_member_role(
+                    new.guild_id.into(),
+                    new.user.id.into(),
+                    prisoner_role_id.into(),
+                    Some("Prisoner role removed during a sentence."),
+                )
+                .await?;
+        }
+        AnyResult::<()>::Ok(())
+    }
+    .await;
+    if let Err(err) = &reverted {
+        entry.failure = Some(err.to_string());
+    }
+    entry.post(&ctx.http, mod_log_channel).await;
+    reverted
+}
@@ -1,72 +1,140 @@
 use crate::{
-    cache_keys::{BotIdKey, ConfigKey, HigherRolesKey, NitroRoleKey, TaskSenderKey, TasksKey},
+    cache_keys::{
+        BotIdKey, ConfigKey, HigherRolesKey, NitroRoleKey, StorageKey, TaskSenderKey, TasksKey,
+    },
+    cases::Case,
+    invocation::Invocation,
     misc::{insufficient_perms, is_administrator, CreateTimePeriod},
+    mod_log::{ModLogEntry, ModLogKind},
+    scheduler::{task_changed, TaskMessage},
     tasks::{gulag::Gulag, TaskType},
 };
 use anyhow::Result as AnyResult;
 use chrono::prelude::*;
-use clap::{ColorChoice, Parser};
+use chrono_tz::Tz;
+use clap::{ArgAction, ColorChoice, Parser};
 use serenity::{
     client::Context,
     framework::standard::{macros::command, CommandResult},
     model::{channel::Message, id::UserId, prelude::MessageReference},
+    prelude::TypeMap,
+};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    time::Instant,
 };
-use std::time::Instant;
 
 #[derive(Clone, Debug, Parser)]
 #[command(
     name = "Gulag",
     about = "Sends a user to gulag",
     color(ColorChoice::Never),
-    no_binary_name(true)
+    no_binary_name(true),
+    disable_help_flag(true),
+    mut_group("CreateTimePeriod", |group| group.required(false))
 )]
 pub(crate) struct GulagApp {
+    /// User to send to gulag
     #[arg(short = 'u', long = "user", name = "user_id")]
     user_id: UserId,
     #[command(flatten)]
-    time_period: CreateTimePeriod,
+    time_period: Option<CreateTimePeriod>,
+    /// Why the user is being sent to gulag
+    #[arg(short = 'r', long = "reason", name = "reason", num_args = 1..)]
+    reason: Vec<String>,
+    // `-h` is taken by `--hours`.
+    /// Print help
+    #[arg(long = "help", action = ArgAction::Help)]
+    help: Option<bool>,
 }
 
-fn try_get_gulag(s: &str) -> AnyResult<(UserId, DateTime<Utc>)> {
-    println!("GL | Parsing gulag command use from '{s}'");
-    let trimmed = s.trim_start_matches("=>gulag").trim();
-    let arg_matches = GulagApp::try_parse_from(trimmed.split_whitespace())?;
+fn try_get_gulag(
+    args: Vec<String>,
+    tz: Tz,
+) -> AnyResult<(UserId, Option<DateTime<Utc>>, Option<String>)> {
+    println!("GL | Parsing gulag command use from {args:?}");
+    let arg_matches = GulagApp::try_parse_from(args)?;
     println!("GL | Successfully parsed usage.");
     let GulagApp {
         user_id,
         time_period,
+        reason,
+        ..
     } = arg_matches;
-    let end = time_period.to_datetime_utc()?;
+    let end = time_period
+        .map(|time_period| time_period.to_datetime_utc(tz))
+        .transpose()?;
+    let reason = Some(reason.join(" ")).filter(|reason| !reason.is_empty());
     println!("GL | Successfully parsed user ID and gulag duration.");
-    Ok((user_id, end))
+    Ok((user_id, end, reason))
+}
+
+/// The end of the sentence the escalation policy gives the user, for when the command isn't given a
+/// length.
+fn escalated_end(context_data: &TypeMap, user_id: UserId) -> AnyResult<DateTime<Utc>> {
+    let policy = context_data
+        .get::<ConfigKey>()
+        .unwrap()
+        .escalation
+        .as_ref()
+        .ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                "Give a sentence length, like `-d 1`. There's no escalation policy to pick one.",
+            )
+        })?;
+    let history = context_data
+        .get::<StorageKey>()
+        .unwrap()
+        .cases_for(user_id)?
+        .into_iter()
+        .map(|(_, case)| case)
+        .collect::<Vec<_>>();
+    let now = Utc::now();
+    let (length, counted) = policy.sentence_for(&history, now);
+    println!("GL | {counted} earlier sentences count. Escalated sentence length: {length}.");
+    Ok(length.end(now))
 }
 
-#[allow(clippy::unreadable_literal)]
 #[command]
 pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
+    run_gulag(ctx, &message.into()).await
+}
+
+#[allow(clippy::unreadable_literal)]
+pub async fn run_gulag(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("GL | Start handling gulag command.");
     let start = Instant::now();
     println!("GL | Grabbing read 'lock' on context data.");
     let context_data = ctx.data.read().await;
     let self_id = *context_data.get::<BotIdKey>().unwrap();
+    let tz = context_data.get::<ConfigKey>().unwrap().time_zone;
     println!("GL | Checking permissions.");
-    if is_administrator(&ctx.http, context_data, message).await? {
-        match try_get_gulag(message.content.as_str()) {
-            Ok((user_id, end)) => {
+    if is_administrator(&ctx.http, context_data, invocation).await? {
+        match try_get_gulag(invocation.args("=>gulag"), tz) {
+            Ok((user_id, end, reason)) => {
                 if user_id == self_id {
-                    let mr: MessageReference = message.into();
-                    message
-                        .channel_id
-                        .send_message(&ctx.http, |m| {
-                            m.content("Haha. Very funny.")
-                                .sticker_id(988793966281498704)
-                                .reference_message(mr)
-                                .allowed_mentions(|f| f.replied_user(false))
-                        })
-                        .await?;
+                    if let Invocation::Message(message) = invocation {
+                        let mr: MessageReference = (*message).into();
+                        message
+                            .channel_id
+                            .send_message(&ctx.http, |m| {
+                                m.content("Haha. Very funny.")
+                                    .sticker_id(988793966281498704)
+                                    .reference_message(mr)
+                                    .allowed_mentions(|f| f.replied_user(false))
+                            })
+                            .await?;
+                    } else {
+                        invocation.reply(&ctx.http, "Haha. Very funny.").await?;
+                    }
                 } else {
+                    // Fetching everything needed to gulag someone can take a while.
+                    invocation.defer(&ctx.http).await?;
                     println!("GL | Getting write lock on context data.");
                     let mut context_data = ctx.data.write().await;
+                    let mod_log_channel = context_data.get::<ConfigKey>().unwrap().mod_log_channel;
+                    let moderator = (invocation.author().name.clone(), invocation.author().id);
                     println!("GL | Getting tasks list.");
                     let tasks = context_data.get_mut::<TasksKey>().unwrap();
                     println!(
@@ -74,18 +142,79 @@ pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
                         user_id
                     );
                     // Check if any gulags exist for this user presently, and if they do, update the
-                    // end time.
-                    if let Some(gulag) = tasks.iter_mut().find_map(|task| match task {
-                        TaskType::Gulag(gulag) if gulag.user.1 == user_id => Some(gulag),
-                        _ => None,
-                    }) {
-                        println!("GL | Found existing gulag entry - updating.");
-                        gulag.end = end;
+                    // end time. Without a new length only the reason changes, since the sentence
+                    // being served isn't an earlier offence to escalate from.
+                    if let Some((id, task)) =
+                        tasks
+                            .iter_mut()
+                            .find_map(|(&id, task)| match task.gulag_mut() {
+                                Some(gulag) if gulag.user.1 == user_id => {
+                                    println!("GL | Found existing gulag entry - updating.");
+                                    gulag.end = end.unwrap_or(gulag.end);
+                                    Some((id, task.clone()))
+                                }
+                                _ => None,
+                            })
+                    {
+                        println!("GL | Saving updated gulag entry.");
+                        let storage = context_data.get::<StorageKey>().unwrap();
+                        storage.update_task(id, &task)?;
+                        let gulag = task.gulag_ref().unwrap();
+                        let end = gulag.end;
+                        let mut entry = ModLogEntry::new(ModLogKind::Extended, gulag.user.clone());
+                        entry.moderator = Some(moderator);
+                        entry.case = gulag.case;
+                        entry.reason = reason.clone();
+                        entry.end = Some(end);
+                        // Sentences from before cases were recorded don't have one.
+                        let reply = match gulag.case {
+                            Some(case) => {
+                                println!("GL | Updating case {case}.");
+                                storage.update_case(case, |case| {
+                                    case.end = end;
+                                    if reason.is_some() {
+                                        case.reason = reason;
+                                    }
+                                })?;
+                                format!("Changed the sentence in case {case}.")
+                            }
+                            None => "Changed the sentence.".into(),
+                        };
+                        task_changed(&context_data, id);
+                        drop(context_data);
+                        invocation.reply(&ctx.http, reply).await?;
+                        entry.post(&ctx.http, mod_log_channel).await;
                     } else {
                         println!("GL | No gulag entries for that user exist.");
-                        println!("GL | Getting guild ID.");
+                        let end =
+                            match end.map_or_else(|| escalated_end(&context_data, user_id), Ok) {
+                                Ok(end) => end,
+                                Err(err) => {
+                                    drop(context_data);
+                                    println!("GL | No sentence length to use. Notifying user.");
+                                    invocation.reply_ephemeral(&ctx.http, err).await?;
+                                    println!("GL | Elapsed: {:?}", start.elapsed());
+                                    return Ok(());
+                                }
+                            };
+                        println!("GL | Getting guild ID and gulag role ID.");
                         let config = context_data.get::<ConfigKey>().unwrap();
                         let guild_id = config.guild_id;
+                        let gulag_id = config.prisoner_role_id;
+                        println!("GL | Fetching Nitro, admin and higher role IDs.");
+                        let mut kept_roles = vec![context_data.get::<NitroRoleKey>().unwrap().id];
+                        kept_roles.extend(config.admin_roles.iter().map(|&(_, role_id)| role_id));
+                        kept_roles.extend(
+                            context_data
+                                .get::<HigherRolesKey>()
+                                .unwrap()
+                                .iter()
+                                .map(|role| role.id),
+                        );
+                        let storage = context_data.get::<StorageKey>().unwrap().clone();
+                        let task_sender = context_data.get::<TaskSenderKey>().unwrap().clone();
+                        // Nothing below needs the context data, and the requests can take a while.
+                        drop(context_data);
                         println!("GL | Getting member information.");
                         let mut member =
                             match ctx.http.get_member(guild_id.into(), user_id.into()).await {
@@ -98,7 +227,7 @@ pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
                                         "Failed to get member information. Error details:\n{}",
                                         err
                                     );
-                                    let _ = message.reply(&ctx.http, content.as_str()).await?;
+                                    invocation.reply_ephemeral(&ctx.http, content).await?;
                                     Err(err)
                                 }
                             }?;
@@ -107,8 +236,6 @@ pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
                             member.display_name(),
                             member.user.id,
                         );
-                        println!("GL | Fetching Nitro role ID");
-                        let nitro_role_id = context_data.get::<NitroRoleKey>().unwrap().id;
                         let user = (member.display_name().clone().into_owned(), user_id);
                         println!("GL | Fetching guild information.");
                         let guild = match ctx.http.get_guild(guild_id.into()).await {
@@ -119,26 +246,18 @@ pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
                                     "Failed to fetch guild information to save roles. Details:\n{}",
                                     err
                                 );
-                                let _ = message.reply(&ctx.http, content.as_str()).await?;
+                                invocation.reply_ephemeral(&ctx.http, content).await?;
                                 Err(err)
                             }
                         }?;
                         println!("GL | Successfully retrieved guild information.");
                         let mut roles_map = guild.roles;
-                        println!("GL | Removing Nitro role ID from ID => role map.");
-                        let _ = roles_map.remove(&nitro_role_id);
-                        println!("GL | Removing admin_roles from ID => role map.");
-                        config.admin_roles.iter().for_each(|(_, role_id)| {
+                        println!(
+                            "GL | Removing Nitro, admin and higher roles from ID => role map."
+                        );
+                        kept_roles.iter().for_each(|role_id| {
                             let _ = roles_map.remove(role_id);
                         });
-                        println!("GL | Removing higher_roles from ID => role map.");
-                        context_data
-                            .get::<HigherRolesKey>()
-                            .unwrap()
-                            .iter()
-                            .for_each(|role| {
-                                let _ = roles_map.remove(&role.id);
-                            });
                         println!("GL | Mapping role IDs to role names.");
                         let roles = member
                             .roles
@@ -154,54 +273,74 @@ pub async fn gulag(ctx: &Context, message: &Message) -> CommandResult {
                             .iter()
                             .map(|(_, role_id)| *role_id)
                             .collect::<Vec<_>>();
-                        println!("GL | Creating gulag entry.");
-                        let gulag = Gulag::new(user, roles, end);
-                        println!("GL | Getting gulag role ID.");
-                        let gulag_id = context_data.get::<ConfigKey>().unwrap().prisoner_role_id;
-                        println!("GL | Removing user's roles.");
-                        member.remove_roles(&ctx.http, &remove_list).await?;
-                        println!("GL | Adding prisoner role.");
-                        ctx.http
-                            .add_member_role(
-                                guild_id.into(),
-                                user_id.into(),
-                                gulag_id.into(),
-                                Some("To gulag with this fool."),
-                            )
-                            .await?;
+                        let mut entry = ModLogEntry::new(ModLogKind::Gulagged, user.clone());
+                        entry.moderator = Some(moderator.clone());
+                        entry.reason = reason.clone();
+                        entry.end = Some(end);
+                        entry.roles = roles.clone();
+                        let jailed = async {
+                            println!("GL | Removing user's roles.");
+                            member.remove_roles(&ctx.http, &remove_list).await?;
+                            println!("GL | Adding prisoner role.");
+                            ctx.http
+                                .add_member_role(
+                                    guild_id.into(),
+                                    user_id.into(),
+                                    gulag_id.into(),
+                                    Some("To gulag with this fool."),
+                                )
+                                .await
+                        }
+                        .await;
+                        if let Err(err) = jailed {
+                            println!("GL | Failed to change the user's roles.");
+                            entry.failure = Some(err.to_string());
+                            entry.post(&ctx.http, mod_log_channel).await;
+                            return Err(err.into());
+                        }
                         println!("GL | Successfully gulagged user.");
-                        println!("GL | Getting task sender.");
-                        let task_sender = context_data.get_mut::<TaskSenderKey>().unwrap();
-                        println!("GL | Sending task to main thread.");
-                        match task_sender.send(TaskType::Gulag(gulag)) {
+                        println!("GL | Opening case.");
+                        let case = storage.insert_case(&Case::new(
+                            user.clone(),
+                            moderator,
+                            reason,
+                            end,
+                        ))?;
+                        println!("GL | Opened case {case}.");
+                        entry.case = Some(case);
+                        println!("GL | Creating gulag entry.");
+                        let gulag = Gulag::new(user, roles, end, Some(case));
+                        println!("GL | Sending task to scheduler.");
+                        match task_sender.send(TaskMessage::New(Box::new(TaskType::Gulag(gulag)))) {
                             Ok(_) => Ok(()),
                             Err(err) => {
                                 println!(
-                                    "GL | SN | Failed to send task to main thread. Notifying user."
+                                    "GL | SN | Failed to send task to scheduler. Notifying user."
                                 );
                                 let content = format!(
                                     "Failed to send gulag task to task handler. Details:\n{}",
                                     err
                                 );
-                                let _ = message.reply(&ctx.http, content.as_str()).await?;
+                                invocation.reply_ephemeral(&ctx.http, content).await?;
                                 Err(err)
                             }
                         }?;
-                        println!("GL | SN | Successfully sent task to main thread.");
+                        println!("GL | SN | Successfully sent task to scheduler.");
+                        entry.post(&ctx.http, mod_log_channel).await;
+                        invocation
+                            .reply(&ctx.http, format!("Opened case {case}."))
+                            .await?;
                     }
                 }
             }
             Err(err) => {
                 println!("GL | User input an invalid command. Displaying error message.");
                 let content = format!("Error parsing command. Details:\n```{}\n```", err);
-                let _ = message
-                    .channel_id
-                    .send_message(&ctx.http, |m| m.content(content.as_str()))
-                    .await?;
+                invocation.reply_ephemeral(&ctx.http, content).await?;
             }
         }
     } else {
-        insufficient_perms(ctx, message).await?;
+        insufficient_perms(ctx, invocation).await?;
     }
     println!("GL | Elapsed: {:?}", start.elapsed());
     Ok(())
@@ -0,0 +1,113 @@
+use crate::{
+    cache_keys::ConfigKey,
+    leaderboard::reactions::{emoji_allowed, emoji_display, normalise_emoji},
+    storage::Storage,
+    EMBED_COLOUR, FOOTER_TEXT,
+};
+use anyhow::Result as AnyResult;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    builder::CreateEmbed,
+    model::{channel::Message, id::ChannelId},
+    prelude::Context,
+};
+
+/// Messages that get enough reactions are reposted to a channel so they don't get lost.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HallOfFame {
+    /// Where messages are reposted. Reactions in here don't count.
+    pub channel: ChannelId,
+    /// How many reactions a message needs to get in.
+    pub threshold: usize,
+    /// Emoji that count towards the threshold. Any emoji counts if this is empty.
+    #[serde(default)]
+    pub emoji: Vec<String>,
+}
+
+/// Reposts a message to the hall of fame if it has just got enough reactions.
+pub async fn consider(ctx: &Context, storage: &Storage, message: &Message) -> AnyResult<()> {
+    let hall_of_fame = match ctx
+        .data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .hall_of_fame
+    {
+        Some(ref hall_of_fame) if hall_of_fame.channel != message.channel_id => {
+            hall_of_fame.clone()
+        }
+        _ => return Ok(()),
+    };
+    let reactions = storage
+        .reactions_on(message.channel_id, message.id)?
+        .into_iter()
+        .filter(|reaction| emoji_allowed(&hall_of_fame.emoji, &reaction.emoji))
+        .count();
+    if reactions < hall_of_fame.threshold {
+        return Ok(());
+    }
+    // Marked first so that two reactions arriving together can't both repost it.
+    if !storage.add_to_hall_of_fame(message.id)? {
+        return Ok(());
+    }
+    println!(
+        "HF | Message {} reached {reactions} reactions. Reposting.",
+        message.id
+    );
+    let embed = build_embed(&hall_of_fame, message, reactions);
+    if let Err(why) = hall_of_fame
+        .channel
+        .send_message(&ctx.http, |m| m.set_embed(embed))
+        .await
+    {
+        // Let the next reaction try again.
+        storage.remove_from_hall_of_fame(message.id)?;
+        return Err(why.into());
+    }
+    Ok(())
+}
+
+fn build_embed(hall_of_fame: &HallOfFame, message: &Message, reactions: usize) -> CreateEmbed {
+    let counted = if hall_of_fame.emoji.is_empty() {
+        "reactions".to_string()
+    } else {
+        hall_of_fame
+            .emoji
+            .iter()
+            .map(|emoji| emoji_display(&normalise_emoji(emoji)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let mut embed = CreateEmbed::default();
+    embed
+        .author(|author| {
+            author.name(&message.author.name);
+            if let Some(avatar) = message.author.avatar_url() {
+                author.icon_url(avatar);
+            }
+            author
+        })
+        .description(&message.content)
+        .colour(EMBED_COLOUR)
+        .field(
+            "Hall of fame",
+            format!(
+                "{reactions} {counted} in <#{}> | [Jump to message]({})",
+                message.channel_id,
+                message.link()
+            ),
+            false,
+        )
+        .timestamp(message.timestamp)
+        .footer(|footer| footer.text(FOOTER_TEXT));
+    if let Some(image) = message.attachments.iter().find(|attachment| {
+        attachment
+            .content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.starts_with("image/"))
+    }) {
+        embed.image(&image.url);
+    }
+    embed
+}
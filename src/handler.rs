@@ -1,11 +1,26 @@
 use crate::{
-    tasks::{message::MessageType, task::Task, TaskType},
-    BotIdKey, TasksKey,
+    activity::record_message,
+    cache_keys::ConfigKey,
+    escapes::{member_joined, member_left, member_updated},
+    interactions::{handle_autocomplete, handle_command, register_commands},
+    leaderboard::{
+        pings::{ingest_message, reset_caught_up},
+        reactions::{forget_reaction, ingest_reaction},
+    },
+    tasks::components::handle_component,
+    LeaderboardsKey, ReadyKey, ShuttingDownKey, StorageKey,
 };
 use serenity::{
     async_trait,
     framework::standard::{macros::hook, CommandError},
-    model::{channel::Message, prelude::Ready},
+    model::{
+        application::interaction::Interaction,
+        channel::{Message, Reaction},
+        guild::Member,
+        id::{ChannelId, GuildId, MessageId},
+        prelude::Ready,
+        user::User,
+    },
     prelude::*,
 };
 
@@ -14,40 +29,180 @@ pub struct Handler;
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, context: Context, message: Message) {
-        if message.author.id != *context.data.read().await.get::<BotIdKey>().unwrap() {
-            println!("HL | Begin handling sent message.");
-            // Get a lock on the data by holding onto the result of `write`.
-            let mut data = context.data.write().await;
-            // Get the task list.
-            let tasks = data.get_mut::<TasksKey>().unwrap();
-            // Try and find a periodic task with the header "Activity report".
-            let try_find_counter = tasks.iter_mut().find_map(|task_type| match task_type {
-                TaskType::PeriodicTask(task) => match &mut task.task {
-                    Task::SendMessage { message, .. } => match message {
-                        MessageType::Embed(msg)
-                            if msg.as_ref().description.as_deref() == Some("Activity report") =>
-                        {
-                            msg.as_mut().fields.as_mut().unwrap().get_mut(0)
-                        }
-                        _ => None,
-                    },
-                    _ => None,
-                },
-                _ => None,
-            });
-            // If it exists, parse the number the content contains, increment the number, and
-            // update the content.
-            if let Some((_, counter, _)) = try_find_counter {
-                let mut current_count = counter.parse::<usize>().unwrap();
-                current_count += 1;
-                counter.clear();
-                counter.push_str(&format!("{}", current_count));
+        if is_ingested(&context, message.channel_id).await {
+            let storage = context
+                .data
+                .read()
+                .await
+                .get::<StorageKey>()
+                .unwrap()
+                .clone();
+            if let Err(why) = ingest_message(&storage, &message) {
+                println!("HL | Failed to record pings: {why}");
             }
         }
+        if let Err(why) = record_message(&context, &message).await {
+            println!("HL | Failed to count message towards activity: {why}");
+        }
+    }
+
+    async fn message_delete(
+        &self,
+        context: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _: Option<GuildId>,
+    ) {
+        let storage = context
+            .data
+            .read()
+            .await
+            .get::<StorageKey>()
+            .unwrap()
+            .clone();
+        if is_ingested(&context, channel_id).await {
+            if let Err(why) = storage.remove_pings(channel_id, deleted_message_id) {
+                println!("HD | Failed to forget pings: {why}");
+            }
+        }
+        if let Err(why) = storage.remove_reactions(channel_id, deleted_message_id) {
+            println!("HD | Failed to forget reactions: {why}");
+        }
+    }
+
+    async fn reaction_add(&self, context: Context, reaction: Reaction) {
+        if let Err(why) = ingest_reaction(&context, &reaction).await {
+            println!("HD | Failed to record reaction: {why}");
+        }
+    }
+
+    async fn reaction_remove(&self, context: Context, reaction: Reaction) {
+        if let Err(why) = forget_reaction(&context, &reaction).await {
+            println!("HD | Failed to forget reaction: {why}");
+        }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn reaction_remove_all(
+        &self,
+        context: Context,
+        channel_id: ChannelId,
+        removed_from_message_id: MessageId,
+    ) {
+        let storage = context
+            .data
+            .read()
+            .await
+            .get::<StorageKey>()
+            .unwrap()
+            .clone();
+        if let Err(why) = storage.remove_reactions(channel_id, removed_from_message_id) {
+            println!("HD | Failed to forget reactions: {why}");
+        }
+    }
+
+    async fn guild_member_addition(&self, context: Context, new_member: Member) {
+        if let Err(why) = member_joined(&context, &new_member).await {
+            println!("HD | Failed to send rejoining prisoner back to gulag: {why}");
+        }
+    }
+
+    async fn guild_member_removal(
+        &self,
+        context: Context,
+        guild_id: GuildId,
+        user: User,
+        _: Option<Member>,
+    ) {
+        if let Err(why) = member_left(&context, guild_id, &user).await {
+            println!("HD | Failed to handle member leaving: {why}");
+        }
+    }
+
+    async fn guild_member_update(&self, context: Context, old: Option<Member>, new: Member) {
+        if let Err(why) = member_updated(&context, old.as_ref(), &new).await {
+            println!("HD | Failed to undo role changes to a prisoner: {why}");
+        }
+    }
+
+    async fn ready(&self, context: Context, ready: Ready) {
         println!("HD | Connected as user '{}'.", ready.user.name);
+        // Messages sent while disconnected never arrive, so recorded pings have to catch up again.
+        reset_caught_up();
+        // `ready` fires again on reconnects, but the commands only need registering once, and the
+        // shard keeps whatever presence it had.
+        if context.data.read().await.get::<ReadyKey>().copied() != Some(true) {
+            set_default_presence(&context).await;
+            if let Err(why) = register_commands(&context).await {
+                println!("HD | Failed to register application commands: {why}");
+            } else {
+                context.data.write().await.insert::<ReadyKey>(true);
+            }
+        }
+    }
+
+    async fn interaction_create(&self, context: Context, interaction: Interaction) {
+        if is_shutting_down(&context).await {
+            println!("HD | Shutting down. Ignoring interaction.");
+            return;
+        }
+        match interaction {
+            Interaction::ApplicationCommand(command) => handle_command(&context, &command).await,
+            Interaction::Autocomplete(autocomplete) => {
+                handle_autocomplete(&context, &autocomplete).await;
+            }
+            Interaction::MessageComponent(component) => {
+                if let Err(why) = handle_component(&context, &component).await {
+                    println!("HD | Failed to answer component interaction: {why}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether any leaderboard scores messages in the channel as they come in.
+async fn is_ingested(ctx: &Context, channel: ChannelId) -> bool {
+    ctx.data
+        .read()
+        .await
+        .get::<LeaderboardsKey>()
+        .unwrap()
+        .values()
+        .any(|leaderboard| leaderboard.scoring_method.ingests_from() == Some(channel))
+}
+
+/// Sets the presence from the config on the shard that just connected, if there is one.
+async fn set_default_presence(ctx: &Context) {
+    let presence = ctx
+        .data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .presence
+        .clone();
+    if let Some(presence) = presence {
+        match presence.resolve(&ctx.data, &ctx.http).await {
+            Ok((activity, status)) => {
+                println!("HD | Setting default presence.");
+                ctx.set_presence(activity, status).await;
+            }
+            Err(why) => println!("HD | Failed to set default presence: {why}"),
+        }
+    }
+}
+
+async fn is_shutting_down(ctx: &Context) -> bool {
+    ctx.data.read().await.get::<ShuttingDownKey>().copied() == Some(true)
+}
+
+#[hook]
+pub async fn before(ctx: &Context, _: &Message, cmd_name: &str) -> bool {
+    if is_shutting_down(ctx).await {
+        println!("HD | Shutting down. Ignoring command {cmd_name:?}.");
+        false
+    } else {
+        true
     }
 }
 
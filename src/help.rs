@@ -1,12 +1,17 @@
 #![allow(clippy::unreadable_literal)]
 
 use crate::{
+    cases::HistoryApp,
+    dead_letters::RerunDeadLetterApp,
+    edit_task::{EditCondition, EditMisfire, EditPeriod, EditSchedule, EditTask, TaskIdApp},
     gulag::GulagApp,
+    invocation::Invocation,
+    leaderboard::{CreateLeaderboardApp, LeaderboardIdApp},
     misc::{escape_formatting, get_help_msg, is_administrator},
     release::ReleaseSearchCriteriumApp,
     tasks::{
-        date_conditional_task::DateConditionalTask, message::MessageType,
-        periodic_task::CreatePeriodicTask, task::Task, CreateTask,
+        cron_task::CreateCronTask, date_conditional_task::DateConditionalTask,
+        message::MessageType, periodic_task::CreatePeriodicTask, task::Task, CreateTask,
     },
     EMBED_COLOUR, FOOTER_TEXT,
 };
@@ -14,6 +19,7 @@ use chrono::{Duration, Utc};
 use clap::CommandFactory;
 use lazy_static::lazy_static;
 use serenity::{
+    builder::CreateEmbed,
     client::Context,
     framework::standard::{macros::command, CommandResult},
     model::channel::Message,
@@ -43,9 +49,24 @@ lazy_static! {
         let mut string = get_help_msg(CreateTask::command());
         string.push_str(get_help_msg(DateConditionalTask::command()).as_str());
         string.push_str(get_help_msg(CreatePeriodicTask::command()).as_str());
+        string.push_str(get_help_msg(CreateCronTask::command()).as_str());
         string
     };
     pub static ref RELEASE_HELP_MSG: String = get_help_msg(ReleaseSearchCriteriumApp::command());
+    pub static ref HISTORY_HELP_MSG: String = get_help_msg(HistoryApp::command());
+    pub static ref EDIT_TASK_HELP_MSG: String = {
+        let mut string = get_help_msg(EditTask::command());
+        string.push_str(get_help_msg(EditPeriod::command()).as_str());
+        string.push_str(get_help_msg(EditCondition::command()).as_str());
+        string.push_str(get_help_msg(EditSchedule::command()).as_str());
+        string.push_str(get_help_msg(EditMisfire::command()).as_str());
+        string
+    };
+    pub static ref TASK_ID_HELP_MSG: String = get_help_msg(TaskIdApp::command());
+    pub static ref CREATE_LEADERBOARD_HELP_MSG: String =
+        get_help_msg(CreateLeaderboardApp::command());
+    pub static ref LEADERBOARD_ID_HELP_MSG: String = get_help_msg(LeaderboardIdApp::command());
+    pub static ref RERUN_DEAD_LETTER_HELP_MSG: String = get_help_msg(RerunDeadLetterApp::command());
     pub static ref CREATE_TASK_EXAMPLE: String = {
         format!(
             "\
@@ -58,13 +79,30 @@ lazy_static! {
             each year. The message 'haha yes' will be sent to channel ID 549647427397877822 \
             every time that date and time occurs.\n\
             ```\n\
-            =>create_task periodic_task -s 1 -m 33 -h 7 -d 4 -w 2 --start {tomorrow}\n\
+            =>create_task periodic_task -s 1 -m 33 -h 7 -d 4 -w 2 --start {tomorrow}T09:00:00 --count 10\n\
             `\u{200b}`\u{200b}`json\n\
             {example_pt_json}\n\
             `\u{200b}`\u{200b}`\n\
             ```This creates a task that executes every two weeks, four days, 7 hours, 33 minutes, \
-            and 1 second. The bot's nickname will be changed to the new name and the avatar to the \
-            image in the specified filename at that time.\
+            and 1 second, starting at 9am tomorrow and stopping after 10 runs. The bot's nickname \
+            will be changed to the new name and the avatar to the image in the specified filename \
+            at that time.\n\
+            ```\n\
+            =>create_task cron_task --schedule 0 9 L * 5L\n\
+            `\u{200b}`\u{200b}`json\n\
+            {example_dct_json}\n\
+            `\u{200b}`\u{200b}`\n\
+            ```This creates a task that executes at 09:00 on the last day of every month and on the \
+            last Friday of every month. Cron expressions also take lists (`1,15`), ranges \
+            (`MON-FRI`), steps (`*/10`), nth weekdays (`MON#2`) and an optional leading seconds \
+            field. Times are in the configured time zone unless `--tz` gives another one, like \
+            `--tz Europe/London`.\n\
+            Message text can have placeholders that are filled in when it's sent: `{{date}}`, \
+            `{{weekday}}`, `{{member_count}}`, `{{gulag_count}}`, `{{random:a|b|c}}`, \
+            `{{activity.<channel>}}`, `{{user:<id>}}`, `{{role:<id>}}` and `{{channel:<id>}}`.\n\
+            `SetPresence` tasks change the bot's status and activity, whose text can have \
+            placeholders too. `ApplyTheme` and `RotateThemes` tasks change the bot's look to themes named in the \
+            config. The avatar is changed at most twice an hour, so faster rotations skip it.\
             ",
             time_now = Utc::now().time().format("%H:%M:%S"),
             example_dct_json = serde_json::to_string_pretty(&Task::SendMessage {
@@ -113,12 +151,12 @@ macro_rules! def_help_lists {
         }
     ) => {
         lazy_static! {
-            static ref NONADMIN_HELP_INFO: Vec<[String; 4]> = vec![
+            pub(crate) static ref NONADMIN_HELP_INFO: Vec<[String; 4]> = vec![
                 $(
                     [$cmd.into(), $short_desc.into(), $long_help, $example]
                 ),+
             ];
-            static ref ADMIN_HELP_INFO: Vec<[String; 4]> = vec![
+            pub(crate) static ref ADMIN_HELP_INFO: Vec<[String; 4]> = vec![
                 $(
                     [$cmd.into(), $short_desc.into(), $long_help, $example]
                 ),+,
@@ -166,12 +204,31 @@ def_help_lists! {
             "Sends naughty boys to gulag to be educated and turned into girls.",
             GULAG_HELP_MSG.clone(),
             "\
-                `=>gulag --user @some_user -s 1 -m 2 -h 3 -d 4 -w 5`\n\
+                `=>gulag --user @some_user -s 1 -m 2 -h 3 -d 4 -w 5 --reason spamming`\n\
                 The above gulags the user `@some_user` for one second, two minutes, three \
-                hours, four days, and five weeks. Note that `-s` could be replaced with \
-                `--secs`, `-m` with `--mins`, and so on.\
+                hours, four days, and five weeks, and opens a case saying it was for spamming. \
+                Note that `-s` could be replaced with `--secs`, `-m` with `--mins`, and so on.\n\n\
+                `=>gulag --user @some_user --reason spamming`\n\
+                With no length given, the sentence comes from the escalation policy in the config, \
+                getting longer the more sentences the user has had recently. For a user who's already in \
+                gulag it keeps the sentence's end and only changes the reason.\
             ".into(),
         },
+        {
+            "case",
+            "Looks up a gulag sentence by its case number.",
+            "\
+                Every gulag sentence gets a numbered case recording who was sent, by whom, why and \
+                when. Cases are kept after the prisoner is released. Takes the case number.\
+            ".into(),
+            "`=>case 12`".into(),
+        },
+        {
+            "history",
+            "Lists every gulag sentence a user has had.",
+            HISTORY_HELP_MSG.clone(),
+            "`=>history --user @some_user`".into(),
+        },
         {
             "create_task",
             "Gives me something to do other than work prisoners to death.",
@@ -185,8 +242,8 @@ def_help_lists! {
             "\
                 `=>release --user @some_user`\n\
                 This releases the user @some_user from gulag.\n\n\
-                `=>release --index N`\n\
-                Searches through the task list and ends the `N`th gulag sentence.
+                `=>release --id N`\n\
+                Ends the gulag sentence with task ID `N`, as shown by `current_gulags`.
             ".into(),
         },
         {
@@ -195,64 +252,146 @@ def_help_lists! {
             "No arguments are expected. Should only be called as `=>list_tasks`.".into(),
             String::new(),
         },
+        {
+            "edit_task",
+            "Changes the period, condition, or JSON of an existing task.",
+            EDIT_TASK_HELP_MSG.clone(),
+            "\
+                `=>edit_task period --id 3 -d 1`\n\
+                Makes periodic task 3 act once a day.\n\n\
+                `=>edit_task condition --id 4 --time 09:00:00 --weekday mon`\n\
+                Makes date conditional task 4 act at 9am on Mondays.\n\n\
+                `=>edit_task task --id 5` followed by a `json` code block on the next line \
+                replaces what task 5 does, in the same format as `create_task`.\
+            ".into(),
+        },
+        {
+            "delete_task",
+            "Deletes a task for good.",
+            TASK_ID_HELP_MSG.clone(),
+            "`=>delete_task --id 3`\nDeletes task 3. Gulag sentences have to be ended with \
+            `release` instead.".into(),
+        },
+        {
+            "pause_task",
+            "Stops a task from acting until it's resumed.",
+            TASK_ID_HELP_MSG.clone(),
+            "`=>pause_task --id 3`".into(),
+        },
+        {
+            "resume_task",
+            "Lets a paused task act again.",
+            TASK_ID_HELP_MSG.clone(),
+            "`=>resume_task --id 3`\nPeriodic tasks don't make up for periods missed while \
+            paused.".into(),
+        },
+        {
+            "create_leaderboard",
+            "Starts keeping score.",
+            CREATE_LEADERBOARD_HELP_MSG.clone(),
+            "\
+                `=>create_leaderboard --name Most pinned --channel #general --pins #memes --top 5 \
+                --award @Pinned -d 7`\n\
+                Posts the five users with the most pins in #memes to #general every week, \
+                starting now, and moves the Pinned role to whoever is first.\n\n\
+                `=>create_leaderboard --name Popular --channel #general --pings #chat --window 30 \
+                -d 1`\n\
+                Posts the users pinged most in #chat over the last 30 days every day.\
+            ".into(),
+        },
+        {
+            "list_leaderboards",
+            "Lists the leaderboards.",
+            "No arguments are expected. Should only be called as `=>list_leaderboards`.".into(),
+            String::new(),
+        },
+        {
+            "delete_leaderboard",
+            "Stops keeping score.",
+            LEADERBOARD_ID_HELP_MSG.clone(),
+            "`=>delete_leaderboard --id 2`\nDeletes leaderboard 2 and stops posting it. The \
+            award role stays with whoever has it.".into(),
+        },
+        {
+            "dead_letters",
+            "Lists tasks that kept failing.",
+            "\
+                Tasks that fail to act are retried a few times, waiting longer each time. Ones \
+                that still fail end up on this list, along with the last error. No arguments are \
+                expected.\
+            ".into(),
+            "`=>dead_letters`".into(),
+        },
+        {
+            "rerun_dead_letter",
+            "Tries a task that kept failing again.",
+            RERUN_DEAD_LETTER_HELP_MSG.clone(),
+            "\
+                `=>rerun_dead_letter --id 12`\n\
+                Runs the task in dead letter 12 once and removes the entry if it works.\n\n\
+                `=>rerun_dead_letter --id 12 --discard`\n\
+                Removes dead letter 12 without running it.\
+            ".into(),
+        },
     }
 }
 
 #[command]
 pub async fn help(ctx: &Context, message: &Message) -> CommandResult {
+    run_help(ctx, &message.into()).await
+}
+
+pub async fn run_help(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("HL | Start handling help command.");
     let start = Instant::now();
-    let trimmed_content = message.content.trim_start_matches("=>help").trim();
+    let trimmed_content = invocation.text("=>help", "command");
+    let trimmed_content = trimmed_content.as_str();
     let icon_url = ctx.http.get_current_user().await?.avatar_url().unwrap();
     println!("HL | Got current avatar URL.");
-    let help_list = if is_administrator(&ctx.http, ctx.data.read().await, message).await? {
+    let help_list = if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
         &ADMIN_HELP_INFO[..]
     } else {
         &NONADMIN_HELP_INFO[..]
     };
     println!("HL | Got help list.");
-    let _ =
-        message
-            .channel_id
-            .send_message(&ctx.http, |msg| {
-                msg.embed(|embed| {
-                    embed
-                        .title("Okay, fine...")
-                        .description("...I guess I can help you with that.")
-                        .colour(EMBED_COLOUR)
-                        .footer(|footer| footer.text(FOOTER_TEXT).icon_url(icon_url));
-                    println!("HL | Constructed base embed.");
-                    if trimmed_content.is_empty() {
-                        println!("HL | User requested general help.");
-                        embed.fields(help_list.iter().map(|[name, short_desc, ..]| {
-                            (name.as_str(), short_desc.as_str(), false)
-                        }))
-                    } else if let Some([.., long_help, example]) = help_list
-                        .iter()
-                        .find(|[name, ..]| name.as_str() == trimmed_content)
-                    {
-                        println!("HL | User requested help with '{}'", trimmed_content,);
-                        embed.fields(vec![
-                            ("Command information", long_help.as_str(), false),
-                            ("Usage", example.as_str(), false),
-                        ])
-                    } else {
-                        println!("HL | User requested help for unknown command.");
-                        embed.field(
-                            "Excuse me what",
-                            format!(
-                                "\
-                                No clue what\n\
-                                ```{}```is. Make sure you entered a valid command name.\
-                            ",
-                                escape_formatting(trimmed_content),
-                            ),
-                            false,
-                        )
-                    }
-                })
-            })
-            .await?;
+    let mut embed = CreateEmbed::default();
+    embed
+        .title("Okay, fine...")
+        .description("...I guess I can help you with that.")
+        .colour(EMBED_COLOUR)
+        .footer(|footer| footer.text(FOOTER_TEXT).icon_url(icon_url));
+    println!("HL | Constructed base embed.");
+    if trimmed_content.is_empty() {
+        println!("HL | User requested general help.");
+        embed.fields(
+            help_list
+                .iter()
+                .map(|[name, short_desc, ..]| (name.as_str(), short_desc.as_str(), false)),
+        );
+    } else if let Some([.., long_help, example]) = help_list
+        .iter()
+        .find(|[name, ..]| name.as_str() == trimmed_content)
+    {
+        println!("HL | User requested help with '{}'", trimmed_content,);
+        embed.fields(vec![
+            ("Command information", long_help.as_str(), false),
+            ("Usage", example.as_str(), false),
+        ]);
+    } else {
+        println!("HL | User requested help for unknown command.");
+        embed.field(
+            "Excuse me what",
+            format!(
+                "\
+                No clue what\n\
+                ```{}```is. Make sure you entered a valid command name.\
+            ",
+                escape_formatting(trimmed_content),
+            ),
+            false,
+        );
+    }
+    invocation.send_embed(&ctx.http, embed).await?;
     println!("HL | Elapsed: {:?}", start.elapsed());
     Ok(())
 }
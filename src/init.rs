@@ -1,7 +1,12 @@
-use crate::{config::Config, tasks::TaskType};
+use crate::{
+    config::Config,
+    storage::Storage,
+    tasks::{TaskId, TaskType},
+};
 use anyhow::{Error as AnyError, Result as AnyResult};
 use serenity::model::guild::Role;
 use std::{
+    collections::BTreeMap,
     fs::{self, File},
     io::{ErrorKind as IoErrorKind, Write},
 };
@@ -27,23 +32,11 @@ pub fn read_config_file(cf: &str) -> AnyResult<String> {
     }
 }
 
-pub fn read_tasks_file(config: &Config) -> AnyResult<Vec<TaskType>> {
-    match fs::read_to_string(&config.tasks_file) {
-        Ok(contents) if contents.is_empty() => Ok(Vec::new()),
-        Ok(contents) => Ok(serde_json::from_str::<Vec<TaskType>>(&contents)?),
-        Err(error) => match error.kind() {
-            IoErrorKind::NotFound => {
-                println!(
-                    "IN | Tasks file not found. Attempting to create new tasks file at '{}'",
-                    config.tasks_file
-                );
-                let _ = File::create(&config.tasks_file)?;
-                println!("IN | Created new blank tasks file.");
-                Ok(Vec::new())
-            }
-            _ => Err(error.into()),
-        },
-    }
+pub fn open_storage(config: &Config) -> AnyResult<(Storage, BTreeMap<TaskId, TaskType>)> {
+    let storage = Storage::open(&config.database_path)?;
+    storage.import_tasks_file(&config.tasks_file)?;
+    let tasks = storage.load_tasks()?;
+    Ok((storage, tasks))
 }
 
 pub fn find_role_by<Find: FnMut(&&Role) -> bool, Err: FnOnce() -> AnyError>(
@@ -0,0 +1,491 @@
+use crate::{
+    anagram::run_anagram,
+    cache_keys::{ConfigKey, LeaderboardsKey, StorageKey, TasksKey},
+    cases::{run_case, run_history, HistoryApp},
+    current_gulags::run_current_gulags,
+    dead_letters::{run_dead_letters, run_rerun_dead_letter, RerunDeadLetterApp},
+    edit_task::{run_delete_task, run_edit_task, run_set_paused, EditTask, TaskIdApp},
+    gulag::{run_gulag, GulagApp},
+    help::{run_help, ADMIN_HELP_INFO, NONADMIN_HELP_INFO},
+    invocation::Invocation,
+    leaderboard::{
+        run_create_leaderboard, run_delete_leaderboard, run_list_leaderboards,
+        CreateLeaderboardApp, LeaderboardIdApp,
+    },
+    list_tasks::run_list_tasks,
+    misc::has_admin_role,
+    release::{run_release, ReleaseSearchCriteriumApp},
+    source::run_source,
+    tasks::{run_create_task, CreateTask},
+};
+use anyhow::Result as AnyResult;
+use clap::{builder::ArgAction, Arg, Command, CommandFactory};
+use serde_json::Value;
+use serenity::{
+    builder::CreateApplicationCommandOption,
+    client::Context,
+    framework::standard::CommandResult,
+    model::{
+        application::{
+            command::CommandOptionType,
+            interaction::{
+                application_command::{ApplicationCommandInteraction, CommandDataOption},
+                autocomplete::AutocompleteInteraction,
+            },
+        },
+        id::{ChannelId, RoleId, UserId},
+    },
+};
+use std::{any::TypeId, time::Instant};
+
+// Options that are handed to the command as-is rather than being turned into arguments for clap.
+const RAW_OPTIONS: &[&str] = &["task"];
+
+// (command, option) pairs that get autocompletion.
+const AUTOCOMPLETED: &[(&str, &str)] = &[
+    ("help", "command"),
+    ("release", "id"),
+    ("edit_task", "id"),
+    ("delete_task", "id"),
+    ("pause_task", "id"),
+    ("resume_task", "id"),
+    ("rerun_dead_letter", "id"),
+    ("delete_leaderboard", "id"),
+];
+
+// Discord limits option descriptions to 100 characters and choices to 25 per option.
+const MAX_DESCRIPTION_LEN: usize = 100;
+const MAX_CHOICES: usize = 25;
+
+pub async fn register_commands(ctx: &Context) -> AnyResult<()> {
+    println!("IA | Registering application commands.");
+    let guild_id = ctx.data.read().await.get::<ConfigKey>().unwrap().guild_id;
+    let commands = guild_id
+        .set_application_commands(&ctx.http, |commands| {
+            for [name, short_desc, ..] in ADMIN_HELP_INFO.iter() {
+                commands.create_application_command(|command| {
+                    command
+                        .name(name)
+                        .description(truncate_description(short_desc))
+                        .set_options(command_options(name))
+                });
+            }
+            commands
+        })
+        .await?;
+    println!(
+        "IA | Registered {} application commands for guild {guild_id}.",
+        commands.len()
+    );
+    Ok(())
+}
+
+fn command_options(name: &str) -> Vec<CreateApplicationCommandOption> {
+    match name {
+        "help" => {
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .name("command")
+                .description("Command to get more information about")
+                .kind(CommandOptionType::String)
+                .required(false)
+                .set_autocomplete(true);
+            vec![option]
+        }
+        "anagram" => {
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .name("text")
+                .description("Text to scramble")
+                .kind(CommandOptionType::String)
+                .required(true);
+            vec![option]
+        }
+        "case" => {
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .name("number")
+                .description("Number of the case")
+                .kind(CommandOptionType::String)
+                .required(true);
+            vec![option]
+        }
+        "history" => options_from_clap(name, &HistoryApp::command()),
+        "gulag" => options_from_clap(name, &GulagApp::command()),
+        "release" => options_from_clap(name, &ReleaseSearchCriteriumApp::command()),
+        "create_task" => subcommand_options(name, &CreateTask::command(), |_| true),
+        "edit_task" => subcommand_options(name, &EditTask::command(), |sub| sub == "task"),
+        "delete_task" | "pause_task" | "resume_task" => {
+            options_from_clap(name, &TaskIdApp::command())
+        }
+        "create_leaderboard" => options_from_clap(name, &CreateLeaderboardApp::command()),
+        "delete_leaderboard" => options_from_clap(name, &LeaderboardIdApp::command()),
+        "rerun_dead_letter" => options_from_clap(name, &RerunDeadLetterApp::command()),
+        _ => Vec::new(),
+    }
+}
+
+/// Options for a clap command made of subcommands. Subcommands for which `takes_task` is true get
+/// an extra `task` option for the task JSON.
+fn subcommand_options(
+    command_name: &str,
+    command: &Command,
+    takes_task: fn(&str) -> bool,
+) -> Vec<CreateApplicationCommandOption> {
+    command
+        .get_subcommands()
+        .map(|subcommand| {
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .name(subcommand.get_name())
+                .description(truncate_description(
+                    &subcommand
+                        .get_about()
+                        .map_or_else(|| subcommand.get_name().into(), ToString::to_string),
+                ))
+                .kind(CommandOptionType::SubCommand);
+            if takes_task(subcommand.get_name()) {
+                let mut task = CreateApplicationCommandOption::default();
+                task.name("task")
+                    .description("JSON for the task to run")
+                    .kind(CommandOptionType::String)
+                    .required(true);
+                option.add_sub_option(task);
+            }
+            for sub_option in options_from_clap(command_name, subcommand) {
+                option.add_sub_option(sub_option);
+            }
+            option
+        })
+        .collect()
+}
+
+/// Builds slash command options from the arguments of a clap command so that the clap structs
+/// stay the only definition of each command's arguments.
+fn options_from_clap(command_name: &str, command: &Command) -> Vec<CreateApplicationCommandOption> {
+    let mut args = command
+        .get_arguments()
+        .filter(|arg| {
+            !matches!(
+                arg.get_action(),
+                ArgAction::Help | ArgAction::HelpShort | ArgAction::HelpLong | ArgAction::Version
+            )
+        })
+        .collect::<Vec<_>>();
+    // Discord wants required options before optional ones.
+    args.sort_by_key(|arg| !arg.is_required_set());
+    args.into_iter()
+        .map(|arg| {
+            let name = arg.get_long().unwrap_or_else(|| arg.get_id().as_str());
+            let description = arg
+                .get_help()
+                .map_or_else(|| arg.get_id().to_string(), ToString::to_string);
+            let mut option = CreateApplicationCommandOption::default();
+            option
+                .name(name)
+                .description(truncate_description(&description))
+                .kind(option_type(arg))
+                .required(arg.is_required_set());
+            if AUTOCOMPLETED.contains(&(command_name, name)) {
+                option.set_autocomplete(true);
+            }
+            for value in arg.get_possible_values().iter().take(MAX_CHOICES) {
+                option.add_string_choice(value.get_name(), value.get_name());
+            }
+            option
+        })
+        .collect()
+}
+
+fn option_type(arg: &Arg) -> CommandOptionType {
+    let type_id = arg.get_value_parser().type_id();
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        CommandOptionType::Boolean
+    } else if type_id == TypeId::of::<UserId>() {
+        CommandOptionType::User
+    } else if type_id == TypeId::of::<RoleId>() {
+        CommandOptionType::Role
+    } else if type_id == TypeId::of::<ChannelId>() {
+        CommandOptionType::Channel
+    } else if [
+        TypeId::of::<i64>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<usize>(),
+    ]
+    .iter()
+    .any(|id| type_id == *id)
+    {
+        CommandOptionType::Integer
+    } else {
+        CommandOptionType::String
+    }
+}
+
+fn truncate_description(description: &str) -> String {
+    let description = description.trim();
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        let mut truncated = description
+            .chars()
+            .take(MAX_DESCRIPTION_LEN - 3)
+            .collect::<String>();
+        truncated.push_str("...");
+        truncated
+    } else {
+        description.into()
+    }
+}
+
+/// Turns interaction options back into arguments clap can parse. Subcommands become their name,
+/// and everything else becomes `--name=value`.
+pub fn option_argv(options: &[CommandDataOption]) -> Vec<String> {
+    let mut argv = Vec::new();
+    for option in options {
+        match option.kind {
+            CommandOptionType::SubCommand | CommandOptionType::SubCommandGroup => {
+                argv.push(option.name.clone());
+                argv.extend(option_argv(&option.options));
+            }
+            _ if RAW_OPTIONS.contains(&option.name.as_str()) => {}
+            CommandOptionType::Boolean => {
+                if option.value == Some(Value::Bool(true)) {
+                    argv.push(format!("--{}", option.name));
+                }
+            }
+            _ => {
+                if let Some(value) = &option.value {
+                    argv.push(format!("--{}={}", option.name, value_string(value)));
+                }
+            }
+        }
+    }
+    argv
+}
+
+fn value_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+pub async fn handle_command(ctx: &Context, command: &ApplicationCommandInteraction) {
+    println!(
+        "IA | Received '/{}' from {}.",
+        command.data.name,
+        command.user.tag()
+    );
+    let start = Instant::now();
+    let invocation = Invocation::from(command);
+    let result: CommandResult = match command.data.name.as_str() {
+        "anagram" => run_anagram(ctx, &invocation).await,
+        "case" => run_case(ctx, &invocation).await,
+        "create_leaderboard" => run_create_leaderboard(ctx, &invocation).await,
+        "create_task" => run_create_task(ctx, &invocation).await,
+        "current_gulags" => run_current_gulags(ctx, &invocation).await,
+        "dead_letters" => run_dead_letters(ctx, &invocation).await,
+        "delete_leaderboard" => run_delete_leaderboard(ctx, &invocation).await,
+        "delete_task" => run_delete_task(ctx, &invocation).await,
+        "edit_task" => run_edit_task(ctx, &invocation).await,
+        "gulag" => run_gulag(ctx, &invocation).await,
+        "help" => run_help(ctx, &invocation).await,
+        "history" => run_history(ctx, &invocation).await,
+        "list_leaderboards" => run_list_leaderboards(ctx, &invocation).await,
+        "list_tasks" => run_list_tasks(ctx, &invocation).await,
+        "pause_task" => run_set_paused(ctx, &invocation, "=>pause_task", true).await,
+        "release" => run_release(ctx, &invocation).await,
+        "rerun_dead_letter" => run_rerun_dead_letter(ctx, &invocation).await,
+        "resume_task" => run_set_paused(ctx, &invocation, "=>resume_task", false).await,
+        "source" => run_source(ctx, &invocation).await,
+        other => Err(format!("Unknown command '{other}'.").into()),
+    };
+    let response = match result {
+        Ok(()) if !invocation.has_responded() => {
+            invocation.reply_ephemeral(&ctx.http, "Done.").await
+        }
+        Ok(()) => Ok(()),
+        Err(why) => {
+            println!(
+                "IA | Command {:?} triggered by {}: {:?}",
+                command.data.name,
+                command.user.tag(),
+                why
+            );
+            if invocation.has_responded() {
+                Ok(())
+            } else {
+                invocation
+                    .reply_ephemeral(
+                        &ctx.http,
+                        format!("That didn't work. Details:\n```{why}```"),
+                    )
+                    .await
+            }
+        }
+    };
+    if let Err(why) = response {
+        println!("IA | Failed to respond to interaction: {why}");
+    }
+    println!("IA | Elapsed: {:?}", start.elapsed());
+}
+
+pub async fn handle_autocomplete(ctx: &Context, autocomplete: &AutocompleteInteraction) {
+    let focused = match find_focused(&autocomplete.data.options) {
+        Some(focused) => focused,
+        None => return,
+    };
+    let partial = focused
+        .value
+        .as_ref()
+        .map(value_string)
+        .unwrap_or_default()
+        .to_lowercase();
+    let choices = match (autocomplete.data.name.as_str(), focused.name.as_str()) {
+        ("help", "command") => {
+            let is_admin = match &autocomplete.member {
+                Some(member) => has_admin_role(ctx.data.read().await, &member.roles),
+                None => false,
+            };
+            ADMIN_HELP_INFO
+                .iter()
+                .take(if is_admin {
+                    ADMIN_HELP_INFO.len()
+                } else {
+                    NONADMIN_HELP_INFO.len()
+                })
+                .map(|[name, ..]| (name.clone(), Value::String(name.clone())))
+                .filter(|(name, _)| name.starts_with(&partial))
+                .collect::<Vec<_>>()
+        }
+        ("release", "id") => ctx
+            .data
+            .read()
+            .await
+            .get::<TasksKey>()
+            .unwrap()
+            .iter()
+            .filter_map(|(&id, task)| {
+                task.gulag_ref()
+                    .map(|gulag| (format!("{id}: {}", gulag.user.0), Value::from(id)))
+            })
+            .filter(|(name, _)| name.to_lowercase().contains(&partial))
+            .collect(),
+        ("delete_leaderboard", "id") => ctx
+            .data
+            .read()
+            .await
+            .get::<LeaderboardsKey>()
+            .unwrap()
+            .iter()
+            .map(|(&id, leaderboard)| (format!("{id}: {}", leaderboard.name), Value::from(id)))
+            .filter(|(name, _)| name.to_lowercase().contains(&partial))
+            .collect(),
+        ("rerun_dead_letter", "id") => {
+            let context_data = ctx.data.read().await;
+            let tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+            match context_data
+                .get::<StorageKey>()
+                .unwrap()
+                .load_dead_letters()
+            {
+                Ok(dead_letters) => dead_letters
+                    .iter()
+                    .map(|(&id, dead_letter)| {
+                        let name = format!("{id}: {}", dead_letter.task.list_fmt(tz).trim());
+                        (truncate_description(&name), Value::from(id))
+                    })
+                    .filter(|(name, _)| name.to_lowercase().contains(&partial))
+                    .collect(),
+                Err(why) => {
+                    println!("IA | Failed to load dead letters: {why}");
+                    Vec::new()
+                }
+            }
+        }
+        (_, "id") => {
+            let context_data = ctx.data.read().await;
+            let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+            context_data
+                .get::<TasksKey>()
+                .unwrap()
+                .iter()
+                .filter(|(_, task)| !task.is_gulag())
+                .map(|(&id, task)| {
+                    let name = format!("{id}: {}", task.list_fmt(default_tz).trim());
+                    (truncate_description(&name), Value::from(id))
+                })
+                .filter(|(name, _)| name.to_lowercase().contains(&partial))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    let result = autocomplete
+        .create_autocomplete_response(&ctx.http, |response| {
+            for (name, value) in choices.into_iter().take(MAX_CHOICES) {
+                match value {
+                    Value::Number(n) => {
+                        response.add_int_choice(name, n.as_i64().unwrap_or_default());
+                    }
+                    other => {
+                        response.add_string_choice(name, value_string(&other));
+                    }
+                }
+            }
+            response
+        })
+        .await;
+    if let Err(why) = result {
+        println!("IA | Failed to respond to autocomplete: {why}");
+    }
+}
+
+fn find_focused(options: &[CommandDataOption]) -> Option<&CommandDataOption> {
+    options.iter().find_map(|option| {
+        if option.focused {
+            Some(option)
+        } else {
+            find_focused(&option.options)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{option_argv, options_from_clap};
+    use crate::{gulag::GulagApp, leaderboard::CreateLeaderboardApp};
+    use clap::{CommandFactory, Parser};
+    use serenity::model::application::interaction::application_command::CommandDataOption;
+
+    #[test]
+    fn test_option_argv_parses_with_clap() {
+        let options = serde_json::from_value::<Vec<CommandDataOption>>(serde_json::json!([
+            { "name": "user", "type": 6, "value": "222222222222222222" },
+            { "name": "days", "type": 4, "value": 3 },
+        ]))
+        .unwrap();
+        let argv = option_argv(&options);
+        assert_eq!(argv, ["--user=222222222222222222", "--days=3"]);
+        assert!(GulagApp::try_parse_from(argv).is_ok());
+        // The length can be left to the escalation policy.
+        assert!(GulagApp::try_parse_from(["--user=222222222222222222"]).is_ok());
+    }
+
+    #[test]
+    fn test_leaderboard_argv_needs_a_period() {
+        let leaderboard = ["--name=a", "--channel=1", "--pins=2"];
+        assert!(CreateLeaderboardApp::try_parse_from(leaderboard).is_err());
+        assert!(
+            CreateLeaderboardApp::try_parse_from([&leaderboard[..], &["-d", "1"]].concat()).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_options_from_clap_required_first() {
+        let options = options_from_clap("gulag", &GulagApp::command());
+        assert_eq!(options[0].0["name"], "user");
+        assert_eq!(options[0].0["required"], true);
+        assert!(options[1..]
+            .iter()
+            .all(|option| option.0["required"] == false));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::interactions::option_argv;
+use anyhow::Result as AnyResult;
+use serenity::{
+    builder::CreateEmbed,
+    http::{CacheHttp, Http},
+    model::{
+        application::interaction::{
+            application_command::{ApplicationCommandInteraction, CommandDataOption},
+            InteractionResponseType,
+        },
+        channel::Message,
+        id::RoleId,
+        user::User,
+    },
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Commands can be used either through the old `=>` prefix or as slash commands. Everything a
+// command needs from whatever invoked it goes through here so that the command bodies don't need
+// to care which one it was.
+
+pub enum Invocation<'a> {
+    Message(&'a Message),
+    Interaction {
+        command: &'a ApplicationCommandInteraction,
+        responded: AtomicBool,
+    },
+}
+
+impl<'a> From<&'a Message> for Invocation<'a> {
+    fn from(message: &'a Message) -> Self {
+        Invocation::Message(message)
+    }
+}
+
+impl<'a> From<&'a ApplicationCommandInteraction> for Invocation<'a> {
+    fn from(command: &'a ApplicationCommandInteraction) -> Self {
+        Invocation::Interaction {
+            command,
+            responded: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Invocation<'_> {
+    pub fn has_responded(&self) -> bool {
+        match self {
+            Invocation::Message(_) => false,
+            Invocation::Interaction { responded, .. } => responded.load(Ordering::SeqCst),
+        }
+    }
+
+    /// The user that used the command.
+    pub fn author(&self) -> &User {
+        match self {
+            Invocation::Message(message) => &message.author,
+            Invocation::Interaction { command, .. } => &command.user,
+        }
+    }
+
+    /// Role IDs of the member that used the command.
+    pub async fn author_roles(&self, http: impl CacheHttp) -> AnyResult<Vec<RoleId>> {
+        match self {
+            Invocation::Message(message) => Ok(message.member(http).await?.roles),
+            Invocation::Interaction { command, .. } => match &command.member {
+                Some(member) => Ok(member.roles.clone()),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    /// Arguments for the clap front end. For messages this is everything after `prefix` split on
+    /// whitespace, and for interactions the options are turned back into `--name=value` arguments.
+    pub fn args(&self, prefix: &str) -> Vec<String> {
+        match self {
+            Invocation::Message(message) => message
+                .content
+                .trim_start_matches(prefix)
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            Invocation::Interaction { command, .. } => option_argv(&command.data.options),
+        }
+    }
+
+    /// Arguments plus task JSON for commands that take both. Messages have the arguments on the
+    /// first line and the JSON in a ```` ```json ```` block after it, and interactions have a `task`
+    /// option.
+    pub fn args_and_json(&self, prefix: &str) -> (Vec<String>, Option<String>) {
+        match self {
+            Invocation::Message(message) => {
+                let content = message.content.trim_start_matches(prefix);
+                let (first_line, rest) = content.split_once('\n').unwrap_or((content, ""));
+                let json = rest
+                    .trim()
+                    .strip_prefix("```json")
+                    .and_then(|json| json.strip_suffix("```"))
+                    .map(|json| json.trim().to_string());
+                let args = first_line.split_whitespace().map(String::from).collect();
+                (args, json)
+            }
+            Invocation::Interaction { command, .. } => (
+                option_argv(&command.data.options),
+                find_string_option(&command.data.options, "task"),
+            ),
+        }
+    }
+
+    /// Free text input. For messages this is everything after `prefix`, and for interactions it's
+    /// the string option called `option`.
+    pub fn text(&self, prefix: &str, option: &str) -> String {
+        match self {
+            Invocation::Message(message) => {
+                message.content.trim_start_matches(prefix).trim().into()
+            }
+            Invocation::Interaction { command, .. } => {
+                find_string_option(&command.data.options, option)
+                    .unwrap_or_default()
+                    .trim()
+                    .into()
+            }
+        }
+    }
+
+    /// Replies to the user. Interactions get a public response.
+    pub async fn reply(&self, http: &Http, content: impl ToString) -> AnyResult<()> {
+        self.respond(http, content.to_string(), false).await
+    }
+
+    /// Replies to the user. Interactions get a response only the user can see.
+    pub async fn reply_ephemeral(&self, http: &Http, content: impl ToString) -> AnyResult<()> {
+        self.respond(http, content.to_string(), true).await
+    }
+
+    /// Sends a message to the channel the command was used in without replying to anyone.
+    pub async fn say(&self, http: &Http, content: impl ToString) -> AnyResult<()> {
+        match self {
+            Invocation::Message(message) => {
+                let _ = message
+                    .channel_id
+                    .send_message(http, |m| m.content(content.to_string()))
+                    .await?;
+                Ok(())
+            }
+            Invocation::Interaction { .. } => self.respond(http, content.to_string(), false).await,
+        }
+    }
+
+    /// Sends an embed to the channel the command was used in.
+    pub async fn send_embed(&self, http: &Http, embed: CreateEmbed) -> AnyResult<()> {
+        match self {
+            Invocation::Message(message) => {
+                let _ = message
+                    .channel_id
+                    .send_message(http, |m| m.set_embed(embed))
+                    .await?;
+            }
+            Invocation::Interaction { command, responded } => {
+                if responded.swap(true, Ordering::SeqCst) {
+                    let _ = command
+                        .create_followup_message(http, |f| f.add_embed(embed))
+                        .await?;
+                } else {
+                    command
+                        .create_interaction_response(http, |r| {
+                            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|d| d.add_embed(embed))
+                        })
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledges an interaction ahead of time for commands that make enough requests that they
+    /// might not respond within Discord's three second limit. Does nothing for messages.
+    pub async fn defer(&self, http: &Http) -> AnyResult<()> {
+        if let Invocation::Interaction { command, responded } = self {
+            if !responded.swap(true, Ordering::SeqCst) {
+                command.defer(http).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn respond(&self, http: &Http, content: String, ephemeral: bool) -> AnyResult<()> {
+        match self {
+            Invocation::Message(message) => {
+                let _ = message.reply(http, content).await?;
+            }
+            Invocation::Interaction { command, responded } => {
+                if responded.swap(true, Ordering::SeqCst) {
+                    let _ = command
+                        .create_followup_message(http, |f| f.content(content).ephemeral(ephemeral))
+                        .await?;
+                } else {
+                    command
+                        .create_interaction_response(http, |r| {
+                            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|d| {
+                                    d.content(content).ephemeral(ephemeral)
+                                })
+                        })
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn find_string_option(options: &[CommandDataOption], name: &str) -> Option<String> {
+    options.iter().find_map(|option| {
+        if option.name == name {
+            option
+                .value
+                .as_ref()
+                .and_then(|value| value.as_str())
+                .map(String::from)
+        } else {
+            find_string_option(&option.options, name)
+        }
+    })
+}
@@ -1 +1,63 @@
-pub struct 
\ No newline at end of file
+use anyhow::Result as AnyResult;
+use serenity::{
+    http::Http,
+    model::id::{GuildId, RoleId, UserId},
+};
+
+/// Takes the award role from whoever had it last and gives it to the new winner.
+pub async fn move_award(
+    http: &Http,
+    guild: GuildId,
+    role: RoleId,
+    from: Option<UserId>,
+    to: UserId,
+) -> AnyResult<()> {
+    match from {
+        Some(from) if from == to => {
+            println!("LB | AW | User {to} won again.");
+        }
+        Some(from) => {
+            // The last winner may have left the server since. That shouldn't stop the new winner
+            // from getting the role.
+            if let Err(why) = try_take_role(http, guild, from, role).await {
+                println!("LB | AW | Failed to take role ID {role} from user {from}: {why}");
+            }
+        }
+        None => println!("LB | AW | No awards given previously."),
+    }
+    try_give_role(http, guild, to, role).await
+}
+
+async fn try_give_role(http: &Http, guild: GuildId, user: UserId, role: RoleId) -> AnyResult<()> {
+    let member = guild.member(http, user).await?;
+    if member.roles.contains(&role) {
+        println!("LB | AW | User {user} already has role ID {role}");
+    } else {
+        println!("LB | AW | Giving role ID {role} to user {user}");
+        http.add_member_role(
+            guild.into(),
+            user.into(),
+            role.into(),
+            Some("Won a leaderboard."),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn try_take_role(http: &Http, guild: GuildId, user: UserId, role: RoleId) -> AnyResult<()> {
+    let member = guild.member(http, user).await?;
+    if member.roles.contains(&role) {
+        println!("LB | AW | Taking role ID {role} from user {user}");
+        http.remove_member_role(
+            guild.into(),
+            user.into(),
+            role.into(),
+            Some("Lost a leaderboard."),
+        )
+        .await?;
+    } else {
+        println!("LB | AW | User {user} doesn't have role ID {role}");
+    }
+    Ok(())
+}
@@ -1,87 +1,632 @@
-use crate::cache_keys::ConfigKey;
-use anyhow::{anyhow, Result as AnyResult};
+use crate::{
+    cache_keys::{ConfigKey, LeaderboardsKey, StorageKey, TaskSenderKey, TasksKey},
+    invocation::Invocation,
+    misc::{insufficient_perms, is_administrator, ClapResult, CreateTimePeriod},
+    scheduler::{task_changed, TaskMessage},
+    storage::Storage,
+    tasks::{periodic_task::PeriodicTask, task::Task, TaskType},
+    EMBED_COLOUR, FOOTER_TEXT,
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Duration, Utc};
+use clap::{error::ErrorKind, value_parser, ArgAction, ColorChoice, Parser};
 use serde::{Deserialize, Serialize};
 use serenity::{
-    client::Cache,
-    http::{CacheHttp, Http},
-    model::id::{ChannelId, GuildId, RoleId, UserId},
-    prelude::{RwLock, TypeMap},
+    builder::CreateEmbed,
+    framework::standard::{macros::command, CommandResult},
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, GuildId, RoleId, UserId},
+    },
+    prelude::{Context, RwLock, TypeMap},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    sync::Arc,
+    time::Instant,
 };
-use std::{sync::Arc, time::Duration};
 
+mod award;
+pub mod pings;
+mod pins;
+mod ranking;
+pub mod reactions;
 mod scoring_method;
 
-use scoring_method::ScoringMethod;
+pub use ranking::{RankedEntry, TieRanking};
+pub use scoring_method::ScoringMethod;
+
+pub type LeaderboardId = u64;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct LeaderboardEntry {
     pub name: String,
     pub id: UserId,
     pub score: usize,
+    /// When they reached their score, which decides who goes first between tied users.
+    #[serde(default)]
+    pub achieved_at: Option<DateTime<Utc>>,
 }
 
 impl LeaderboardEntry {
-    pub fn new(name: String, id: UserId, score: usize) -> Self {
-        LeaderboardEntry { name, id, score }
+    pub fn new(name: String, id: UserId, score: usize, achieved_at: Option<DateTime<Utc>>) -> Self {
+        LeaderboardEntry {
+            name,
+            id,
+            score,
+            achieved_at,
+        }
     }
 }
 
 // serde is a temporary stand-in while I learn DB stuff.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Leaderboard {
-    pub leaderboard: Vec<LeaderboardEntry>,
+    pub name: String,
+    /// Channel the leaderboard gets posted in whenever it's recomputed.
+    pub post_in: ChannelId,
+    pub leaderboard: Vec<RankedEntry>,
+    /// The standings from the update before the last, to show how people moved.
+    #[serde(default)]
+    pub previous: Vec<RankedEntry>,
     pub number: usize,
-    pub guild: GuildId,
+    #[serde(default)]
+    pub ties: TieRanking,
     pub scoring_method: ScoringMethod,
-    pub update_period: Duration,
     pub award: Option<(String, RoleId)>,
     pub last_given_to: Option<UserId>,
+    pub last_updated: Option<DateTime<Utc>>,
 }
 
 impl Leaderboard {
-    async fn update(&mut self, cache_http: &impl CacheHttp) -> AnyResult<()> {
-        let untrimmed_leaderboard = self.scoring_method.get_leaderboard(http).await?;
-        self.leaderboard = untrimmed_leaderboard
-            .into_iter()
-            .take(self.number)
-            .collect();
+    async fn update(&mut self, http: &Http, storage: &Storage) -> AnyResult<()> {
+        let scores = self.scoring_method.get_leaderboard(http, storage).await?;
+        let mut standings = ranking::rank(scores, self.ties);
+        standings.truncate(self.number);
+        self.previous = std::mem::replace(&mut self.leaderboard, standings);
+        self.last_updated = Some(Utc::now());
         Ok(())
     }
 
-    async fn award_winner(&mut self, cache_http: &impl CacheHttp) -> AnyResult<()> {
+    async fn award_winner(&mut self, http: &Http, guild: GuildId) -> AnyResult<()> {
         if let Some((role_name, role_id)) = &self.award {
+            let winner = match self.leaderboard.first() {
+                Some(winner) => &winner.entry,
+                None => {
+                    println!("LB | AW | Nobody scored. Leaving role '{role_name}' where it is.");
+                    return Ok(());
+                }
+            };
             println!(
                 "LB | AW | Attempting to award role '{}' to user '{}' ({})",
-                role_name, self.leaderboard[0].name, self.leaderboard[0].id
+                role_name, winner.name, winner.id
             );
-            let award_to = self.leaderboard[0].id;
-            println!("LB | AW | Getting guild ID");
-            let guild_id = cache_http
-                .cache()
-                .read()
-                .await
-                .get::<ConfigKey>()
-                .unwrap()
-                .guild_id;
-            if let Some(last) = self.last_given_to {
-                println!("LB | AW | No awards given previously.");
-            }
+            award::move_award(http, guild, *role_id, self.last_given_to, winner.id).await?;
+            self.last_given_to = Some(winner.id);
         }
         Ok(())
     }
+
+    fn build_embed(&self, icon_url: Option<String>) -> CreateEmbed {
+        let standings = if self.leaderboard.is_empty() {
+            "Nobody has scored yet.".to_string()
+        } else {
+            ranking::render(&self.leaderboard, &self.previous)
+        };
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(&self.name)
+            .description(format!("Scored by {}.", self.scoring_method))
+            .colour(EMBED_COLOUR)
+            .field("Standings", standings, false)
+            .timestamp(Utc::now().to_rfc3339())
+            .footer(|footer| {
+                footer.text(FOOTER_TEXT);
+                if let Some(icon_url) = icon_url {
+                    footer.icon_url(icon_url);
+                }
+                footer
+            });
+        if let (Some((role_name, _)), Some(winner)) = (&self.award, self.leaderboard.first()) {
+            embed.field(
+                "Award",
+                format!("{} gets the {role_name} role.", winner.entry.name),
+                false,
+            );
+        }
+        embed
+    }
+
+    pub fn list_fmt(&self) -> String {
+        let mut line = format!(
+            "**{}** | Top {} by {}, {} ranking | Posted in <#{}>",
+            self.name, self.number, self.scoring_method, self.ties, self.post_in
+        );
+        if let Some((role_name, _)) = &self.award {
+            line.push_str(&format!(" | Award: {role_name}"));
+            if let Some(holder) = self.last_given_to.and_then(|id| {
+                self.leaderboard
+                    .iter()
+                    .find(|ranked| ranked.entry.id == id)
+                    .map(|ranked| ranked.entry.name.clone())
+            }) {
+                line.push_str(&format!(" (held by {holder})"));
+            }
+        }
+        match self.last_updated {
+            Some(last_updated) => line.push_str(&format!(
+                " | Updated {}",
+                last_updated.format("%Y-%m-%d %H:%M UTC")
+            )),
+            None => line.push_str(" | Not updated yet"),
+        }
+        line
+    }
 }
 
-async fn try_give_role(
-    http: &impl AsRef<Http>,
-    cache: &Arc<Cache>,
-    guild: GuildId,
-    user: UserId,
-    role: RoleId,
+/// Reads the saved leaderboards. A missing file just means there aren't any yet.
+pub fn load_leaderboards(filename: &str) -> AnyResult<BTreeMap<LeaderboardId, Leaderboard>> {
+    match fs::read_to_string(filename) {
+        Ok(contents) if contents.trim().is_empty() => Ok(BTreeMap::new()),
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(error) if error.kind() == IoErrorKind::NotFound => {
+            println!("LB | No leaderboard file at '{filename}'. Starting with no leaderboards.");
+            Ok(BTreeMap::new())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Writes the leaderboards out, replacing the old file only once the new one is complete.
+pub fn save_leaderboards(
+    filename: &str,
+    leaderboards: &BTreeMap<LeaderboardId, Leaderboard>,
+) -> AnyResult<()> {
+    let temp_filename = format!("{filename}.tmp");
+    fs::write(&temp_filename, serde_json::to_string_pretty(leaderboards)?)?;
+    fs::rename(&temp_filename, filename)?;
+    Ok(())
+}
+
+/// Recomputes a leaderboard, moves its award role to the new winner, and posts it.
+pub async fn update_leaderboard(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    id: LeaderboardId,
 ) -> AnyResult<()> {
-    let member = guild.member((cache, http.as_ref()), user).await?;
-    if member.roles.contains(&role) {
-        println!("LB | AW | User already has role ID {}", role);
+    let (mut leaderboard, guild_id, storage) = {
+        let context_data = data.read().await;
+        let leaderboard = context_data
+            .get::<LeaderboardsKey>()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| {
+                IoError::new(
+                    IoErrorKind::NotFound,
+                    format!("There's no leaderboard with ID {id}.").as_str(),
+                )
+            })?;
+        (
+            leaderboard,
+            context_data.get::<ConfigKey>().unwrap().guild_id,
+            context_data.get::<StorageKey>().unwrap().clone(),
+        )
+    };
+    println!(
+        "LB | Recomputing leaderboard {id} ('{}').",
+        leaderboard.name
+    );
+    leaderboard.update(http, &storage).await?;
+    leaderboard.award_winner(http, guild_id).await?;
+    // Saved before posting, so that who has the award role isn't forgotten if posting fails.
+    let mut context_data = data.write().await;
+    let filename = context_data
+        .get::<ConfigKey>()
+        .unwrap()
+        .leaderboard_filename
+        .clone();
+    let leaderboards = context_data.get_mut::<LeaderboardsKey>().unwrap();
+    // It may have been deleted in the meantime.
+    if let Some(current) = leaderboards.get_mut(&id) {
+        *current = leaderboard.clone();
+        save_leaderboards(&filename, leaderboards)?;
+    }
+    drop(context_data);
+    let icon_url = http.get_current_user().await?.avatar_url();
+    let embed = leaderboard.build_embed(icon_url);
+    let _ = leaderboard
+        .post_in
+        .send_message(http, |m| m.set_embed(embed))
+        .await?;
+    println!("LB | Posted leaderboard {id}.");
+    Ok(())
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Create Leaderboard",
+    color(ColorChoice::Never),
+    no_binary_name(true),
+    disable_help_flag(true)
+)]
+pub(crate) struct CreateLeaderboardApp {
+    /// Title of the leaderboard
+    #[arg(long = "name", name = "name", num_args = 1.., required = true)]
+    name: Vec<String>,
+    /// Channel to post the leaderboard in
+    #[arg(long = "channel", name = "channel")]
+    channel: ChannelId,
+    /// Score users by how many pinned messages they have in this channel
+    #[arg(
+        long = "pins",
+        name = "pins",
+        required_unless_present_any = ["pings", "reactions"]
+    )]
+    pins: Option<ChannelId>,
+    /// Score users by how many times they're pinged in this channel
+    #[arg(long = "pings", name = "pings", conflicts_with = "pins")]
+    pings: Option<ChannelId>,
+    /// Score users by how many reactions their messages get from others, from now on
+    #[arg(
+        long = "reactions",
+        name = "reactions",
+        action = ArgAction::SetTrue,
+        conflicts_with_all = ["pins", "pings"]
+    )]
+    reactions: bool,
+    /// Only count reactions in these channels
+    #[arg(
+        long = "in",
+        name = "in",
+        num_args = 1..,
+        conflicts_with_all = ["pins", "pings"]
+    )]
+    in_channels: Vec<ChannelId>,
+    /// Only count these emoji
+    #[arg(
+        long = "emoji",
+        name = "emoji",
+        num_args = 1..,
+        conflicts_with_all = ["pins", "pings"]
+    )]
+    emoji: Vec<String>,
+    /// Number of days of pings or reactions to count
+    #[arg(
+        long = "window",
+        name = "window",
+        default_value_t = 7,
+        value_parser = value_parser!(i64).range(1..=3650)
+    )]
+    window: i64,
+    /// Number of users to show
+    #[arg(long = "top", name = "top", default_value_t = 10)]
+    top: usize,
+    /// Give the score after a tie the next rank (1, 2, 2, 3) instead of skipping ranks (1, 2, 2, 4)
+    #[arg(long = "dense", name = "dense", action = ArgAction::SetTrue)]
+    dense: bool,
+    /// Role to give the winner, taken from the previous winner
+    #[arg(long = "award", name = "award")]
+    award: Option<RoleId>,
+    /// How often to recompute and post the leaderboard
+    #[command(flatten)]
+    period: CreateTimePeriod,
+    // `-h` is taken by `--hours`.
+    /// Print help
+    #[arg(long = "help", action = ArgAction::Help)]
+    help: Option<bool>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(
+    name = "Leaderboard ID",
+    color(ColorChoice::Never),
+    no_binary_name(true)
+)]
+pub(crate) struct LeaderboardIdApp {
+    /// ID of the leaderboard, as shown by `list_leaderboards`
+    #[arg(long = "id", name = "id")]
+    pub id: LeaderboardId,
+}
+
+async fn reply_parse_error(
+    ctx: &Context,
+    invocation: &Invocation<'_>,
+    err: clap::Error,
+) -> CommandResult {
+    if err.kind() == ErrorKind::DisplayHelp {
+        println!("LB | User requested help.");
+        invocation
+            .reply_ephemeral(&ctx.http, format!("```{err}```"))
+            .await?;
         Ok(())
     } else {
+        println!("LB | Failed to parse user input. Sending error back.");
+        invocation
+            .reply_ephemeral(
+                &ctx.http,
+                format!("Error parsing command. Details:\n```{err}```"),
+            )
+            .await?;
+        Err(err.into())
+    }
+}
+
+fn try_get_create_leaderboard(args: Vec<String>) -> ClapResult<CreateLeaderboardApp> {
+    println!("LB | Parsing create leaderboard command use from {args:?}");
+    CreateLeaderboardApp::try_parse_from(args)
+}
+
+#[command]
+#[aliases("create-leaderboard")]
+pub async fn create_leaderboard(ctx: &Context, message: &Message) -> CommandResult {
+    run_create_leaderboard(ctx, &message.into()).await
+}
+
+pub async fn run_create_leaderboard(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("LB | Start handling create leaderboard command.");
+    let start = Instant::now();
+    if !is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        insufficient_perms(ctx, invocation).await?;
+        println!("LB | Elapsed: {:?}", start.elapsed());
+        return Ok(());
+    }
+    let app = match try_get_create_leaderboard(invocation.args("=>create_leaderboard")) {
+        Ok(app) => app,
+        Err(err) => {
+            let result = reply_parse_error(ctx, invocation, err).await;
+            println!("LB | Elapsed: {:?}", start.elapsed());
+            return result;
+        }
+    };
+    let (guild_id, tz) = {
+        let context_data = ctx.data.read().await;
+        let config = context_data.get::<ConfigKey>().unwrap();
+        (config.guild_id, config.time_zone)
+    };
+    let period = app.period.to_duration(tz);
+    if period <= Duration::zero() {
+        invocation
+            .reply_ephemeral(&ctx.http, "The update period has to be positive.")
+            .await?;
+        println!("LB | Elapsed: {:?}", start.elapsed());
+        return Ok(());
+    }
+    let award = match app.award {
+        Some(role_id) => {
+            let roles = ctx.http.get_guild_roles(guild_id.into()).await?;
+            match roles.into_iter().find(|role| role.id == role_id) {
+                Some(role) => Some((role.name, role.id)),
+                None => {
+                    invocation
+                        .reply_ephemeral(&ctx.http, format!("There's no role with ID {role_id}."))
+                        .await?;
+                    println!("LB | Elapsed: {:?}", start.elapsed());
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+    let scoring_method = match (app.pins, app.pings) {
+        (Some(channel), _) => ScoringMethod::PinsIn(channel),
+        (None, Some(channel)) => ScoringMethod::PingsIn {
+            channel,
+            within_days: app.window,
+        },
+        // Slash commands pass all the emoji as one value.
+        (None, None) => ScoringMethod::ReactionsIn {
+            channels: app.in_channels,
+            emoji: app
+                .emoji
+                .iter()
+                .flat_map(|emoji| emoji.split_whitespace())
+                .map(reactions::normalise_emoji)
+                .collect(),
+            within_days: app.window,
+        },
+    };
+    let leaderboard = Leaderboard {
+        name: app.name.join(" "),
+        post_in: app.channel,
+        leaderboard: Vec::new(),
+        previous: Vec::new(),
+        number: app.top,
+        ties: if app.dense {
+            TieRanking::Dense
+        } else {
+            TieRanking::Competition
+        },
+        scoring_method,
+        award,
+        last_given_to: None,
+        last_updated: None,
+    };
+    let list_entry = leaderboard.list_fmt();
+    let mut context_data = ctx.data.write().await;
+    let filename = context_data
+        .get::<ConfigKey>()
+        .unwrap()
+        .leaderboard_filename
+        .clone();
+    let newest = context_data
+        .get::<LeaderboardsKey>()
+        .unwrap()
+        .keys()
+        .next_back()
+        .copied()
+        .unwrap_or(0);
+    let id = context_data
+        .get::<StorageKey>()
+        .unwrap()
+        .next_leaderboard_id(newest)?;
+    let leaderboards = context_data.get_mut::<LeaderboardsKey>().unwrap();
+    let _ = leaderboards.insert(id, leaderboard);
+    save_leaderboards(&filename, leaderboards)?;
+    println!("LB | Saved leaderboard {id}. Scheduling updates.");
+    // Backdated by one period so that the first update happens straight away.
+    let update_task = TaskType::PeriodicTask(PeriodicTask {
+        task: Task::UpdateLeaderboard { id },
+        diff: period.num_seconds(),
+        last_sent: (Utc::now() - period).naive_utc(),
+        time_zone: None,
+        misfire_policy: Default::default(),
+        paused: false,
+        runs: 0,
+        max_runs: None,
+        until: None,
+    });
+    context_data
+        .get::<TaskSenderKey>()
+        .unwrap()
+        .send(TaskMessage::New(Box::new(update_task)))?;
+    drop(context_data);
+    invocation
+        .reply(&ctx.http, format!("Created leaderboard {id}: {list_entry}"))
+        .await?;
+    println!("LB | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[command]
+#[aliases("list-leaderboards")]
+pub async fn list_leaderboards(ctx: &Context, message: &Message) -> CommandResult {
+    run_list_leaderboards(ctx, &message.into()).await
+}
+
+pub async fn run_list_leaderboards(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("LB | Start handling list leaderboards command.");
+    let start = Instant::now();
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        let context_data = ctx.data.read().await;
+        let leaderboards = context_data.get::<LeaderboardsKey>().unwrap();
+        let msg = if leaderboards.is_empty() {
+            "No leaderboards currently!".to_string()
+        } else {
+            leaderboards
+                .iter()
+                .map(|(id, leaderboard)| format!("{id}: {}", leaderboard.list_fmt()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        drop(context_data);
+        invocation.say(&ctx.http, msg).await?;
+    } else {
+        insufficient_perms(ctx, invocation).await?;
+    }
+    println!("LB | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[command]
+#[aliases("delete-leaderboard")]
+pub async fn delete_leaderboard(ctx: &Context, message: &Message) -> CommandResult {
+    run_delete_leaderboard(ctx, &message.into()).await
+}
+
+pub async fn run_delete_leaderboard(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
+    println!("LB | Start handling delete leaderboard command.");
+    let start = Instant::now();
+    if !is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
+        insufficient_perms(ctx, invocation).await?;
+        println!("LB | Elapsed: {:?}", start.elapsed());
+        return Ok(());
+    }
+    let id = match LeaderboardIdApp::try_parse_from(invocation.args("=>delete_leaderboard")) {
+        Ok(app) => app.id,
+        Err(err) => {
+            let result = reply_parse_error(ctx, invocation, err).await;
+            println!("LB | Elapsed: {:?}", start.elapsed());
+            return result;
+        }
+    };
+    let mut context_data = ctx.data.write().await;
+    let filename = context_data
+        .get::<ConfigKey>()
+        .unwrap()
+        .leaderboard_filename
+        .clone();
+    let leaderboards = context_data.get_mut::<LeaderboardsKey>().unwrap();
+    let leaderboard = match leaderboards.remove(&id) {
+        Some(leaderboard) => leaderboard,
+        None => {
+            drop(context_data);
+            println!("LB | No leaderboard with ID {id}.");
+            invocation
+                .reply_ephemeral(&ctx.http, format!("There's no leaderboard with ID {id}."))
+                .await?;
+            println!("LB | Elapsed: {:?}", start.elapsed());
+            return Ok(());
+        }
+    };
+    save_leaderboards(&filename, leaderboards)?;
+    // Its update tasks would only fail from now on.
+    let update_tasks = context_data
+        .get::<TasksKey>()
+        .unwrap()
+        .iter()
+        .filter(|(_, task)| {
+            matches!(
+                task.task_ref(),
+                Some(Task::UpdateLeaderboard { id: task_leaderboard }) if *task_leaderboard == id
+            )
+        })
+        .map(|(&task_id, _)| task_id)
+        .collect::<Vec<_>>();
+    for &task_id in &update_tasks {
+        let _ = context_data.get_mut::<TasksKey>().unwrap().remove(&task_id);
+        context_data
+            .get::<StorageKey>()
+            .unwrap()
+            .remove_task(task_id)?;
+        task_changed(&context_data, task_id);
+    }
+    drop(context_data);
+    println!("LB | Deleted leaderboard {id} and its update tasks {update_tasks:?}.",);
+    invocation
+        .reply(
+            &ctx.http,
+            format!("Deleted leaderboard {id}: {}", leaderboard.list_fmt()),
+        )
+        .await?;
+    println!("LB | Elapsed: {:?}", start.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_leaderboards, save_leaderboards, Leaderboard, ScoringMethod, TieRanking};
+    use crate::misc::TestDir;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_save_and_load_leaderboards() {
+        let dir = TestDir::new("leaderboard");
+        let filename = dir.file("leaderboard");
+        assert!(load_leaderboards(&filename).unwrap().is_empty());
+        let mut leaderboards = BTreeMap::new();
+        let _ = leaderboards.insert(
+            1,
+            Leaderboard {
+                name: "Most pinned".into(),
+                post_in: 1.into(),
+                leaderboard: Vec::new(),
+                previous: Vec::new(),
+                number: 5,
+                ties: TieRanking::Dense,
+                scoring_method: ScoringMethod::PinsIn(2.into()),
+                award: Some(("Pin King".into(), 3.into())),
+                last_given_to: Some(4.into()),
+                last_updated: None,
+            },
+        );
+        save_leaderboards(&filename, &leaderboards).unwrap();
+        let loaded = load_leaderboards(&filename).unwrap();
+        assert_eq!(loaded[&1].name, "Most pinned");
+        assert_eq!(loaded[&1].last_given_to, Some(4.into()));
+        assert_eq!(loaded[&1].ties, TieRanking::Dense);
     }
 }
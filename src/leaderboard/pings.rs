@@ -1 +1,243 @@
+use super::LeaderboardEntry;
+use crate::storage::{Mentions, Storage};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serenity::{
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, MessageId},
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
 
+// Mentions are recorded as messages come in, so scoring only has to read the stored ones for the
+// window instead of fetching the channel's history from Discord every time. Before scoring, a
+// channel catches up on whatever was sent while the bot wasn't listening, and history from before
+// the bot started recording is fetched once.
+
+/// Milliseconds between the Unix epoch and the first second of 2015, which snowflakes count from.
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+/// Most messages Discord returns per history request.
+const PAGE_SIZE: u64 = 100;
+
+lazy_static! {
+    // Channels whose messages have all been read up to the point the gateway took over since
+    // connecting. Until a channel is in here, messages from the gateway can't move its
+    // `pings_read_up_to` on, since there may be unread ones before them.
+    static ref CAUGHT_UP: Mutex<HashSet<ChannelId>> = Mutex::new(HashSet::new());
+}
+
+/// The lowest message ID a message sent at `time` can have.
+pub fn first_message_id_at(time: DateTime<Utc>) -> MessageId {
+    let since_epoch = (time.timestamp_millis() - DISCORD_EPOCH).max(0);
+    #[allow(clippy::cast_sign_loss)]
+    MessageId((since_epoch as u64) << 22)
+}
+
+fn is_caught_up(channel: ChannelId) -> bool {
+    CAUGHT_UP.lock().unwrap().contains(&channel)
+}
+
+/// Forgets which channels are caught up, for when gateway events may have been missed.
+pub fn reset_caught_up() {
+    CAUGHT_UP.lock().unwrap().clear();
+}
+
+/// Records who a message mentions, if anyone. Bots pinging people doesn't count.
+fn record_message(storage: &Storage, message: &Message) -> AnyResult<()> {
+    if message.author.bot || message.mentions.is_empty() {
+        return Ok(());
+    }
+    let mentioned = message
+        .mentions
+        .iter()
+        .map(|user| (user.name.clone(), user.id))
+        .collect::<Vec<_>>();
+    storage.insert_pings(message.channel_id, message.id, &mentioned)
+}
+
+fn advance_read_up_to(storage: &Storage, channel: ChannelId, message: MessageId) -> AnyResult<()> {
+    if storage
+        .pings_read_up_to(channel)?
+        .is_none_or(|read_up_to| read_up_to < message)
+    {
+        storage.set_pings_read_up_to(channel, message)?;
+    }
+    Ok(())
+}
+
+/// Records a message that just came in from the gateway.
+pub fn ingest_message(storage: &Storage, message: &Message) -> AnyResult<()> {
+    record_message(storage, message)?;
+    if is_caught_up(message.channel_id) {
+        advance_read_up_to(storage, message.channel_id, message.id)?;
+    }
+    Ok(())
+}
+
+/// Reads the messages sent since the channel was last read up to.
+async fn catch_up(http: &Http, storage: &Storage, channel: ChannelId) -> AnyResult<()> {
+    if is_caught_up(channel) {
+        return Ok(());
+    }
+    let mut after = match storage.pings_read_up_to(channel)? {
+        Some(after) => after,
+        None => {
+            // Nothing has been read from this channel yet, so backfilling reads everything.
+            storage.set_pings_read_up_to(channel, first_message_id_at(Utc::now()))?;
+            let _ = CAUGHT_UP.lock().unwrap().insert(channel);
+            return Ok(());
+        }
+    };
+    let mut read = 0;
+    loop {
+        let page = channel
+            .messages(http, |retriever| retriever.after(after).limit(PAGE_SIZE))
+            .await?;
+        for message in &page {
+            record_message(storage, message)?;
+        }
+        read += page.len();
+        after = match page.iter().map(|message| message.id).max() {
+            Some(newest) => newest,
+            None => break,
+        };
+        advance_read_up_to(storage, channel, after)?;
+        if page.len() < PAGE_SIZE as usize {
+            break;
+        }
+    }
+    println!("LB | PG | Caught up on {read} messages in channel {channel}.");
+    let _ = CAUGHT_UP.lock().unwrap().insert(channel);
+    Ok(())
+}
+
+/// Makes sure the stored mentions for a channel go back to at least `since`, fetching whatever
+/// history hasn't been read yet.
+async fn backfill(
+    http: &Http,
+    storage: &Storage,
+    channel: ChannelId,
+    since: DateTime<Utc>,
+) -> AnyResult<()> {
+    let backfilled_since = storage.pings_backfilled_since(channel)?;
+    if backfilled_since.is_some_and(|backfilled_since| backfilled_since <= since) {
+        return Ok(());
+    }
+    println!("LB | PG | Reading history of channel {channel} back to {since}.");
+    // Everything newer than what was read before is already stored.
+    let mut before = backfilled_since.map(first_message_id_at);
+    let mut read = 0;
+    loop {
+        let page = channel
+            .messages(http, |retriever| {
+                if let Some(before) = before {
+                    retriever.before(before);
+                }
+                retriever.limit(PAGE_SIZE)
+            })
+            .await?;
+        // Newest first.
+        let reached_end = page.len() < PAGE_SIZE as usize
+            || page.last().is_some_and(|oldest| *oldest.timestamp < since);
+        for message in page.iter().filter(|message| *message.timestamp >= since) {
+            record_message(storage, message)?;
+            read += 1;
+        }
+        before = page.last().map(|oldest| oldest.id);
+        if reached_end || before.is_none() {
+            break;
+        }
+    }
+    println!("LB | PG | Read {read} messages from channel {channel}.");
+    storage.set_pings_backfilled_since(channel, since)
+}
+
+/// Scores each user by how many messages in the channel since `since` mention them.
+pub async fn score_pings(
+    http: &Http,
+    storage: &Storage,
+    channel: ChannelId,
+    since: DateTime<Utc>,
+) -> AnyResult<Vec<LeaderboardEntry>> {
+    catch_up(http, storage, channel).await?;
+    backfill(http, storage, channel, since).await?;
+    let pings = storage.pings_since(channel, first_message_id_at(since))?;
+    Ok(ping_list_to_scoreboard(pings))
+}
+
+fn ping_list_to_scoreboard(pings: Vec<(MessageId, Mentions)>) -> Vec<LeaderboardEntry> {
+    let mut score_map = HashMap::new();
+    for (message, mentioned) in pings {
+        let sent_at = *message.created_at();
+        for (name, id) in mentioned {
+            // Later messages have the more up to date name, and are when the score was reached.
+            score_map
+                .entry(id)
+                .and_modify(|(latest_name, score, achieved_at)| {
+                    *latest_name = name.clone();
+                    *score += 1;
+                    *achieved_at = sent_at;
+                })
+                .or_insert((name, 1, sent_at));
+        }
+    }
+    score_map
+        .into_iter()
+        .map(|(id, (name, score, achieved_at))| {
+            LeaderboardEntry::new(name, id, score, Some(achieved_at))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{first_message_id_at, ping_list_to_scoreboard};
+    use crate::storage::Storage;
+    use chrono::{Duration, TimeZone, Utc};
+    use serenity::model::id::{ChannelId, MessageId};
+
+    #[test]
+    fn test_pings_in_window() {
+        // The example from Discord's documentation on snowflakes.
+        let time = Utc.timestamp_millis_opt(1_462_015_105_796).unwrap();
+        assert_eq!(
+            first_message_id_at(time).0 >> 22,
+            175_928_847_299_117_063 >> 22
+        );
+        let storage = Storage::temporary();
+        let channel = ChannelId(1);
+        let now = Utc::now();
+        let message_at =
+            |days_ago| MessageId(first_message_id_at(now - Duration::days(days_ago)).0 + 1);
+        let alice = ("alice".to_string(), 10.into());
+        let bob = ("bob".to_string(), 20.into());
+        storage
+            .insert_pings(channel, message_at(10), std::slice::from_ref(&alice))
+            .unwrap();
+        storage
+            .insert_pings(channel, message_at(3), &[alice.clone(), bob.clone()])
+            .unwrap();
+        storage
+            .insert_pings(channel, message_at(1), &[bob])
+            .unwrap();
+        // Pings in other channels don't count.
+        storage
+            .insert_pings(ChannelId(2), message_at(1), &[alice])
+            .unwrap();
+        let pings = storage
+            .pings_since(channel, first_message_id_at(now - Duration::days(7)))
+            .unwrap();
+        let mut scores = ping_list_to_scoreboard(pings)
+            .into_iter()
+            .map(|entry| (entry.name, entry.score))
+            .collect::<Vec<_>>();
+        scores.sort();
+        assert_eq!(scores, [("alice".to_string(), 1), ("bob".to_string(), 2)]);
+    }
+}
@@ -1,6 +1,35 @@
-use serde::{Deserialize, Serialize};
+use super::LeaderboardEntry;
+use serenity::model::{channel::Message, prelude::User};
+use std::collections::HashMap;
 
-/*#[derive(Deserialize, Serialize)]
-pub struct PinLeaderboard {
-    pub channel_name
-}*/
+/// Scores each author by how many of the messages are theirs.
+pub fn pin_list_to_scoreboard(messages: Vec<Message>) -> Vec<LeaderboardEntry> {
+    let mut score_map = HashMap::new();
+    for message in messages {
+        let Message {
+            author:
+                User {
+                    id: author_id,
+                    name: author_name,
+                    ..
+                },
+            timestamp,
+            ..
+        } = message;
+        let pinned_message_at = *timestamp;
+        // The author reached their score with their newest pinned message.
+        score_map
+            .entry((author_name, author_id))
+            .and_modify(|(score, achieved_at)| {
+                *score += 1;
+                *achieved_at = pinned_message_at.max(*achieved_at);
+            })
+            .or_insert((1, pinned_message_at));
+    }
+    score_map
+        .into_iter()
+        .map(|((name, id), (score, achieved_at))| {
+            LeaderboardEntry::new(name, id, score, Some(achieved_at))
+        })
+        .collect()
+}
@@ -0,0 +1,163 @@
+use super::LeaderboardEntry;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    fmt::{self, Display, Formatter},
+};
+
+/// How people with the same score are ranked.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum TieRanking {
+    /// Ties share a rank and the next score down gets the rank after it, like 1, 2, 2, 3.
+    Dense,
+    /// Ties share a rank and the ranks they take up are skipped, like 1, 2, 2, 4.
+    #[default]
+    Competition,
+}
+
+impl Display for TieRanking {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            TieRanking::Dense => write!(f, "dense"),
+            TieRanking::Competition => write!(f, "competition"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RankedEntry {
+    #[serde(default)]
+    pub rank: usize,
+    #[serde(flatten)]
+    pub entry: LeaderboardEntry,
+}
+
+/// Sorts the entries from highest score to lowest and ranks them. Whoever reached a tied score
+/// first is listed first, and user ID settles anything left so the order never depends on how the
+/// scores were collected.
+pub fn rank(mut entries: Vec<LeaderboardEntry>, ties: TieRanking) -> Vec<RankedEntry> {
+    // Entries without an achievement time go after those with one.
+    entries.sort_by_key(|entry| {
+        (
+            Reverse(entry.score),
+            entry.achieved_at.is_none(),
+            entry.achieved_at,
+            entry.id,
+        )
+    });
+    let mut ranked: Vec<RankedEntry> = Vec::with_capacity(entries.len());
+    for (position, entry) in entries.into_iter().enumerate() {
+        let rank = match ranked.last() {
+            Some(last) if last.entry.score == entry.score => last.rank,
+            Some(last) => match ties {
+                TieRanking::Dense => last.rank + 1,
+                TieRanking::Competition => position + 1,
+            },
+            None => 1,
+        };
+        ranked.push(RankedEntry { rank, entry });
+    }
+    ranked
+}
+
+/// One line per entry with its rank, name, score and how it moved since the previous standings.
+pub fn render(standings: &[RankedEntry], previous: &[RankedEntry]) -> String {
+    standings
+        .iter()
+        .map(|ranked| {
+            let tied = standings
+                .iter()
+                .filter(|other| other.rank == ranked.rank)
+                .count()
+                > 1;
+            format!(
+                "**{}{}.** {} - {}{}",
+                if tied { "=" } else { "" },
+                ranked.rank,
+                ranked.entry.name,
+                ranked.entry.score,
+                movement(ranked, previous)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn movement(ranked: &RankedEntry, previous: &[RankedEntry]) -> String {
+    let before = match previous
+        .iter()
+        .find(|before| before.entry.id == ranked.entry.id)
+    {
+        Some(before) => before,
+        None if previous.is_empty() => return String::new(),
+        None => return " (new)".to_string(),
+    };
+    let score_change = ranked.entry.score as i64 - before.entry.score as i64;
+    let rank_change = if ranked.rank < before.rank {
+        format!("▲{}", before.rank - ranked.rank)
+    } else if ranked.rank > before.rank {
+        format!("▼{}", ranked.rank - before.rank)
+    } else {
+        "-".to_string()
+    };
+    format!(" ({score_change:+}, {rank_change})")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rank, render, TieRanking};
+    use crate::leaderboard::LeaderboardEntry;
+    use chrono::{TimeZone, Utc};
+
+    fn entry(name: &str, id: u64, score: usize, achieved_at: i64) -> LeaderboardEntry {
+        LeaderboardEntry::new(
+            name.to_string(),
+            id.into(),
+            score,
+            Utc.timestamp_opt(achieved_at, 0).single(),
+        )
+    }
+
+    #[test]
+    fn test_rank_ties() {
+        let entries = vec![
+            entry("carol", 3, 2, 300),
+            entry("alice", 1, 5, 100),
+            entry("dave", 4, 1, 100),
+            entry("bob", 2, 2, 200),
+        ];
+        let ranks = |ties| {
+            rank(entries.clone(), ties)
+                .into_iter()
+                .map(|ranked| (ranked.rank, ranked.entry.name))
+                .collect::<Vec<_>>()
+        };
+        // Bob got to 2 before Carol did.
+        assert_eq!(
+            ranks(TieRanking::Competition),
+            [
+                (1, "alice".to_string()),
+                (2, "bob".to_string()),
+                (2, "carol".to_string()),
+                (4, "dave".to_string()),
+            ]
+        );
+        assert_eq!(
+            ranks(TieRanking::Dense)
+                .into_iter()
+                .map(|(rank, _)| rank)
+                .collect::<Vec<_>>(),
+            [1, 2, 2, 3]
+        );
+
+        let previous = rank(
+            vec![entry("alice", 1, 1, 0), entry("bob", 2, 3, 0)],
+            TieRanking::Competition,
+        );
+        let current = rank(entries, TieRanking::Competition);
+        assert_eq!(
+            render(&current, &previous),
+            "**1.** alice - 5 (+4, ▲1)\n**=2.** bob - 2 (-1, ▼1)\n**=2.** carol - 2 (new)\n**4.** dave - 1 (new)"
+        );
+    }
+}
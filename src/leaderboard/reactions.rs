@@ -0,0 +1,211 @@
+use super::LeaderboardEntry;
+use crate::{
+    cache_keys::{ConfigKey, LeaderboardsKey, StorageKey},
+    hall_of_fame,
+    storage::{Storage, StoredReaction},
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use serenity::{
+    model::{
+        channel::{Reaction, ReactionType},
+        id::ChannelId,
+    },
+    prelude::Context,
+};
+use std::collections::HashMap;
+
+// Discord can't list who reacted to what over a period of time, so reactions are only known from
+// when they're seen happening. Scores only count reactions from after a channel started being
+// tracked.
+
+/// How an emoji is stored: the emoji itself for Unicode emoji, or the ID for custom ones, which
+/// can be renamed.
+pub fn emoji_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Custom { id, .. } => id.to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Turns an emoji as someone would type it, like `⭐` or `<:velvet:1234>`, into its stored form.
+pub fn normalise_emoji(emoji: &str) -> String {
+    emoji
+        .parse::<ReactionType>()
+        .map_or_else(|_| emoji.to_string(), |emoji| emoji_key(&emoji))
+}
+
+/// Shows a stored emoji the way Discord will render it.
+pub fn emoji_display(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_digit()) {
+        format!("<:emoji:{key}>")
+    } else {
+        key.to_string()
+    }
+}
+
+/// Whether an emoji gets through a filter. No filter lets every emoji through.
+pub fn emoji_allowed(filter: &[String], key: &str) -> bool {
+    filter.is_empty() || filter.iter().any(|allowed| normalise_emoji(allowed) == key)
+}
+
+/// Whether reactions in the channel need recording, for a leaderboard or for the hall of fame.
+async fn is_tracked(ctx: &Context, channel: ChannelId) -> bool {
+    let context_data = ctx.data.read().await;
+    let hall_of_fame = context_data
+        .get::<ConfigKey>()
+        .unwrap()
+        .hall_of_fame
+        .as_ref();
+    hall_of_fame.is_some_and(|hall_of_fame| hall_of_fame.channel != channel)
+        || context_data
+            .get::<LeaderboardsKey>()
+            .unwrap()
+            .values()
+            .any(|leaderboard| leaderboard.scoring_method.counts_reactions_in(channel))
+}
+
+/// Records a reaction that was just added. People reacting to their own messages and bots
+/// reacting don't count, and neither do reactions to bots' messages.
+pub async fn ingest_reaction(ctx: &Context, reaction: &Reaction) -> AnyResult<()> {
+    if !is_tracked(ctx, reaction.channel_id).await {
+        return Ok(());
+    }
+    // Reactions in servers come with the user, and anyone else is likely to be cached.
+    let reactor = match reaction
+        .member
+        .as_ref()
+        .and_then(|member| member.user.clone())
+        .or_else(|| reaction.user_id.and_then(|id| ctx.cache.user(id)))
+    {
+        Some(reactor) => reactor,
+        None => reaction.user(ctx).await?,
+    };
+    if reactor.bot {
+        return Ok(());
+    }
+    let message = match ctx.cache.message(reaction.channel_id, reaction.message_id) {
+        Some(message) => message,
+        None => reaction.message(&ctx.http).await?,
+    };
+    if message.author.bot || message.author.id == reactor.id {
+        return Ok(());
+    }
+    let storage = ctx.data.read().await.get::<StorageKey>().unwrap().clone();
+    storage.insert_reaction(
+        reaction.channel_id,
+        &StoredReaction {
+            message: message.id,
+            reactor: reactor.id,
+            emoji: emoji_key(&reaction.emoji),
+            author: message.author.id,
+            author_name: message.author.name.clone(),
+            // Gateway events don't say when they happened, but they arrive straight away.
+            reacted_at: Utc::now(),
+        },
+    )?;
+    hall_of_fame::consider(ctx, &storage, &message).await
+}
+
+/// Forgets a reaction that was just removed.
+pub async fn forget_reaction(ctx: &Context, reaction: &Reaction) -> AnyResult<()> {
+    let reactor = match reaction.user_id {
+        Some(reactor) => reactor,
+        None => return Ok(()),
+    };
+    let storage = ctx.data.read().await.get::<StorageKey>().unwrap().clone();
+    storage.remove_reaction(
+        reaction.channel_id,
+        reaction.message_id,
+        reactor,
+        &emoji_key(&reaction.emoji),
+    )
+}
+
+/// Scores each user by how many reactions their messages got since `since`, counting only the
+/// given emoji in the given channels, or any if none are given.
+pub fn score_reactions(
+    storage: &Storage,
+    channels: &[ChannelId],
+    emoji: &[String],
+    since: DateTime<Utc>,
+) -> AnyResult<Vec<LeaderboardEntry>> {
+    let reactions = if channels.is_empty() {
+        storage.reactions_since(None, since)?
+    } else {
+        let mut reactions = Vec::new();
+        for &channel in channels {
+            reactions.extend(storage.reactions_since(Some(channel), since)?);
+        }
+        reactions
+    };
+    Ok(reaction_list_to_scoreboard(reactions, emoji))
+}
+
+fn reaction_list_to_scoreboard(
+    reactions: Vec<StoredReaction>,
+    emoji: &[String],
+) -> Vec<LeaderboardEntry> {
+    let mut score_map: HashMap<_, (String, usize, DateTime<Utc>)> = HashMap::new();
+    for reaction in reactions
+        .into_iter()
+        .filter(|reaction| emoji_allowed(emoji, &reaction.emoji))
+    {
+        // The author reached their score with the latest reaction.
+        score_map
+            .entry(reaction.author)
+            .and_modify(|(_, score, latest)| {
+                *score += 1;
+                *latest = reaction.reacted_at.max(*latest);
+            })
+            .or_insert((reaction.author_name, 1, reaction.reacted_at));
+    }
+    score_map
+        .into_iter()
+        .map(|(id, (name, score, latest))| LeaderboardEntry::new(name, id, score, Some(latest)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{emoji_display, normalise_emoji, reaction_list_to_scoreboard};
+    use crate::storage::StoredReaction;
+    use chrono::Utc;
+
+    #[test]
+    fn test_reaction_scores() {
+        assert_eq!(normalise_emoji("<:velvet:1234>"), "1234");
+        assert_eq!(normalise_emoji("⭐"), "⭐");
+        assert_eq!(emoji_display("1234"), "<:emoji:1234>");
+        let reaction = |message: u64, reactor: u64, emoji: &str, author: u64| StoredReaction {
+            message: message.into(),
+            reactor: reactor.into(),
+            emoji: emoji.to_string(),
+            author: author.into(),
+            author_name: format!("user {author}"),
+            reacted_at: Utc::now(),
+        };
+        let reactions = vec![
+            reaction(1, 10, "⭐", 1),
+            reaction(1, 11, "⭐", 1),
+            reaction(1, 11, "1234", 1),
+            reaction(2, 10, "⭐", 2),
+            reaction(3, 10, "🙃", 2),
+        ];
+        let mut scores = reaction_list_to_scoreboard(
+            reactions.clone(),
+            &["⭐".to_string(), "<:velvet:1234>".to_string()],
+        )
+        .into_iter()
+        .map(|entry| (entry.id.0, entry.score))
+        .collect::<Vec<_>>();
+        scores.sort();
+        assert_eq!(scores, [(1, 3), (2, 1)]);
+        let mut scores = reaction_list_to_scoreboard(reactions, &[])
+            .into_iter()
+            .map(|entry| (entry.id.0, entry.score))
+            .collect::<Vec<_>>();
+        scores.sort();
+        assert_eq!(scores, [(1, 3), (2, 2)]);
+    }
+}
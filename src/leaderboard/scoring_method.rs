@@ -1,28 +1,45 @@
-use std::collections::HashMap;
-
-use super::LeaderboardEntry;
+use super::{
+    pings::score_pings,
+    pins::pin_list_to_scoreboard,
+    reactions::{emoji_display, score_reactions},
+    LeaderboardEntry,
+};
+use crate::storage::Storage;
 use anyhow::Result as AnyResult;
-use chrono::{Duration, Utc, DateTime};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use serenity::{
-    futures::StreamExt,
-    http::Http,
-    model::{channel::Message, id::ChannelId, prelude::User},
+use serenity::{http::Http, model::id::ChannelId};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{Error as IoError, ErrorKind as IoErrorKind},
 };
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+// Each variant says what gets counted where, so they all end in `In`.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ScoringMethod {
     PinsIn(ChannelId),
     PingsIn {
         channel: ChannelId,
         within_days: i64,
     },
+    /// Reactions people's messages got from others. Only reactions seen while the bot is running
+    /// count.
+    ReactionsIn {
+        /// Channels to count reactions in. Every channel counts if this is empty.
+        channels: Vec<ChannelId>,
+        /// Emoji to count, as stored. Every emoji counts if this is empty.
+        emoji: Vec<String>,
+        within_days: i64,
+    },
 }
 
 impl ScoringMethod {
+    /// Scores for everyone who scored at all, in no particular order.
     pub async fn get_leaderboard(
         &self,
-        http: &impl AsRef<Http>,
+        http: &Http,
+        storage: &Storage,
     ) -> AnyResult<Vec<LeaderboardEntry>> {
         match self {
             ScoringMethod::PinsIn(channel) => {
@@ -33,68 +50,86 @@ impl ScoringMethod {
                 channel,
                 within_days,
             } => {
-                let mut messages = Vec::new();
-                let mut stream = channel.messages_iter(http).boxed_local();
-                while let Some(msg_result) = stream.next().await {
-                    let msg = msg_result?;
-                    let timestamp = msg.timestamp;
-                    let naive_dt = timestamp.naive_utc();
-                    let dt: DateTime<Utc> = DateTime::from_utc(naive_dt, Utc);
-                    if Utc::now().signed_duration_since(dt)
-                        >= Duration::days(*within_days)
-                        && !msg.mentions.is_empty()
-                    {
-                        messages.push(msg);
-                    } else {
-                        break;
-                    }
-                }
-                Ok(ping_list_to_scoreboard(messages))
+                let since = window_start(*within_days)?;
+                score_pings(http, storage, *channel, since).await
+            }
+            ScoringMethod::ReactionsIn {
+                channels,
+                emoji,
+                within_days,
+            } => {
+                let since = window_start(*within_days)?;
+                score_reactions(storage, channels, emoji, since)
             }
         }
     }
-}
 
-fn pin_list_to_scoreboard(messages: Vec<Message>) -> Vec<LeaderboardEntry> {
-    let mut score_map = HashMap::new();
-    for message in messages {
-        let Message {
-            author:
-                User {
-                    id: author_id,
-                    name: author_name,
-                    ..
-                },
-            ..
-        } = message;
-        score_map
-            .entry((author_name, author_id))
-            .and_modify(|score| *score += 1)
-            .or_insert(1);
+    /// The channel whose messages need recording as they come in, if any.
+    pub fn ingests_from(&self) -> Option<ChannelId> {
+        match self {
+            ScoringMethod::PinsIn(_) | ScoringMethod::ReactionsIn { .. } => None,
+            ScoringMethod::PingsIn { channel, .. } => Some(*channel),
+        }
+    }
+
+    /// Whether reactions in the channel need recording as they happen.
+    pub fn counts_reactions_in(&self, channel: ChannelId) -> bool {
+        match self {
+            ScoringMethod::ReactionsIn { channels, .. } => {
+                channels.is_empty() || channels.contains(&channel)
+            }
+            _ => false,
+        }
     }
-    let mut leaderboard = score_map
-        .into_iter()
-        .map(|((name, id), score)| LeaderboardEntry::new(name, id, score))
-        .collect::<Vec<_>>();
-    leaderboard.sort_unstable_by(|e0, e1| e0.score.cmp(&e1.score));
-    leaderboard
 }
 
-fn ping_list_to_scoreboard(messages: Vec<Message>) -> Vec<LeaderboardEntry> {
-    let mut score_map = HashMap::new();
-    for message in messages {
-        for mention in message.mentions {
-            let User { id, name, .. } = mention;
-            score_map
-                .entry((name, id))
-                .and_modify(|score| *score += 1)
-                .or_insert(1);
+impl Display for ScoringMethod {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ScoringMethod::PinsIn(channel) => write!(f, "pins in <#{channel}>"),
+            ScoringMethod::PingsIn {
+                channel,
+                within_days,
+            } => write!(f, "pings in <#{channel}> over {within_days} days"),
+            ScoringMethod::ReactionsIn {
+                channels,
+                emoji,
+                within_days,
+            } => {
+                if emoji.is_empty() {
+                    write!(f, "reactions")?;
+                } else {
+                    let emoji = emoji
+                        .iter()
+                        .map(|emoji| emoji_display(emoji))
+                        .collect::<Vec<_>>();
+                    write!(f, "{} reactions", emoji.join(" "))?;
+                }
+                if channels.is_empty() {
+                    write!(f, " anywhere")?;
+                } else {
+                    let channels = channels
+                        .iter()
+                        .map(|channel| format!("<#{channel}>"))
+                        .collect::<Vec<_>>();
+                    write!(f, " in {}", channels.join(", "))?;
+                }
+                write!(f, " over {within_days} days")
+            }
         }
     }
-    let mut leaderboard = score_map
-        .into_iter()
-        .map(|((name, id), score)| LeaderboardEntry::new(name, id, score))
-        .collect::<Vec<_>>();
-    leaderboard.sort_unstable_by(|e0, e1| e0.score.cmp(&e1.score));
-    leaderboard
+}
+
+/// When a window of `within_days` days up to now started. Leaderboards saved before the window was
+/// checked on creation can hold any number of days.
+fn window_start(within_days: i64) -> AnyResult<DateTime<Utc>> {
+    Duration::try_days(within_days)
+        .and_then(|window| Utc::now().checked_sub_signed(window))
+        .ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                format!("Invalid window of {within_days} days").as_str(),
+            )
+            .into()
+        })
 }
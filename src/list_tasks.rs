@@ -5,25 +5,33 @@ use serenity::{
 };
 use std::time::Instant;
 
-use crate::cache_keys::TasksKey;
+use crate::{
+    cache_keys::{ConfigKey, TasksKey},
+    invocation::Invocation,
+};
 
 #[command]
 pub async fn list_tasks(ctx: &Context, message: &Message) -> CommandResult {
+    run_list_tasks(ctx, &message.into()).await
+}
+
+pub async fn run_list_tasks(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("LT | Start handling list tasks command.");
     let start = Instant::now();
     println!("LT | Grabbing read 'lock' on context data.");
     let context_data = ctx.data.read().await;
     println!("LT | Grabbing tasks from context data.");
-    let tasks = context_data.get::<TasksKey>().unwrap().as_slice();
+    let tasks = context_data.get::<TasksKey>().unwrap();
+    let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
     println!("LT | Formatting message contents.");
     let msg = if tasks.is_empty() {
         "No tasks currently!".into()
     } else {
         let mut msg = "Current task list:\n```".to_string();
-        tasks.iter().enumerate().for_each(|(ind, task)| {
+        tasks.iter().for_each(|(id, task)| {
             msg.push('\n');
-            msg.push_str(&format!("{ind}: "));
-            let add = task.list_fmt();
+            msg.push_str(&format!("{id}: "));
+            let add = task.list_fmt(default_tz);
             msg.push_str(&add);
         });
         msg.push_str("\n```");
@@ -31,10 +39,7 @@ pub async fn list_tasks(ctx: &Context, message: &Message) -> CommandResult {
     };
     drop(context_data);
     println!("LT | Sending message.");
-    message
-        .channel_id
-        .send_message(&ctx.http, |m| m.content(msg))
-        .await?;
+    invocation.say(&ctx.http, msg).await?;
     println!("LT | Elapsed: {:?}", start.elapsed());
     Ok(())
 }
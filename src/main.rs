@@ -1,57 +1,89 @@
 #![allow(clippy::module_name_repetitions)]
 
+mod activity;
 mod anagram;
+mod appearance;
 mod args;
 mod cache_keys;
+mod cases;
 mod config;
 mod current_gulags;
+mod dead_letters;
+mod edit_task;
+mod escalation;
+mod escapes;
 mod gulag;
+mod hall_of_fame;
 mod handler;
 mod help;
 mod init;
-// mod leaderboard;
+mod interactions;
+mod invocation;
+mod leaderboard;
 mod list_tasks;
 mod misc;
+mod mod_log;
 mod release;
+mod scheduler;
+mod shutdown;
 mod source;
+mod storage;
 mod tasks;
 
 use anagram::ANAGRAM_COMMAND;
 use anyhow::Result as AnyResult;
 #[allow(clippy::wildcard_imports)]
 use cache_keys::*;
+use cases::{CASE_COMMAND, HISTORY_COMMAND};
 use clap::Parser;
 use config::Config;
-use crossbeam_channel::{unbounded, Receiver as CbReceiver};
 use current_gulags::CURRENT_GULAGS_COMMAND;
+use dead_letters::{DEAD_LETTERS_COMMAND, RERUN_DEAD_LETTER_COMMAND};
+use edit_task::{DELETE_TASK_COMMAND, EDIT_TASK_COMMAND, PAUSE_TASK_COMMAND, RESUME_TASK_COMMAND};
 use gulag::GULAG_COMMAND;
-use handler::{after, Handler};
+use handler::{after, before, Handler};
 use help::HELP_COMMAND;
-use init::{find_role_by, read_config_file, read_tasks_file, update_config_if};
+use init::{find_role_by, open_storage, read_config_file, update_config_if};
+use leaderboard::{
+    load_leaderboards, CREATE_LEADERBOARD_COMMAND, DELETE_LEADERBOARD_COMMAND,
+    LIST_LEADERBOARDS_COMMAND,
+};
 use list_tasks::LIST_TASKS_COMMAND;
-use misc::update_task_list;
 use release::RELEASE_COMMAND;
 use serenity::{
     framework::{standard::macros::group, StandardFramework},
-    http::client::Http,
     prelude::*,
     utils::Colour,
 };
+use shutdown::{shut_down, wait_for_signal, EXIT_CLIENT_ERROR, EXIT_NOT_SAVED, EXIT_OK};
 use source::SOURCE_COMMAND;
-use std::{
-    io::{Error as IoError, ErrorKind as IoErrorKind},
-    sync::Arc,
-    time::Duration,
-};
-use tasks::{TaskType, CREATE_TASK_COMMAND};
-use tokio::time::interval;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use tasks::CREATE_TASK_COMMAND;
+use tokio::sync::mpsc::unbounded_channel;
 
 #[group]
 #[commands(anagram, help, source)]
 struct GeneralCommands;
 
 #[group]
-#[commands(create_task, current_gulags, gulag, release, list_tasks)]
+#[commands(
+    case,
+    create_leaderboard,
+    create_task,
+    current_gulags,
+    dead_letters,
+    delete_leaderboard,
+    delete_task,
+    edit_task,
+    gulag,
+    history,
+    release,
+    list_leaderboards,
+    list_tasks,
+    pause_task,
+    rerun_dead_letter,
+    resume_task
+)]
 struct AdminCommands;
 
 pub const FOOTER_TEXT: &str = "Your friendly neighbourhood gulag officer, Officer Velvet";
@@ -67,10 +99,17 @@ async fn main() -> AnyResult<()> {
     let mut config = serde_json::from_str::<Config>(&config_contents)?;
     let intents = GatewayIntents::all();
     println!("IN | Parsed config from config file contents.");
-    let tasks = read_tasks_file(&config)?;
+    appearance::validate_config(&config)?;
+    println!("IN | Checked appearance images.");
+    if let Some(escalation) = &config.escalation {
+        escalation.validate()?;
+        println!("IN | Checked escalation policy.");
+    }
+    let (storage, tasks) = open_storage(&config)?;
     println!("IN | Collected tasks.");
     let framework = StandardFramework::new()
         .configure(|c| c.prefix("=>"))
+        .before(before)
         .after(after)
         .group(&GENERALCOMMANDS_GROUP)
         .group(&ADMINCOMMANDS_GROUP);
@@ -230,74 +269,55 @@ async fn main() -> AnyResult<()> {
         .write()
         .await
         .insert::<HigherRolesKey>(higher_roles);
+    // Cache the leaderboards.
+    let leaderboards = load_leaderboards(&config.leaderboard_filename)?;
+    client
+        .data
+        .write()
+        .await
+        .insert::<LeaderboardsKey>(leaderboards);
+    println!("IN | Loaded leaderboards.");
     // Cache the config.
     client.data.write().await.insert::<ConfigKey>(config);
     // Cache the tasks - they may need to be updated depending on role changes and such.
     client.data.write().await.insert::<TasksKey>(tasks);
+    client.data.write().await.insert::<StorageKey>(storage);
     println!("IN | Cached tasks.");
-    // Create a channel for the bot thread to be able to send new tasks to the main thread.
-    let (send, recv) = unbounded();
+    // Create a channel for the bot to be able to tell the scheduler about new and changed tasks.
+    let (send, recv) = unbounded_channel();
     client.data.write().await.insert::<TaskSenderKey>(send);
-    // Spawn a ctrl+c handler here and have it send the proper instructions n' stuff.
-    // todo
-    // Start the task handling loop in a separate thread.
-    println!("IN | Starting task handling loop.");
+    client
+        .data
+        .write()
+        .await
+        .insert::<ShardManagerKey>(client.shard_manager.clone());
+    // Start the scheduler in a separate task.
+    println!("IN | Starting scheduler.");
     let data_clone = client.data.clone();
     let http_clone = client.cache_and_http.http.clone();
-    tokio::spawn(start_task_handler(data_clone, http_clone, recv));
+    tokio::spawn(scheduler::run_scheduler(data_clone, http_clone, recv));
+    // Shut down cleanly on ctrl+c or SIGTERM.
+    let data_clone = client.data.clone();
+    let signal_handler = tokio::spawn(async move {
+        let signal = wait_for_signal().await;
+        println!("SD | Received {signal}. Shutting down.");
+        shut_down(&data_clone).await
+    });
     // Start the client.
     println!("IN | Starting client.");
-    if let Err(why) = client.start().await {
-        println!("IN | Client error: {:?}", why);
-    }
-    Ok(())
-}
-
-async fn start_task_handler(
-    data: Arc<RwLock<TypeMap>>,
-    http: Arc<Http>,
-    recv: CbReceiver<TaskType>,
-) {
-    let mut interval = interval(Duration::from_millis(1000));
-    let tasklist_filename = data
-        .read()
-        .await
-        .get::<ConfigKey>()
-        .unwrap()
-        .tasks_file
-        .clone();
-    loop {
-        // Get copy of task list.
-        let mut tasks = data.read().await.get::<TasksKey>().unwrap().clone();
-        let mut made_changes = false;
-        // Check for new tasks.
-        while let Ok(task) = recv.try_recv() {
-            println!("TL | Received new task - pushing to task list.");
-            tasks.push(task);
-            made_changes = true;
-        }
-        // Check whether any current tasks need to be executed.
-        for i in (0..tasks.len()).rev() {
-            // println!("TL | {}", tasks[i].list_fmt().trim());
-            if tasks[i].time_to_act() {
-                if let Err(e) = tasks[i].act(&data, &http).await {
-                    println!("TL | error: {e}");
-                }
-                if tasks[i].is_gulag() {
-                    println!("TL | Gulag period has elapsed - removing from task list.");
-                    tasks.remove(i);
-                    made_changes = true;
-                }
-            }
-        }
-        if made_changes {
-            println!("TL | Changes to task list were made.");
-            if let Err(e) = update_task_list(&tasklist_filename, &tasks).await {
-                println!("TL | error: {e}");
-            }
-            // Sync global tasklist with new list.
-            *data.write().await.get_mut::<TasksKey>().unwrap() = tasks;
+    let exit_code = match client.start().await {
+        // The client only stops without an error once the shutdown has closed its connections.
+        Ok(()) => match signal_handler.await {
+            Ok(true) => EXIT_OK,
+            _ => EXIT_NOT_SAVED,
+        },
+        Err(why) => {
+            println!("IN | Client error: {:?}", why);
+            signal_handler.abort();
+            let _ = shut_down(&client.data).await;
+            EXIT_CLIENT_ERROR
         }
-        interval.tick().await;
-    }
+    };
+    println!("IN | Exiting with code {exit_code}.");
+    std::process::exit(exit_code)
 }
@@ -1,18 +1,20 @@
-use crate::{cache_keys::ConfigKey, tasks::TaskType};
+use crate::{cache_keys::ConfigKey, invocation::Invocation};
 use anyhow::Result as AnyResult;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use clap::{
     error::{DefaultFormatter, Error},
     ColorChoice, Command, Parser,
 };
-use serenity::{http::CacheHttp, model::channel::Message, prelude::*};
+use serenity::{http::CacheHttp, model::id::RoleId, prelude::*};
 use std::io::{Error as IoError, ErrorKind};
-use tokio::{fs::File as AsyncFile, io::AsyncWriteExt, sync::RwLockReadGuard};
+use tokio::sync::RwLockReadGuard;
 
 // This file just contains some QoL stuff. Nothing important.
 
 pub type ClapResult<T, F = DefaultFormatter> = Result<T, Error<F>>;
 
+#[allow(unused_macros)]
 macro_rules! debug_println {
     ($($all:tt)*) => {
         #[cfg(debug_assertions)]
@@ -22,6 +24,7 @@ macro_rules! debug_println {
     }
 }
 
+#[allow(unused_imports)]
 pub(crate) use debug_println;
 
 pub fn escape_formatting<S: AsRef<str>>(s: S) -> String {
@@ -31,28 +34,84 @@ pub fn escape_formatting<S: AsRef<str>>(s: S) -> String {
 pub async fn is_administrator(
     http: impl CacheHttp,
     context_data: RwLockReadGuard<'_, TypeMap>,
-    message: &Message,
+    invocation: &Invocation<'_>,
 ) -> AnyResult<bool> {
     println!("CK | Getting user's roles.");
-    let user_roles = message.member(http).await?.roles;
-    Ok(context_data
+    let user_roles = invocation.author_roles(http).await?;
+    Ok(has_admin_role(context_data, &user_roles))
+}
+
+pub fn has_admin_role(context_data: RwLockReadGuard<'_, TypeMap>, user_roles: &[RoleId]) -> bool {
+    context_data
         .get::<ConfigKey>()
         .unwrap()
         .admin_roles
         .iter()
-        .any(|(_, role_id)| user_roles.contains(role_id)))
+        .any(|(_, role_id)| user_roles.contains(role_id))
 }
 
-pub async fn insufficient_perms(ctx: &Context, message: &Message) -> AnyResult<()> {
+pub async fn insufficient_perms(ctx: &Context, invocation: &Invocation<'_>) -> AnyResult<()> {
     println!("    User has insufficient permissions. Notifying and returning.");
-    let _ = message
-        .reply(
+    invocation
+        .reply_ephemeral(
             &ctx.http,
             "You'd best slide me over a bit of the good-good, comrade, or the officers will hear \
         about your attempt to usurp authority.",
         )
-        .await?;
-    Ok(())
+        .await
+}
+
+/// Turns a wall clock time in `tz` into UTC. Times that happen twice when the clocks go back resolve
+/// to the first of the two, and times skipped when the clocks go forward are moved an hour later.
+pub fn local_to_utc(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map_or_else(
+            || Utc.from_utc_datetime(&local),
+            |time| time.with_timezone(&Utc),
+        )
+}
+
+/// Shows a time in `tz` with the UTC time after it, or just the UTC time if `tz` is UTC.
+pub fn fmt_local_and_utc(time: DateTime<Utc>, tz: Tz) -> String {
+    let utc = time.format("%Y-%m-%d %H:%M:%S UTC");
+    if tz == Tz::UTC {
+        utc.to_string()
+    } else {
+        format!(
+            "{} ({utc})",
+            time.with_timezone(&tz).format("%Y-%m-%d %H:%M:%S %Z")
+        )
+    }
+}
+
+/// A date and time from the command line. With an offset (`2024-05-01T09:00:00Z`) it's a fixed
+/// point in time, and without one (`2024-05-01T09:00:00`) it's local to whichever time zone
+/// applies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DateTimeArg {
+    Fixed(DateTime<Utc>),
+    Local(NaiveDateTime),
+}
+
+impl DateTimeArg {
+    pub fn to_utc(self, tz: Tz) -> DateTime<Utc> {
+        match self {
+            DateTimeArg::Fixed(time) => time,
+            DateTimeArg::Local(local) => local_to_utc(tz, local),
+        }
+    }
+}
+
+pub fn parse_date_time(s: &str) -> AnyResult<DateTimeArg> {
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(time) => Ok(DateTimeArg::Fixed(time.with_timezone(&Utc))),
+        Err(_) => Ok(DateTimeArg::Local(s.parse()?)),
+    }
 }
 
 #[derive(Clone, Debug, Parser)]
@@ -61,58 +120,54 @@ pub async fn insufficient_perms(ctx: &Context, message: &Message) -> AnyResult<(
     color(ColorChoice::Never),
     no_binary_name(true)
 )]
+#[group(required = true, multiple = true)]
 pub struct CreateTimePeriod {
+    /// End date and time, local unless an offset is given
     #[arg(
         short = 'e',
         long = "end",
         name = "end_date",
-        required_unless_present_any(&[
-            "duration_secs",
-            "duration_mins",
-            "duration_hours",
-            "duration_days",
-            "duration_weeks"
-        ]),
+        value_parser = parse_date_time,
     )]
-    end_date: Option<DateTime<Utc>>,
+    end_date: Option<DateTimeArg>,
+    /// Seconds
     #[arg(
         short = 's',
         long = "secs",
         name = "duration_secs",
-        required_unless_present_any(&["duration_mins", "duration_hours", "duration_days", "duration_weeks"]),
-        conflicts_with("end_date"),
+        conflicts_with("end_date")
     )]
     duration_secs: Option<i64>,
+    /// Minutes
     #[arg(
         short = 'm',
         long = "mins",
         name = "duration_mins",
-        required_unless_present_any(&["duration_secs", "duration_hours", "duration_days", "duration_weeks"]),
-        conflicts_with("end_date"),
+        conflicts_with("end_date")
     )]
     duration_mins: Option<i64>,
+    /// Hours
     #[arg(
         short = 'h',
         long = "hours",
         name = "duration_hours",
-        required_unless_present_any(&["duration_secs", "duration_mins", "duration_days", "duration_weeks"]),
-        conflicts_with("end_date"),
+        conflicts_with("end_date")
     )]
     duration_hours: Option<i64>,
+    /// Days
     #[arg(
         short = 'd',
         long = "days",
         name = "duration_days",
-        required_unless_present_any(&["duration_secs", "duration_mins", "duration_hours", "duration_weeks"]),
-        conflicts_with("end_date"),
+        conflicts_with("end_date")
     )]
     duration_days: Option<i64>,
+    /// Weeks
     #[arg(
         short = 'w',
         long = "weeks",
         name = "duration_weeks",
-        required_unless_present_any(&["duration_secs", "duration_mins", "duration_hours", "duration_days"]),
-        conflicts_with("end_date"),
+        conflicts_with("end_date")
     )]
     duration_weeks: Option<i64>,
 }
@@ -120,7 +175,7 @@ pub struct CreateTimePeriod {
 type CVPairs<'a> = [(fn(i64) -> Duration, &'a Option<i64>); 5];
 
 impl CreateTimePeriod {
-    pub fn to_duration(&self) -> Duration {
+    pub fn to_duration(&self, tz: Tz) -> Duration {
         let CreateTimePeriod {
             end_date,
             duration_secs,
@@ -131,7 +186,7 @@ impl CreateTimePeriod {
         } = self;
         if let Some(end) = end_date {
             println!("DR | Duration specified by end time.");
-            end.signed_duration_since(Utc::now())
+            end.to_utc(tz).signed_duration_since(Utc::now())
         } else {
             println!("DR | Duration specified by parts.");
             let constructors_and_values: CVPairs = [
@@ -150,7 +205,7 @@ impl CreateTimePeriod {
         }
     }
 
-    pub fn to_datetime_utc(&self) -> AnyResult<DateTime<Utc>> {
+    pub fn to_datetime_utc(&self, tz: Tz) -> AnyResult<DateTime<Utc>> {
         let CreateTimePeriod {
             end_date,
             duration_secs,
@@ -161,7 +216,7 @@ impl CreateTimePeriod {
         } = self;
         if let Some(end) = end_date {
             println!("DR | Duration specified by end time.");
-            Ok(*end)
+            Ok(end.to_utc(tz))
         } else {
             println!("DR | Duration specified by parts.");
             let now = Utc::now();
@@ -188,26 +243,47 @@ impl CreateTimePeriod {
     }
 }
 
-pub async fn update_task_list(filename: &str, tasklist: &[TaskType]) -> AnyResult<()> {
-    println!("TL | Writing out task list.");
-    println!("TL | Assigning context task list to changed list.");
-    let mut tasks_file = AsyncFile::create(filename).await?;
-    let new_contents = serde_json::to_string_pretty(tasklist).unwrap();
-    tasks_file
-        .write_all(new_contents.as_bytes())
-        .await
-        .map_err(Into::into)
-}
-
 pub fn get_help_msg(mut app: Command) -> String {
     format!("```{}```", app.render_help())
 }
 
+/// A fresh directory for a test's files, removed again when dropped.
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("velvet_{name}_test_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Path of a file in the directory.
+    pub fn file(&self, filename: &str) -> String {
+        self.0.join(filename).to_str().unwrap().to_string()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::CreateTimePeriod;
-    use chrono::Duration;
+    use super::{local_to_utc, CreateTimePeriod};
+    use chrono::{Duration, NaiveDate, Timelike};
+    use chrono_tz::Tz;
 
+    #[test]
     fn test_create_time_period_duration() {
         let ctp = CreateTimePeriod {
             end_date: None,
@@ -217,6 +293,25 @@ mod test {
             duration_days: None,
             duration_weeks: None,
         };
-        assert_eq!(ctp.to_duration(), Duration::seconds(100));
+        assert_eq!(ctp.to_duration(Tz::UTC), Duration::seconds(100));
+    }
+
+    #[test]
+    fn test_local_to_utc_across_dst() {
+        let tz = Tz::Europe__London;
+        let at = |month, day, hour, min| {
+            NaiveDate::from_ymd_opt(2024, month, day)
+                .unwrap()
+                .and_hms_opt(hour, min, 0)
+                .unwrap()
+        };
+        assert_eq!(local_to_utc(tz, at(4, 8, 9, 0)).hour(), 8);
+        assert_eq!(local_to_utc(tz, at(1, 8, 9, 0)).hour(), 9);
+        // 01:30 doesn't exist on the 31st of March and happens twice on the 27th of October.
+        assert_eq!(
+            local_to_utc(tz, at(3, 31, 1, 30)),
+            local_to_utc(tz, at(3, 31, 2, 30))
+        );
+        assert_eq!(local_to_utc(tz, at(10, 27, 1, 30)).hour(), 0);
     }
 }
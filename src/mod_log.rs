@@ -0,0 +1,213 @@
+use crate::{escalation::is_permanent, EMBED_COLOUR, FOOTER_TEXT};
+use chrono::{DateTime, Duration, Utc};
+use serenity::{
+    builder::CreateEmbed,
+    http::Http,
+    model::id::{ChannelId, RoleId, UserId},
+    utils::Colour,
+};
+
+/// Something that happened to a prisoner that moderators should hear about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModLogKind {
+    Gulagged,
+    Extended,
+    /// A moderator ended the sentence early.
+    ReleasedEarly,
+    /// The sentence ran out.
+    Released,
+    /// A prisoner left the server.
+    Left,
+    /// A prisoner who left came back and was jailed again.
+    Rejailed,
+    /// Someone released while they weren't in the server came back and got their roles back.
+    Returned,
+    /// Someone changed a prisoner's roles, and they were changed back.
+    Tampered,
+    /// Someone removed the prisoner role, which ends the sentence.
+    RoleRemovedByHand,
+}
+
+impl ModLogKind {
+    fn title(self) -> &'static str {
+        match self {
+            ModLogKind::Gulagged => "Sent to gulag",
+            ModLogKind::Extended => "Sentence changed",
+            ModLogKind::ReleasedEarly => "Released early",
+            ModLogKind::Released => "Released",
+            ModLogKind::Left => "Left while in gulag",
+            ModLogKind::Rejailed => "Rejoined and sent back to gulag",
+            ModLogKind::Returned => "Rejoined after release",
+            ModLogKind::Tampered => "Roles changed during sentence",
+            ModLogKind::RoleRemovedByHand => "Prisoner role removed by hand",
+        }
+    }
+
+    /// What happened to the prisoner's roles, for the kinds of entry that change them.
+    fn roles_title(self) -> Option<&'static str> {
+        match self {
+            ModLogKind::Gulagged => Some("Roles removed"),
+            ModLogKind::ReleasedEarly | ModLogKind::Released | ModLogKind::Returned => {
+                Some("Roles restored")
+            }
+            ModLogKind::Tampered => Some("Roles taken away again"),
+            ModLogKind::Extended
+            | ModLogKind::Left
+            | ModLogKind::Rejailed
+            | ModLogKind::RoleRemovedByHand => None,
+        }
+    }
+}
+
+/// One post to the mod log. Anything left as `None` or empty is left out of the embed.
+#[derive(Clone, Debug)]
+pub struct ModLogEntry {
+    pub kind: ModLogKind,
+    pub user: (String, UserId),
+    pub moderator: Option<(String, UserId)>,
+    pub case: Option<u64>,
+    pub reason: Option<String>,
+    /// When the sentence ends.
+    pub end: Option<DateTime<Utc>>,
+    pub roles: Vec<(String, RoleId)>,
+    /// What went wrong, if the action didn't fully work.
+    pub failure: Option<String>,
+}
+
+impl ModLogEntry {
+    pub fn new(kind: ModLogKind, user: (String, UserId)) -> Self {
+        ModLogEntry {
+            kind,
+            user,
+            moderator: None,
+            case: None,
+            reason: None,
+            end: None,
+            roles: Vec::new(),
+            failure: None,
+        }
+    }
+
+    fn embed(&self) -> CreateEmbed {
+        let mut embed = CreateEmbed::default();
+        let title = match self.case {
+            Some(case) => format!("{} | Case {case}", self.kind.title()),
+            None => self.kind.title().into(),
+        };
+        embed
+            .title(title)
+            .colour(if self.failure.is_some() {
+                Colour::RED
+            } else {
+                EMBED_COLOUR
+            })
+            .field(
+                "User",
+                format!("<@{}> ({}, ID: {})", self.user.1, self.user.0, self.user.1),
+                true,
+            );
+        if let Some((name, id)) = &self.moderator {
+            embed.field("Moderator", format!("<@{id}> ({name})"), true);
+        }
+        match self.end {
+            Some(end) if is_permanent(end) => {
+                embed.field("Sentence", "Permanent", false);
+            }
+            Some(end) => {
+                embed.field(
+                    "Sentence",
+                    format!(
+                        "Until <t:{}> ({})",
+                        end.timestamp(),
+                        fmt_duration(end - Utc::now())
+                    ),
+                    false,
+                );
+            }
+            None => {}
+        }
+        if let Some(reason) = &self.reason {
+            embed.field("Reason", reason, false);
+        }
+        if let Some(roles_title) = self.kind.roles_title() {
+            let roles = if self.roles.is_empty() {
+                "None".into()
+            } else {
+                self.roles
+                    .iter()
+                    .map(|(_, id)| format!("<@&{id}>"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            embed.field(roles_title, roles, false);
+        }
+        if let Some(failure) = &self.failure {
+            embed.field("Failed", failure, false);
+        }
+        embed
+            .timestamp(Utc::now().to_rfc3339())
+            .footer(|f| f.text(FOOTER_TEXT));
+        embed
+    }
+
+    /// Posts the entry to the mod log channel, if one is configured. Failing to post is only
+    /// printed, so that the log can't get in the way of what it's logging.
+    pub async fn post(&self, http: &Http, channel: Option<ChannelId>) {
+        let channel = match channel {
+            Some(channel) => channel,
+            None => return,
+        };
+        println!(
+            "ML | Posting '{}' for user {} to the mod log.",
+            self.kind.title(),
+            self.user.1
+        );
+        let embed = self.embed();
+        if let Err(why) = channel
+            .send_message(http, |m| {
+                m.set_embed(embed)
+                    .allowed_mentions(|mentions| mentions.empty_parse())
+            })
+            .await
+        {
+            println!("ML | Failed to post to the mod log: {why}");
+        }
+    }
+}
+
+/// Roughly how long a duration is, like "3 days, 4 hours".
+fn fmt_duration(duration: Duration) -> String {
+    if duration < Duration::minutes(1) {
+        return "ends now".into();
+    }
+    let parts = [
+        (duration.num_weeks(), "week"),
+        (duration.num_days() % 7, "day"),
+        (duration.num_hours() % 24, "hour"),
+        (duration.num_minutes() % 60, "minute"),
+    ];
+    parts
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .take(2)
+        .map(|(count, unit)| format!("{count} {unit}{}", if *count == 1 { "" } else { "s" }))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::fmt_duration;
+    use chrono::Duration;
+
+    #[test]
+    fn test_fmt_duration() {
+        assert_eq!(fmt_duration(Duration::seconds(5)), "ends now");
+        assert_eq!(fmt_duration(Duration::minutes(61)), "1 hour, 1 minute");
+        assert_eq!(
+            fmt_duration(Duration::days(9) + Duration::hours(3)),
+            "1 week, 2 days"
+        );
+        assert_eq!(fmt_duration(Duration::weeks(2)), "2 weeks");
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
-    cache_keys::TasksKey,
+    cache_keys::{StorageKey, TasksKey},
+    invocation::Invocation,
     misc::{insufficient_perms, is_administrator, ClapResult},
-    tasks::TaskType,
+    scheduler::task_changed,
+    tasks::TaskId,
 };
 use clap::{error::ErrorKind, ColorChoice, Parser};
 use serenity::{
@@ -14,66 +16,72 @@ use std::time::Instant;
 #[derive(Clone, Debug, Parser)]
 #[command(color(ColorChoice::Never), no_binary_name(true))]
 pub(crate) struct ReleaseSearchCriteriumApp {
+    /// Prisoner to release
     #[arg(
         short = 'u',
         long = "user",
         name = "user",
-        conflicts_with("index"),
-        required_unless_present("index")
+        conflicts_with("id"),
+        required_unless_present("id")
     )]
     user: Option<UserId>,
+    /// Task ID of the sentence, as shown by `current_gulags`
     #[arg(
         short = 'i',
-        long = "index",
-        name = "index",
+        long = "id",
+        name = "id",
         conflicts_with("user"),
         required_unless_present("user")
     )]
-    index: Option<usize>,
+    id: Option<TaskId>,
 }
 
 #[derive(Debug)]
 enum ReleaseSearchCriterium {
     UserId(UserId),
-    Index(usize),
+    Id(TaskId),
 }
 
-fn try_get_release_info(s: &str) -> ClapResult<ReleaseSearchCriterium> {
-    println!("RG | Parsing remove gulag info command use from '{s}'");
-    let trimmed = s.trim_start_matches("=>release").trim();
-    println!("RG | Trimmed: '{trimmed}'");
-    let arg_matches = ReleaseSearchCriteriumApp::try_parse_from(trimmed.split_whitespace())?;
+fn try_get_release_info(args: Vec<String>) -> ClapResult<ReleaseSearchCriterium> {
+    println!("RG | Parsing remove gulag info command use from {args:?}");
+    let arg_matches = ReleaseSearchCriteriumApp::try_parse_from(args)?;
     println!("RG | Successfully parsed usage.");
-    let ReleaseSearchCriteriumApp { user, index } = arg_matches;
-    match (user, index) {
+    let ReleaseSearchCriteriumApp { user, id } = arg_matches;
+    match (user, id) {
         (Some(user), None) => Ok(ReleaseSearchCriterium::UserId(user)),
-        (None, Some(index)) => Ok(ReleaseSearchCriterium::Index(index)),
+        (None, Some(id)) => Ok(ReleaseSearchCriterium::Id(id)),
         _ => unreachable!(),
     }
 }
 
 #[command]
 pub async fn release(ctx: &Context, message: &Message) -> CommandResult {
+    run_release(ctx, &message.into()).await
+}
+
+pub async fn run_release(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("RG | Start handling removal of persistent gulag data.");
     let start = Instant::now();
     println!("RG | Grabbing read 'lock' on context data.");
     let context_data = ctx.data.read().await;
     println!("RG | Checking permissions.");
-    if is_administrator(&ctx.http, context_data, message).await? {
+    if is_administrator(&ctx.http, context_data, invocation).await? {
         println!("RG | Grabbing write 'lock' on context data.");
         let mut context_data = ctx.data.write().await;
-        let rgi = match try_get_release_info(&message.content) {
+        let rgi = match try_get_release_info(invocation.args("=>release")) {
             Ok(rgi) => rgi,
             Err(err) if err.kind() == ErrorKind::DisplayHelp => {
                 println!("RG | User requested help.");
-                message.reply(&ctx.http, format!("```{err}```")).await?;
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("```{err}```"))
+                    .await?;
                 println!("RG | Elapsed: {:?}", start.elapsed());
                 return Ok(());
             }
             Err(err) => {
                 println!("RG | Failed to parse user input. Sending error back.");
-                message
-                    .reply(
+                invocation
+                    .reply_ephemeral(
                         &ctx.http,
                         format!("Error parsing command. Details:\n```{err}```"),
                     )
@@ -85,28 +93,41 @@ pub async fn release(ctx: &Context, message: &Message) -> CommandResult {
         println!("RG | Gulag search criteria: {rgi:?}");
         println!("RG | Grabbing current tasks.");
         let tasks = context_data.get_mut::<TasksKey>().unwrap();
-        let gulag = match rgi {
-            ReleaseSearchCriterium::Index(index) => {
-                tasks.iter_mut().filter_map(TaskType::gulag_mut).nth(index)
+        let mut gulags = tasks.iter_mut().filter(|(_, task)| task.is_gulag());
+        let found = match rgi {
+            ReleaseSearchCriterium::Id(id) => gulags.find(|(&task_id, _)| task_id == id),
+            ReleaseSearchCriterium::UserId(user) => {
+                gulags.find(|(_, task)| task.gulag_ref().is_some_and(|gulag| gulag.user.1 == user))
             }
-            ReleaseSearchCriterium::UserId(user) => tasks
-                .iter_mut()
-                .filter_map(TaskType::gulag_mut)
-                .find(|gulag| gulag.user.1 == user),
         };
-        if let Some(gulag) = gulag {
+        if let Some((&id, task)) = found {
+            let gulag = task.gulag_mut().unwrap();
             println!("RG | Found gulag info: {}", gulag.list_fmt());
-            gulag.end = chrono::Utc::now();
+            let now = chrono::Utc::now();
+            gulag.end = now;
             println!("RG | Set gulag end time to now.");
-            // Removing this from the task list is handled by the task handler.
+            let case = gulag.case;
+            let task = task.clone();
+            let storage = context_data.get::<StorageKey>().unwrap();
+            storage.update_task(id, &task)?;
+            if let Some(case) = case {
+                println!("RG | Recording early release in case {case}.");
+                let moderator = (invocation.author().name.clone(), invocation.author().id);
+                storage.update_case(case, |case| {
+                    case.end = now;
+                    case.released_by = Some(moderator);
+                })?;
+            }
+            // Removing this from the task list is handled by the scheduler.
+            task_changed(&context_data, id);
         } else {
             println!("RG | No gulag tasks found for the given criterium.");
-            message
-                .reply(&ctx.http, "No gulag tasks found for the given criteria.")
+            invocation
+                .reply_ephemeral(&ctx.http, "No gulag tasks found for the given criteria.")
                 .await?;
         }
     } else {
-        insufficient_perms(ctx, message).await?;
+        insufficient_perms(ctx, invocation).await?;
     }
     println!("RG | Elapsed: {:?}", start.elapsed());
     Ok(())
@@ -0,0 +1,476 @@
+use crate::{
+    cache_keys::{ConfigKey, StorageKey, TaskSenderKey, TasksKey},
+    dead_letters::DeadLetter,
+    storage::Storage,
+    tasks::{misfire::plan_catch_up, TaskId, TaskType},
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::FutureExt;
+use serenity::{
+    http::client::Http,
+    prelude::{RwLock, TypeMap},
+};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::{Error as IoError, ErrorKind},
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::{JoinError, JoinSet},
+    time::{sleep, timeout},
+};
+
+// Tasks are kept in a queue ordered by when they next act, and the scheduler sleeps until the
+// first of them is due or until something about the task list changes. Due tasks act concurrently
+// so that one slow request (like uploading a new avatar) can't hold up a gulag release.
+
+/// How long a task gets to act before it's given up on.
+const ACT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest the scheduler sleeps for in one go, so that it notices if the system clock jumps.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+/// How many times a task gets to try acting before it's put on the dead letter list.
+const MAX_ATTEMPTS: u32 = 5;
+/// How long to wait before the first retry. Each retry after that waits twice as long.
+const RETRY_BASE_SECS: i64 = 30;
+
+pub type TaskSender = UnboundedSender<TaskMessage>;
+
+#[derive(Debug)]
+pub enum TaskMessage {
+    /// A new task to save and add to the task list.
+    New(Box<TaskType>),
+    /// A task in the task list was edited or removed, so when it next acts may have changed.
+    Changed(TaskId),
+    /// Stop starting tasks, wait for the ones that are acting and save them. Replies with whether
+    /// everything was saved.
+    Shutdown(oneshot::Sender<bool>),
+}
+
+/// Lets the scheduler know that a task in the task list was changed or removed.
+pub fn task_changed(context_data: &TypeMap, id: TaskId) {
+    let sender = context_data.get::<TaskSenderKey>().unwrap();
+    if let Err(e) = sender.send(TaskMessage::Changed(id)) {
+        println!("SC | error: {e}");
+    }
+}
+
+/// A task's ID, the task as it was before acting, the task after acting, and how acting went.
+type ActResult = (TaskId, TaskType, TaskType, AnyResult<()>);
+
+struct Scheduler {
+    data: Arc<RwLock<TypeMap>>,
+    http: Arc<Http>,
+    storage: Storage,
+    queue: BinaryHeap<Reverse<(DateTime<Utc>, TaskId)>>,
+    // The queue isn't updated when a task's deadline changes. Instead, entries that don't match
+    // the deadline here are skipped.
+    deadlines: HashMap<TaskId, DateTime<Utc>>,
+    running: HashSet<TaskId>,
+    // How many times each failing task has tried acting, and when it tries again.
+    retries: HashMap<TaskId, (u32, DateTime<Utc>)>,
+    // How many more times each task acts straight away for times it missed while the bot was
+    // offline.
+    catch_up: HashMap<TaskId, usize>,
+    acting: JoinSet<ActResult>,
+}
+
+/// How long to wait before trying again after a task has failed `attempts` times.
+fn retry_delay(attempts: u32) -> ChronoDuration {
+    ChronoDuration::seconds(RETRY_BASE_SECS << attempts.saturating_sub(1).min(16))
+}
+
+/// Whether two copies of a task are the same, going by what would be saved.
+fn same_task(a: &TaskType, b: &TaskType) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+impl Scheduler {
+    /// Works out when a task next acts and queues it. Finished tasks are removed instead.
+    async fn schedule(&mut self, id: TaskId, not_before: Option<DateTime<Utc>>) {
+        if self.running.contains(&id) {
+            // It gets rescheduled once it's done.
+            return;
+        }
+        let mut context_data = self.data.write().await;
+        let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+        let tasks = context_data.get_mut::<TasksKey>().unwrap();
+        let deadline = match tasks.get(&id) {
+            Some(task) if task.is_finished(default_tz) => {
+                println!("SC | Task {id} has finished - removing from task list.");
+                let _ = tasks.remove(&id);
+                if let Err(e) = self.storage.remove_task(id) {
+                    println!("SC | error: {e}");
+                }
+                None
+            }
+            // Retries are for the run that failed, which the task itself may have moved on from.
+            Some(task) if !task.is_paused() && self.retries.contains_key(&id) => {
+                self.retries.get(&id).map(|&(_, retry_at)| retry_at)
+            }
+            Some(task) if !task.is_paused() && self.catch_up.contains_key(&id) => Some(Utc::now()),
+            Some(task) => task.next_fire(default_tz),
+            None => None,
+        };
+        if deadline.is_none() {
+            let _ = self.retries.remove(&id);
+            let _ = self.catch_up.remove(&id);
+        }
+        match deadline {
+            Some(deadline) => {
+                let deadline = not_before.map_or(deadline, |not_before| deadline.max(not_before));
+                let _ = self.deadlines.insert(id, deadline);
+                self.queue.push(Reverse((deadline, id)));
+            }
+            None => {
+                let _ = self.deadlines.remove(&id);
+            }
+        }
+    }
+
+    async fn receive(&mut self, message: TaskMessage) {
+        match message {
+            TaskMessage::New(task) => {
+                println!("SC | Received new task - saving and adding to task list.");
+                let id = match self.storage.insert_task(&task) {
+                    Ok(id) => id,
+                    Err(e) => {
+                        println!("SC | error: {e}");
+                        return;
+                    }
+                };
+                let _ = self
+                    .data
+                    .write()
+                    .await
+                    .get_mut::<TasksKey>()
+                    .unwrap()
+                    .insert(id, *task);
+                self.schedule(id, None).await;
+                self.flush().await;
+            }
+            TaskMessage::Changed(id) => {
+                println!("SC | Task {id} changed - rescheduling.");
+                self.schedule(id, None).await;
+            }
+            TaskMessage::Shutdown(_) => unreachable!("Handled by the scheduler loop."),
+        }
+    }
+
+    /// Starts acting on every task whose deadline has passed.
+    async fn start_due(&mut self) {
+        let now = Utc::now();
+        while let Some(&Reverse((deadline, id))) = self.queue.peek() {
+            if deadline > now {
+                break;
+            }
+            let _ = self.queue.pop();
+            if self.deadlines.get(&id) != Some(&deadline) {
+                continue;
+            }
+            let _ = self.deadlines.remove(&id);
+            let task = match self.data.read().await.get::<TasksKey>().unwrap().get(&id) {
+                Some(task) => task.clone(),
+                None => continue,
+            };
+            let _ = self.running.insert(id);
+            let data = self.data.clone();
+            let http = self.http.clone();
+            let _ = self.acting.spawn(async move {
+                let before = task.clone();
+                let mut task = task;
+                let acting = AssertUnwindSafe(timeout(ACT_TIMEOUT, task.act(&data, &http)));
+                let result = match acting.catch_unwind().await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(_)) => Err(IoError::new(
+                        ErrorKind::TimedOut,
+                        format!("Timed out after {ACT_TIMEOUT:?}.").as_str(),
+                    )
+                    .into()),
+                    Err(_) => Err(IoError::other("Panicked while acting.").into()),
+                };
+                (id, before, task, result)
+            });
+        }
+    }
+
+    async fn finish(&mut self, joined: Result<ActResult, JoinError>) {
+        let (id, before, task, result) = match joined {
+            Ok(joined) => joined,
+            Err(e) => {
+                // Panics are caught while acting, so this only happens if the runtime is shutting
+                // down.
+                println!("SC | error: {e}");
+                return;
+            }
+        };
+        let _ = self.running.remove(&id);
+        let saved = match result {
+            Ok(()) => {
+                let _ = self.retries.remove(&id);
+                self.caught_up_once(id);
+                self.save_acted(id, &before, task).await
+            }
+            Err(e) => self.handle_failure(id, &e).await,
+        };
+        if let Err(e) = saved {
+            println!("SC | error: {e}");
+        }
+        // A task that acted without moving on to its next deadline would otherwise act again
+        // straight away, over and over.
+        self.schedule(id, Some(Utc::now() + ChronoDuration::seconds(1)))
+            .await;
+        self.flush().await;
+    }
+
+    /// Puts a task that acted successfully back in the task list, or removes it if it was a gulag.
+    /// If the task was changed while it was acting, like a paused task or an extended sentence, the
+    /// changes are kept and only what acting moved on is taken from the acted copy.
+    async fn save_acted(&self, id: TaskId, before: &TaskType, task: TaskType) -> AnyResult<()> {
+        let mut context_data = self.data.write().await;
+        let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+        let tasks = context_data.get_mut::<TasksKey>().unwrap();
+        let current = match tasks.get_mut(&id) {
+            Some(current) => current,
+            // Deleted while it was acting.
+            None => return Ok(()),
+        };
+        if !same_task(current, before) {
+            println!("SC | Task {id} was changed while acting - keeping the changes.");
+            // A gulag that changed is a sentence that was changed, so it isn't over.
+            current.take_progress(&task, default_tz);
+            return self.storage.update_task(id, current);
+        }
+        if task.is_gulag() {
+            println!("SC | Gulag period has elapsed - removing from task list.");
+            let _ = tasks.remove(&id);
+            self.storage.remove_task(id)
+        } else {
+            *current = task;
+            self.storage.update_task(id, current)
+        }
+    }
+
+    /// Counts off one of the runs a task owed for missed times, if it owed any.
+    fn caught_up_once(&mut self, id: TaskId) {
+        if let Some(remaining) = self.catch_up.get_mut(&id) {
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                let _ = self.catch_up.remove(&id);
+            }
+        }
+    }
+
+    /// Schedules a retry for a task that failed to act, or puts it on the dead letter list once it's
+    /// out of attempts. The task list keeps the task as it was before acting, so a retry repeats
+    /// the run that failed.
+    async fn handle_failure(&mut self, id: TaskId, error: &anyhow::Error) -> AnyResult<()> {
+        let attempts = self.retries.get(&id).map_or(0, |&(attempts, _)| attempts) + 1;
+        if attempts < MAX_ATTEMPTS {
+            let retry_at = Utc::now() + retry_delay(attempts);
+            println!(
+                "SC | Task {id} failed (attempt {attempts}/{MAX_ATTEMPTS}): {error}. Retrying at \
+                {retry_at}."
+            );
+            let _ = self.retries.insert(id, (attempts, retry_at));
+            return Ok(());
+        }
+        let _ = self.retries.remove(&id);
+        // The task moves on from the missed times too.
+        let _ = self.catch_up.remove(&id);
+        let mut context_data = self.data.write().await;
+        let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+        let tasks = context_data.get_mut::<TasksKey>().unwrap();
+        let task = match tasks.get_mut(&id) {
+            Some(task) => task,
+            // Deleted while it was acting.
+            None => return Ok(()),
+        };
+        let dead_letter = DeadLetter {
+            task_id: id,
+            task: task.clone(),
+            error: error.to_string(),
+            attempts,
+            failed_at: Utc::now(),
+        };
+        let dead_letter_id = self.storage.insert_dead_letter(&dead_letter)?;
+        println!(
+            "SC | Task {id} failed {attempts} times: {error}. Moved to dead letter \
+            {dead_letter_id}."
+        );
+        // Rerunning the dead letter makes up for the failed run, so the task moves on without it.
+        if task.is_gulag() {
+            let _ = tasks.remove(&id);
+            self.storage.remove_task(id)
+        } else {
+            task.skip_missed(default_tz)?;
+            self.storage.update_task(id, task)
+        }
+    }
+
+    /// Waits for every acting task to finish and saves the results. Returns whether everything was
+    /// saved.
+    async fn stop(&mut self) -> bool {
+        println!(
+            "SC | Stopping. Waiting for {} acting tasks.",
+            self.running.len()
+        );
+        self.queue.clear();
+        self.deadlines.clear();
+        while let Some(joined) = self.acting.join_next().await {
+            self.finish(joined).await;
+        }
+        // `last_evaluated` is left alone, so anything that comes due from now on counts as missed
+        // when the bot starts again.
+        match self.storage.flush().await {
+            Ok(()) => true,
+            Err(e) => {
+                println!("SC | error: {e}");
+                false
+            }
+        }
+    }
+
+    async fn flush(&self) {
+        if let Err(e) = self.storage.flush().await {
+            println!("SC | error: {e}");
+        }
+    }
+
+    fn time_until_next(&self) -> Duration {
+        self.queue
+            .peek()
+            .map_or(MAX_SLEEP, |&Reverse((deadline, _))| {
+                deadline
+                    .signed_duration_since(Utc::now())
+                    .to_std()
+                    .unwrap_or(Duration::ZERO)
+                    .min(MAX_SLEEP)
+            })
+    }
+}
+
+pub async fn run_scheduler(
+    data: Arc<RwLock<TypeMap>>,
+    http: Arc<Http>,
+    mut recv: UnboundedReceiver<TaskMessage>,
+) {
+    let storage = data.read().await.get::<StorageKey>().unwrap().clone();
+    let catch_up = plan_catch_up(&data, &storage).await;
+    let ids = data
+        .read()
+        .await
+        .get::<TasksKey>()
+        .unwrap()
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    let mut scheduler = Scheduler {
+        data,
+        http,
+        storage,
+        queue: BinaryHeap::new(),
+        deadlines: HashMap::new(),
+        running: HashSet::new(),
+        retries: HashMap::new(),
+        catch_up,
+        acting: JoinSet::new(),
+    };
+    for id in ids {
+        scheduler.schedule(id, None).await;
+    }
+    println!("SC | Scheduled {} tasks.", scheduler.deadlines.len());
+    loop {
+        scheduler.start_due().await;
+        // Everything that was due has been started. Remember that in case the bot goes down.
+        if let Err(e) = scheduler.storage.set_last_evaluated(Utc::now()) {
+            println!("SC | error: {e}");
+        }
+        let wait = scheduler.time_until_next();
+        tokio::select! {
+            message = recv.recv() => match message {
+                Some(TaskMessage::Shutdown(reply)) => {
+                    let saved = scheduler.stop().await;
+                    let _ = reply.send(saved);
+                    break;
+                }
+                Some(message) => scheduler.receive(message).await,
+                None => {
+                    println!("SC | Task channel closed. Stopping scheduler.");
+                    break;
+                }
+            },
+            Some(joined) = scheduler.acting.join_next() => scheduler.finish(joined).await,
+            () = sleep(wait) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{retry_delay, same_task, MAX_ATTEMPTS};
+    use crate::tasks::{misfire::MisfirePolicy, periodic_task::PeriodicTask, task::Task, TaskType};
+    use chrono::{Duration, Utc};
+    use chrono_tz::Tz;
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(4), Duration::minutes(4));
+        let total = (1..MAX_ATTEMPTS)
+            .map(retry_delay)
+            .fold(Duration::zero(), |a, b| a + b);
+        assert!(total < Duration::hours(1));
+        // Doesn't overflow however many attempts there have been.
+        assert!(retry_delay(u32::MAX) > Duration::zero());
+    }
+
+    #[test]
+    fn test_changes_made_while_acting_are_kept() {
+        let before = TaskType::PeriodicTask(PeriodicTask {
+            task: Task::RotateThemes {
+                themes: vec!["day".into(), "night".into()],
+                next: 0,
+            },
+            diff: 60,
+            last_sent: Utc::now().naive_utc() - Duration::seconds(61),
+            time_zone: None,
+            misfire_policy: MisfirePolicy::default(),
+            paused: false,
+            runs: 0,
+            max_runs: None,
+            until: None,
+        });
+        let mut acted = before.clone();
+        if let TaskType::PeriodicTask(pt) = &mut acted {
+            pt.last_sent += Duration::seconds(60);
+            pt.runs = 1;
+            if let Task::RotateThemes { next, .. } = &mut pt.task {
+                *next = 1;
+            }
+        }
+        let mut current = before.clone();
+        current.set_paused(true, Tz::UTC).unwrap();
+        assert!(!same_task(&current, &before));
+        current.take_progress(&acted, Tz::UTC);
+        match &current {
+            TaskType::PeriodicTask(pt) => {
+                assert!(pt.paused);
+                assert_eq!(pt.runs, 1);
+                assert!(matches!(pt.task, Task::RotateThemes { next: 1, .. }));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
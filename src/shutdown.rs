@@ -0,0 +1,96 @@
+use crate::{
+    cache_keys::{ShardManagerKey, ShuttingDownKey, StorageKey, TaskSenderKey, TasksKey},
+    scheduler::TaskMessage,
+};
+use serenity::prelude::{RwLock, TypeMap};
+use std::{sync::Arc, time::Duration};
+use tokio::{signal, sync::oneshot, time::timeout};
+
+// Exit codes, so that whatever restarts the bot can tell what happened.
+
+/// Shut down after a signal with everything saved.
+pub const EXIT_OK: i32 = 0;
+/// The connection to Discord failed.
+pub const EXIT_CLIENT_ERROR: i32 = 2;
+/// Shut down, but some of the task list or in-flight task actions may not have been saved.
+pub const EXIT_NOT_SAVED: i32 = 3;
+
+/// How long to wait for tasks that are acting to finish. Acting times out after a minute anyway.
+const SCHEDULER_STOP_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Resolves once the process is asked to stop, with the name of the signal.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal as unix_signal, SignalKind};
+        match unix_signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = signal::ctrl_c() => return "SIGINT",
+                    _ = sigterm.recv() => return "SIGTERM",
+                }
+            }
+            Err(e) => println!("SD | Can't listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = signal::ctrl_c().await {
+        println!("SD | Can't listen for ctrl+c: {e}");
+        // Nothing can ask for a shutdown, so never resolve.
+        std::future::pending::<()>().await;
+    }
+    "SIGINT"
+}
+
+/// Stops taking commands, lets the scheduler finish whatever tasks are acting, saves the task list
+/// and disconnects from Discord. Returns whether everything was saved.
+pub async fn shut_down(data: &Arc<RwLock<TypeMap>>) -> bool {
+    println!("SD | No longer accepting commands.");
+    data.write().await.insert::<ShuttingDownKey>(true);
+    let mut saved = stop_scheduler(data).await;
+    println!("SD | Saving task list.");
+    let context_data = data.read().await;
+    let storage = context_data.get::<StorageKey>().unwrap().clone();
+    for (&id, task) in context_data.get::<TasksKey>().unwrap() {
+        if let Err(e) = storage.update_task(id, task) {
+            println!("SD | Failed to save task {id}: {e}");
+            saved = false;
+        }
+    }
+    drop(context_data);
+    if let Err(e) = storage.flush().await {
+        println!("SD | Failed to flush storage: {e}");
+        saved = false;
+    }
+    println!("SD | Closing gateway connections.");
+    let shard_manager = data.read().await.get::<ShardManagerKey>().cloned();
+    if let Some(shard_manager) = shard_manager {
+        shard_manager.lock().await.shutdown_all().await;
+    }
+    saved
+}
+
+async fn stop_scheduler(data: &Arc<RwLock<TypeMap>>) -> bool {
+    println!("SD | Waiting for acting tasks to finish.");
+    let (send, recv) = oneshot::channel();
+    let sent = data
+        .read()
+        .await
+        .get::<TaskSenderKey>()
+        .unwrap()
+        .send(TaskMessage::Shutdown(send));
+    if let Err(e) = sent {
+        println!("SD | Scheduler isn't running: {e}");
+        return false;
+    }
+    match timeout(SCHEDULER_STOP_TIMEOUT, recv).await {
+        Ok(Ok(saved)) => saved,
+        Ok(Err(e)) => {
+            println!("SD | Scheduler stopped without replying: {e}");
+            false
+        }
+        Err(_) => {
+            println!("SD | Gave up waiting for the scheduler after {SCHEDULER_STOP_TIMEOUT:?}.");
+            false
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::invocation::Invocation;
 use serenity::{
     client::Context,
     framework::standard::{macros::command, CommandResult},
@@ -7,9 +8,13 @@ use std::time::Instant;
 
 #[command]
 pub async fn source(ctx: &Context, message: &Message) -> CommandResult {
+    run_source(ctx, &message.into()).await
+}
+
+pub async fn run_source(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     let start = Instant::now();
     println!("SC | Responding to source command.");
-    let _ = message
+    invocation
         .reply(
             &ctx.http,
             "My source code is available at https://github.com/AuroransSolis/officer_velvet.",
@@ -0,0 +1,855 @@
+use crate::{
+    cases::Case,
+    dead_letters::DeadLetter,
+    tasks::{components::MessageComponent, TaskId, TaskType},
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use serenity::model::id::{ChannelId, MessageId, RoleId, UserId};
+use sled::{
+    transaction::{TransactionResult, Transactional},
+    Batch, Db, Tree,
+};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+};
+
+// Everything the bot needs to remember across restarts lives in one sled database. Each kind of
+// record gets its own tree, and records are stored as JSON so that they stay readable with any
+// sled inspection tool.
+
+const TASKS_TREE: &str = "tasks";
+const META_TREE: &str = "meta";
+const DEAD_LETTERS_TREE: &str = "dead_letters";
+const PINGS_TREE: &str = "pings";
+const REACTIONS_TREE: &str = "reactions";
+const ACTIVITY_TREE: &str = "activity";
+const CASES_TREE: &str = "cases";
+const COMPONENTS_TREE: &str = "components";
+const TASKS_IMPORTED_KEY: &str = "tasks_file_imported";
+const LAST_EVALUATED_KEY: &str = "last_evaluated";
+const PINGS_BACKFILLED_KEY: &str = "pings_backfilled";
+const PINGS_READ_UP_TO_KEY: &str = "pings_read_up_to";
+const HALL_OF_FAME_KEY: &str = "hall_of_fame";
+const ACTIVITY_SINCE_KEY: &str = "activity_since";
+const AVATAR_CHANGES_KEY: &str = "avatar_changes";
+const CURRENT_AVATAR_KEY: &str = "current_avatar";
+const LAST_LEADERBOARD_ID_KEY: &str = "last_leaderboard_id";
+const OWED_ROLES_KEY: &str = "owed_roles";
+const LAST_CASE_NUMBER_KEY: &str = "last_case_number";
+// Prefixes for the two kinds of counter in the activity tree.
+const CHANNEL_ACTIVITY: u8 = b'c';
+const USER_ACTIVITY: u8 = b'u';
+
+/// Name and ID of each user a message mentions.
+pub type Mentions = Vec<(String, UserId)>;
+
+/// Case a released prisoner's sentence was recorded in, and the roles they're still owed.
+pub type OwedRoles = (Option<u64>, Vec<(String, RoleId)>);
+
+/// Someone reacting to a message with an emoji.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredReaction {
+    pub message: MessageId,
+    pub reactor: UserId,
+    /// The emoji itself for Unicode emoji, or the emoji's ID for custom ones.
+    pub emoji: String,
+    pub author: UserId,
+    pub author_name: String,
+    pub reacted_at: DateTime<Utc>,
+}
+
+/// Messages sent since the activity counters were last reset.
+#[derive(Clone, Debug, Default)]
+pub struct ActivityCounts {
+    pub since: Option<DateTime<Utc>>,
+    pub channels: Vec<(ChannelId, u64)>,
+    /// Latest name, ID and message count of each user.
+    pub users: Vec<(String, UserId, u64)>,
+}
+
+impl ActivityCounts {
+    fn add_entry(&mut self, key: &[u8], value: &[u8]) -> AnyResult<()> {
+        let id = key_to_id(&key[1..])?;
+        match key[0] {
+            CHANNEL_ACTIVITY => self
+                .channels
+                .push((ChannelId(id), serde_json::from_slice(value)?)),
+            _ => {
+                let (name, count) = serde_json::from_slice(value)?;
+                self.users.push((name, UserId(id), count));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    db: Db,
+    tasks: Tree,
+    meta: Tree,
+    dead_letters: Tree,
+    // Users mentioned in each message, keyed by channel ID then message ID. Message IDs grow with
+    // time, so a channel's messages since some time are one range.
+    pings: Tree,
+    // One entry per reaction, keyed by channel ID, message ID, reacting user ID and emoji, holding
+    // who wrote the message.
+    reactions: Tree,
+    // Message counts per channel and per user, keyed by a prefix byte then the ID.
+    activity: Tree,
+    // Gulag sentences past and present, keyed by case number.
+    cases: Tree,
+    // Components of each sent message that has replies, keyed by message ID. They're kept after
+    // the task that sent them is gone, so that its buttons keep working.
+    components: Tree,
+}
+
+impl Storage {
+    pub fn open(path: &str) -> AnyResult<Self> {
+        println!("DB | Opening database at '{path}'.");
+        Self::with_db(sled::open(path)?)
+    }
+
+    /// A database that only lives in memory and is gone once dropped, for tests.
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        Self::with_db(sled::Config::new().temporary(true).open().unwrap()).unwrap()
+    }
+
+    fn with_db(db: Db) -> AnyResult<Self> {
+        let tasks = db.open_tree(TASKS_TREE)?;
+        let meta = db.open_tree(META_TREE)?;
+        let dead_letters = db.open_tree(DEAD_LETTERS_TREE)?;
+        let pings = db.open_tree(PINGS_TREE)?;
+        let reactions = db.open_tree(REACTIONS_TREE)?;
+        let activity = db.open_tree(ACTIVITY_TREE)?;
+        let cases = db.open_tree(CASES_TREE)?;
+        let components = db.open_tree(COMPONENTS_TREE)?;
+        Ok(Storage {
+            db,
+            tasks,
+            meta,
+            dead_letters,
+            pings,
+            reactions,
+            activity,
+            cases,
+            components,
+        })
+    }
+
+    /// Moves the tasks from an old-style JSON tasks file into the database. Only ever happens once
+    /// per database - the file is renamed afterwards so it's obvious that it's no longer used.
+    pub fn import_tasks_file(&self, filename: &str) -> AnyResult<()> {
+        if self.meta.contains_key(TASKS_IMPORTED_KEY)? {
+            return Ok(());
+        }
+        let contents = match fs::read_to_string(filename) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == IoErrorKind::NotFound => {
+                println!("DB | No tasks file at '{filename}' to import.");
+                String::new()
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let imported = if contents.trim().is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_str::<Vec<TaskType>>(&contents)?
+        };
+        println!("DB | Importing {} tasks from '{filename}'.", imported.len());
+        let entries = imported
+            .iter()
+            .map(|task| Ok((self.db.generate_id()?, serde_json::to_vec(task)?)))
+            .collect::<AnyResult<Vec<_>>>()?;
+        // The tasks and the mark that they were imported are written together, so that a crash
+        // can't leave the tasks in without the mark and have them imported again.
+        let imported_now: TransactionResult<bool, sled::Error> = (&self.tasks, &self.meta)
+            .transaction(|(tasks, meta)| {
+                if meta.get(TASKS_IMPORTED_KEY)?.is_some() {
+                    return Ok(false);
+                }
+                for (id, task) in &entries {
+                    let _ = tasks.insert(&id.to_be_bytes(), task.as_slice())?;
+                }
+                let _ = meta.insert(TASKS_IMPORTED_KEY, &[1])?;
+                Ok(true)
+            });
+        if !imported_now? {
+            return Ok(());
+        }
+        self.db.flush()?;
+        if !contents.is_empty() {
+            let renamed = format!("{filename}.imported");
+            fs::rename(filename, &renamed)?;
+            println!("DB | Renamed imported tasks file to '{renamed}'.");
+        }
+        Ok(())
+    }
+
+    pub fn load_tasks(&self) -> AnyResult<BTreeMap<TaskId, TaskType>> {
+        self.tasks
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key_to_id(&key)?, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// Saves a new task and returns the ID it was given.
+    pub fn insert_task(&self, task: &TaskType) -> AnyResult<TaskId> {
+        let id = self.db.generate_id()?;
+        self.tasks
+            .insert(id.to_be_bytes(), serde_json::to_vec(task)?)?;
+        Ok(id)
+    }
+
+    pub fn update_task(&self, id: TaskId, task: &TaskType) -> AnyResult<()> {
+        let _ = self
+            .tasks
+            .insert(id.to_be_bytes(), serde_json::to_vec(task)?)?;
+        Ok(())
+    }
+
+    pub fn remove_task(&self, id: TaskId) -> AnyResult<()> {
+        let _ = self.tasks.remove(id.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Hands out a leaderboard ID that's never been used, so that tasks and ranking state left
+    /// over from a deleted leaderboard can't attach to a new one. IDs start above `above`, for
+    /// leaderboards made before IDs were counted here.
+    pub fn next_leaderboard_id(&self, above: u64) -> AnyResult<u64> {
+        self.next_number(LAST_LEADERBOARD_ID_KEY, above)
+    }
+
+    /// Counts up the number kept at `key` in one atomic step, starting above `above`.
+    fn next_number(&self, key: &str, above: u64) -> AnyResult<u64> {
+        let number = self
+            .meta
+            .update_and_fetch(key, |old| {
+                let last = old
+                    .and_then(|old| serde_json::from_slice::<u64>(old).ok())
+                    .unwrap_or(0);
+                serde_json::to_vec(&(last.max(above) + 1)).ok()
+            })?
+            .map(|value| serde_json::from_slice(&value))
+            .transpose()?
+            .unwrap_or(above + 1);
+        Ok(number)
+    }
+
+    pub fn load_dead_letters(&self) -> AnyResult<BTreeMap<u64, DeadLetter>> {
+        self.dead_letters
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((key_to_id(&key)?, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// Saves a task that failed for good and returns the ID its entry was given.
+    pub fn insert_dead_letter(&self, dead_letter: &DeadLetter) -> AnyResult<u64> {
+        let id = self.db.generate_id()?;
+        self.dead_letters
+            .insert(id.to_be_bytes(), serde_json::to_vec(dead_letter)?)?;
+        Ok(id)
+    }
+
+    pub fn update_dead_letter(&self, id: u64, dead_letter: &DeadLetter) -> AnyResult<()> {
+        let _ = self
+            .dead_letters
+            .insert(id.to_be_bytes(), serde_json::to_vec(dead_letter)?)?;
+        Ok(())
+    }
+
+    pub fn remove_dead_letter(&self, id: u64) -> AnyResult<()> {
+        let _ = self.dead_letters.remove(id.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Saves a new case and returns its number. Cases are numbered from 1 in the order they're
+    /// opened.
+    pub fn insert_case(&self, case: &Case) -> AnyResult<u64> {
+        // Cases opened before the count was kept carry on from the last one.
+        let last = match self.cases.last()? {
+            Some((key, _)) => key_to_id(&key)?,
+            None => 0,
+        };
+        let number = self.next_number(LAST_CASE_NUMBER_KEY, last)?;
+        let _ = self
+            .cases
+            .insert(number.to_be_bytes(), serde_json::to_vec(case)?)?;
+        Ok(number)
+    }
+
+    pub fn case(&self, number: u64) -> AnyResult<Option<Case>> {
+        self.cases
+            .get(number.to_be_bytes())?
+            .map(|value| Ok(serde_json::from_slice::<Case>(&value)?))
+            .transpose()
+    }
+
+    /// Changes a case, if there is one with that number.
+    pub fn update_case(&self, number: u64, update: impl FnOnce(&mut Case)) -> AnyResult<()> {
+        if let Some(mut case) = self.case(number)? {
+            update(&mut case);
+            let _ = self
+                .cases
+                .insert(number.to_be_bytes(), serde_json::to_vec(&case)?)?;
+        }
+        Ok(())
+    }
+
+    /// Every case about a user, oldest first.
+    pub fn cases_for(&self, user: UserId) -> AnyResult<Vec<(u64, Case)>> {
+        let mut cases = Vec::new();
+        for entry in &self.cases {
+            let (key, value) = entry?;
+            let case = serde_json::from_slice::<Case>(&value)?;
+            if case.user.1 == user {
+                cases.push((key_to_id(&key)?, case));
+            }
+        }
+        Ok(cases)
+    }
+
+    /// Keeps the roles of a prisoner who was released while they weren't in the server, so that
+    /// they can be given back if they return.
+    pub fn owe_roles(
+        &self,
+        user: UserId,
+        case: Option<u64>,
+        roles: &[(String, RoleId)],
+    ) -> AnyResult<()> {
+        let _ = self.meta.insert(
+            format!("{OWED_ROLES_KEY}_{user}"),
+            serde_json::to_vec(&(case, roles))?,
+        )?;
+        Ok(())
+    }
+
+    /// Takes the roles owed to a user, along with the case they were taken away in.
+    pub fn take_owed_roles(&self, user: UserId) -> AnyResult<Option<OwedRoles>> {
+        self.meta
+            .remove(format!("{OWED_ROLES_KEY}_{user}"))?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    /// Keeps the components a message was sent with, so that clicks on it can be answered.
+    pub fn insert_components(
+        &self,
+        message: MessageId,
+        components: &[Vec<MessageComponent>],
+    ) -> AnyResult<()> {
+        let _ = self
+            .components
+            .insert(message.0.to_be_bytes(), serde_json::to_vec(components)?)?;
+        Ok(())
+    }
+
+    pub fn components(&self, message: MessageId) -> AnyResult<Option<Vec<Vec<MessageComponent>>>> {
+        self.components
+            .get(message.0.to_be_bytes())?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    pub fn insert_pings(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+        mentioned: &[(String, UserId)],
+    ) -> AnyResult<()> {
+        let _ = self
+            .pings
+            .insert(ping_key(channel, message), serde_json::to_vec(mentioned)?)?;
+        Ok(())
+    }
+
+    pub fn remove_pings(&self, channel: ChannelId, message: MessageId) -> AnyResult<()> {
+        let _ = self.pings.remove(ping_key(channel, message))?;
+        Ok(())
+    }
+
+    /// Users mentioned in each message in the channel with an ID of at least `since`, oldest first.
+    pub fn pings_since(
+        &self,
+        channel: ChannelId,
+        since: MessageId,
+    ) -> AnyResult<Vec<(MessageId, Mentions)>> {
+        self.pings
+            .range(ping_key(channel, since)..=ping_key(channel, MessageId(u64::MAX)))
+            .map(|entry| {
+                let (key, value) = entry?;
+                let message = MessageId(key_to_id(&key[8..])?);
+                Ok((message, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    /// How far back a channel's history has been read into the pings tree, if it has been at all.
+    pub fn pings_backfilled_since(&self, channel: ChannelId) -> AnyResult<Option<DateTime<Utc>>> {
+        self.meta
+            .get(format!("{PINGS_BACKFILLED_KEY}_{channel}"))?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    pub fn set_pings_backfilled_since(
+        &self,
+        channel: ChannelId,
+        since: DateTime<Utc>,
+    ) -> AnyResult<()> {
+        let _ = self.meta.insert(
+            format!("{PINGS_BACKFILLED_KEY}_{channel}"),
+            serde_json::to_vec(&since)?,
+        )?;
+        Ok(())
+    }
+
+    /// The newest message in a channel that everything up to has been read into the pings tree.
+    pub fn pings_read_up_to(&self, channel: ChannelId) -> AnyResult<Option<MessageId>> {
+        self.meta
+            .get(format!("{PINGS_READ_UP_TO_KEY}_{channel}"))?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    pub fn set_pings_read_up_to(&self, channel: ChannelId, message: MessageId) -> AnyResult<()> {
+        let _ = self.meta.insert(
+            format!("{PINGS_READ_UP_TO_KEY}_{channel}"),
+            serde_json::to_vec(&message)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_reaction(&self, channel: ChannelId, reaction: &StoredReaction) -> AnyResult<()> {
+        let _ = self.reactions.insert(
+            reaction_key(channel, reaction.message, reaction.reactor, &reaction.emoji),
+            serde_json::to_vec(&(reaction.author, &reaction.author_name, reaction.reacted_at))?,
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_reaction(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+        reactor: UserId,
+        emoji: &str,
+    ) -> AnyResult<()> {
+        let _ = self
+            .reactions
+            .remove(reaction_key(channel, message, reactor, emoji))?;
+        Ok(())
+    }
+
+    /// Forgets every reaction on a message.
+    pub fn remove_reactions(&self, channel: ChannelId, message: MessageId) -> AnyResult<()> {
+        let mut batch = Batch::default();
+        for entry in self.reactions.scan_prefix(ping_key(channel, message)) {
+            batch.remove(entry?.0);
+        }
+        self.reactions.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Reactions made since `since` in the channel, or in any channel if there isn't one. Old
+    /// messages can get new reactions, so every message in the channel is looked at.
+    pub fn reactions_since(
+        &self,
+        channel: Option<ChannelId>,
+        since: DateTime<Utc>,
+    ) -> AnyResult<Vec<StoredReaction>> {
+        let entries = match channel {
+            Some(channel) => self.reactions.scan_prefix(channel.0.to_be_bytes()),
+            None => self.reactions.range::<&[u8], _>(..),
+        };
+        let mut reactions = Vec::new();
+        for entry in entries {
+            let (key, value) = entry?;
+            let reaction = parse_reaction(&key, &value)?;
+            if reaction.reacted_at >= since {
+                reactions.push(reaction);
+            }
+        }
+        Ok(reactions)
+    }
+
+    /// Every reaction recorded on a message.
+    pub fn reactions_on(
+        &self,
+        channel: ChannelId,
+        message: MessageId,
+    ) -> AnyResult<Vec<StoredReaction>> {
+        self.reactions
+            .scan_prefix(ping_key(channel, message))
+            .map(|entry| {
+                let (key, value) = entry?;
+                parse_reaction(&key, &value)
+            })
+            .collect()
+    }
+
+    /// Marks a message as reposted to the hall of fame. Returns false if it already was.
+    pub fn add_to_hall_of_fame(&self, message: MessageId) -> AnyResult<bool> {
+        let added = self.meta.compare_and_swap(
+            format!("{HALL_OF_FAME_KEY}_{message}"),
+            None as Option<&[u8]>,
+            Some(serde_json::to_vec(&Utc::now())?),
+        )?;
+        Ok(added.is_ok())
+    }
+
+    pub fn remove_from_hall_of_fame(&self, message: MessageId) -> AnyResult<()> {
+        let _ = self.meta.remove(format!("{HALL_OF_FAME_KEY}_{message}"))?;
+        Ok(())
+    }
+
+    /// Counts a message towards its channel's and its author's activity.
+    pub fn record_activity(&self, channel: ChannelId, user: UserId, name: &str) -> AnyResult<()> {
+        self.add_activity(channel, 1)?;
+        self.add_user_activity(user, name, 1, false)?;
+        // The first message counted starts the period if nothing has reset it yet.
+        let _ = self.meta.compare_and_swap(
+            ACTIVITY_SINCE_KEY,
+            None as Option<&[u8]>,
+            Some(serde_json::to_vec(&Utc::now())?),
+        )?;
+        Ok(())
+    }
+
+    fn add_activity(&self, channel: ChannelId, messages: u64) -> AnyResult<()> {
+        // A counter that can't be read starts again from zero rather than stopping the count.
+        let _ =
+            self.activity
+                .update_and_fetch(activity_key(CHANNEL_ACTIVITY, channel.0), |old| {
+                    let count = old
+                        .and_then(|old| serde_json::from_slice::<u64>(old).ok())
+                        .unwrap_or(0);
+                    serde_json::to_vec(&(count + messages)).ok()
+                })?;
+        Ok(())
+    }
+
+    /// Adds to a user's message count. The name given replaces the one stored unless
+    /// `keep_name` is set and there already is one.
+    fn add_user_activity(
+        &self,
+        user: UserId,
+        name: &str,
+        messages: u64,
+        keep_name: bool,
+    ) -> AnyResult<()> {
+        let _ = self
+            .activity
+            .update_and_fetch(activity_key(USER_ACTIVITY, user.0), |old| {
+                let old = old.and_then(|old| serde_json::from_slice::<(String, u64)>(old).ok());
+                let count = old.as_ref().map_or(0, |(_, count)| *count);
+                let name = match old {
+                    Some((old_name, _)) if keep_name => old_name,
+                    _ => name.to_string(),
+                };
+                serde_json::to_vec(&(name, count + messages)).ok()
+            })?;
+        Ok(())
+    }
+
+    pub fn activity(&self) -> AnyResult<ActivityCounts> {
+        let mut counts = ActivityCounts {
+            since: self
+                .meta
+                .get(ACTIVITY_SINCE_KEY)?
+                .map(|value| serde_json::from_slice(&value))
+                .transpose()?,
+            ..Default::default()
+        };
+        for entry in &self.activity {
+            let (key, value) = entry?;
+            counts.add_entry(&key, &value)?;
+        }
+        Ok(counts)
+    }
+
+    /// Takes the activity counters, leaving them at zero for a new period starting now. Each
+    /// counter is removed as it's read, so a message counted meanwhile ends up in either this
+    /// period or the next one.
+    pub fn take_activity(&self) -> AnyResult<ActivityCounts> {
+        let mut counts = ActivityCounts {
+            since: self
+                .meta
+                .insert(ACTIVITY_SINCE_KEY, serde_json::to_vec(&Utc::now())?)?
+                .map(|value| serde_json::from_slice(&value))
+                .transpose()?,
+            ..Default::default()
+        };
+        for key in self.activity.iter().keys() {
+            let key = key?;
+            if let Some(value) = self.activity.remove(&key)? {
+                counts.add_entry(&key, &value)?;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Puts back counters taken with `take_activity`, for when they couldn't be reported.
+    pub fn restore_activity(&self, counts: &ActivityCounts) -> AnyResult<()> {
+        for &(channel, messages) in &counts.channels {
+            self.add_activity(channel, messages)?;
+        }
+        // Names counted since are more recent.
+        for (name, user, messages) in &counts.users {
+            self.add_user_activity(*user, name, *messages, true)?;
+        }
+        if let Some(since) = counts.since {
+            let _ = self
+                .meta
+                .insert(ACTIVITY_SINCE_KEY, serde_json::to_vec(&since)?)?;
+        }
+        Ok(())
+    }
+
+    /// When the bot's avatar was recently changed, oldest first.
+    pub fn avatar_changes(&self) -> AnyResult<Vec<DateTime<Utc>>> {
+        self.meta
+            .get(AVATAR_CHANGES_KEY)?
+            .map_or(Ok(Vec::new()), |value| Ok(serde_json::from_slice(&value)?))
+    }
+
+    pub fn set_avatar_changes(&self, changes: &[DateTime<Utc>]) -> AnyResult<()> {
+        let _ = self
+            .meta
+            .insert(AVATAR_CHANGES_KEY, serde_json::to_vec(changes)?)?;
+        Ok(())
+    }
+
+    /// Name of the file the avatar was last changed to.
+    pub fn current_avatar(&self) -> AnyResult<Option<String>> {
+        Ok(self
+            .meta
+            .get(CURRENT_AVATAR_KEY)?
+            .map(|value| String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    pub fn set_current_avatar(&self, filename: &str) -> AnyResult<()> {
+        let _ = self.meta.insert(CURRENT_AVATAR_KEY, filename.as_bytes())?;
+        Ok(())
+    }
+
+    /// When the task list was last checked for tasks to act on, if it ever has been.
+    pub fn last_evaluated(&self) -> AnyResult<Option<DateTime<Utc>>> {
+        self.meta
+            .get(LAST_EVALUATED_KEY)?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    pub fn set_last_evaluated(&self, time: DateTime<Utc>) -> AnyResult<()> {
+        let _ = self
+            .meta
+            .insert(LAST_EVALUATED_KEY, serde_json::to_vec(&time)?)?;
+        Ok(())
+    }
+
+    pub async fn flush(&self) -> AnyResult<()> {
+        let _ = self.db.flush_async().await?;
+        Ok(())
+    }
+}
+
+fn ping_key(channel: ChannelId, message: MessageId) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..8].copy_from_slice(&channel.0.to_be_bytes());
+    key[8..].copy_from_slice(&message.0.to_be_bytes());
+    key
+}
+
+fn activity_key(prefix: u8, id: u64) -> [u8; 9] {
+    let mut key = [prefix; 9];
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn reaction_key(channel: ChannelId, message: MessageId, reactor: UserId, emoji: &str) -> Vec<u8> {
+    let mut key = ping_key(channel, message).to_vec();
+    key.extend_from_slice(&reactor.0.to_be_bytes());
+    key.extend_from_slice(emoji.as_bytes());
+    key
+}
+
+fn parse_reaction(key: &[u8], value: &[u8]) -> AnyResult<StoredReaction> {
+    if key.len() < 24 {
+        return Err(IoError::new(
+            IoErrorKind::InvalidData,
+            format!("DB | Invalid key in database: {key:?}").as_str(),
+        )
+        .into());
+    }
+    let message = MessageId(key_to_id(&key[8..16])?);
+    // Reactions recorded before their time was stored count from when the message was sent.
+    let (author, author_name, reacted_at) = serde_json::from_slice(value).or_else(|_| {
+        serde_json::from_slice(value)
+            .map(|(author, author_name)| (author, author_name, *message.created_at()))
+    })?;
+    Ok(StoredReaction {
+        message,
+        reactor: UserId(key_to_id(&key[16..24])?),
+        emoji: String::from_utf8(key[24..].to_vec())?,
+        author,
+        author_name,
+        reacted_at,
+    })
+}
+
+fn key_to_id(key: &[u8]) -> AnyResult<u64> {
+    let bytes = <[u8; 8]>::try_from(key).map_err(|_| {
+        IoError::new(
+            IoErrorKind::InvalidData,
+            format!("DB | Invalid key in database: {key:?}").as_str(),
+        )
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Storage, StoredReaction};
+    use crate::{
+        cases::Case,
+        misc::TestDir,
+        tasks::{gulag::Gulag, TaskType},
+    };
+    use chrono::{Duration, Utc};
+    use std::fs;
+
+    #[test]
+    fn test_import_tasks_file_once() {
+        let dir = TestDir::new("storage");
+        let tasks_file = dir.file("tasks.json");
+        let gulag = TaskType::Gulag(Gulag::new(
+            ("someone".into(), 1.into()),
+            Vec::new(),
+            Utc::now(),
+            None,
+        ));
+        fs::write(&tasks_file, serde_json::to_string(&vec![gulag]).unwrap()).unwrap();
+        let storage = Storage::temporary();
+        storage.import_tasks_file(&tasks_file).unwrap();
+        assert!(fs::metadata(&tasks_file).is_err());
+        // A second import must not duplicate anything even if the file shows up again.
+        fs::copy(format!("{tasks_file}.imported"), &tasks_file).unwrap();
+        storage.import_tasks_file(&tasks_file).unwrap();
+        let tasks = storage.load_tasks().unwrap();
+        assert_eq!(tasks.len(), 1);
+        let (&id, _) = tasks.iter().next().unwrap();
+        storage.remove_task(id).unwrap();
+        assert!(storage.load_tasks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cases_outlive_release() {
+        let storage = Storage::temporary();
+        let case = |user: u64| {
+            Case::new(
+                ("someone".into(), user.into()),
+                ("moderator".into(), 9.into()),
+                Some("Being rude".into()),
+                Utc::now(),
+            )
+        };
+        assert_eq!(storage.insert_case(&case(1)).unwrap(), 1);
+        assert_eq!(storage.insert_case(&case(2)).unwrap(), 2);
+        assert_eq!(storage.insert_case(&case(1)).unwrap(), 3);
+        storage
+            .update_case(1, |case| case.released_at = Some(Utc::now()))
+            .unwrap();
+        let history = storage.cases_for(1.into()).unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|(number, _)| *number)
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+        assert!(history[0].1.released_at.is_some());
+        assert!(storage.case(4).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_owed_roles_are_given_once() {
+        let storage = Storage::temporary();
+        let roles = vec![("regular".to_string(), 3.into())];
+        storage.owe_roles(1.into(), Some(2), &roles).unwrap();
+        assert!(storage.take_owed_roles(2.into()).unwrap().is_none());
+        assert_eq!(
+            storage.take_owed_roles(1.into()).unwrap(),
+            Some((Some(2), roles))
+        );
+        assert!(storage.take_owed_roles(1.into()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_take_activity() {
+        let storage = Storage::temporary();
+        storage.record_activity(1.into(), 2.into(), "old").unwrap();
+        storage.record_activity(1.into(), 2.into(), "old").unwrap();
+        let taken = storage.take_activity().unwrap();
+        assert_eq!(taken.channels, [(1.into(), 2)]);
+        assert!(storage.activity().unwrap().channels.is_empty());
+        // Counted while the report was being posted, which then failed.
+        storage.record_activity(1.into(), 2.into(), "new").unwrap();
+        storage.restore_activity(&taken).unwrap();
+        let counts = storage.activity().unwrap();
+        assert_eq!(counts.channels, [(1.into(), 3)]);
+        assert_eq!(counts.users, [("new".to_string(), 2.into(), 3)]);
+        assert_eq!(counts.since, taken.since);
+    }
+
+    #[test]
+    fn test_leaderboard_ids_are_not_reused() {
+        let storage = Storage::temporary();
+        // Leaderboards 1 to 3 were made before the counter existed.
+        assert_eq!(storage.next_leaderboard_id(3).unwrap(), 4);
+        // 4 was deleted, so the newest left is 3 again.
+        assert_eq!(storage.next_leaderboard_id(3).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_reactions_count_from_when_they_happened() {
+        let storage = Storage::temporary();
+        let now = Utc::now();
+        let reaction = |reactor: u64, reacted_at| StoredReaction {
+            // Sent in 2015.
+            message: 1.into(),
+            reactor: reactor.into(),
+            emoji: "⭐".into(),
+            author: 3.into(),
+            author_name: "someone".into(),
+            reacted_at,
+        };
+        storage
+            .insert_reaction(9.into(), &reaction(1, now))
+            .unwrap();
+        storage
+            .insert_reaction(9.into(), &reaction(2, now - Duration::days(10)))
+            .unwrap();
+        let since = now - Duration::days(7);
+        for channel in [Some(9.into()), None] {
+            let reactions = storage.reactions_since(channel, since).unwrap();
+            assert_eq!(reactions.len(), 1);
+            assert_eq!(reactions[0].reactor, 1);
+        }
+        assert!(storage
+            .reactions_since(Some(8.into()), since)
+            .unwrap()
+            .is_empty());
+    }
+}
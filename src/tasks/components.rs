@@ -0,0 +1,365 @@
+use crate::cache_keys::StorageKey;
+use anyhow::Result as AnyResult;
+use serde::{Deserialize, Serialize};
+use serenity::{
+    builder::{CreateActionRow, CreateComponents, CreateSelectMenuOption},
+    model::{
+        application::{
+            component::ButtonStyle,
+            interaction::{
+                message_component::MessageComponentInteraction, InteractionResponseType,
+            },
+        },
+        channel::ReactionType,
+    },
+    prelude::Context,
+};
+use std::{
+    collections::HashSet,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+};
+
+// Limits Discord puts on message components.
+const MAX_ROWS: usize = 5;
+const MAX_BUTTONS_PER_ROW: usize = 5;
+const MAX_SELECT_OPTIONS: usize = 25;
+const MAX_CUSTOM_ID_LEN: usize = 100;
+const MAX_LABEL_LEN: usize = 80;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum ButtonColour {
+    #[default]
+    Primary,
+    Secondary,
+    Success,
+    Danger,
+}
+
+impl From<ButtonColour> for ButtonStyle {
+    fn from(colour: ButtonColour) -> Self {
+        match colour {
+            ButtonColour::Primary => ButtonStyle::Primary,
+            ButtonColour::Secondary => ButtonStyle::Secondary,
+            ButtonColour::Success => ButtonStyle::Success,
+            ButtonColour::Danger => ButtonStyle::Danger,
+        }
+    }
+}
+
+/// Something to click on under a message. Buttons and select menu options can have a reply that
+/// only the person who clicked sees.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MessageComponent {
+    /// Opens a URL.
+    Link {
+        label: String,
+        url: String,
+        #[serde(default)]
+        emoji: Option<String>,
+    },
+    Button {
+        custom_id: String,
+        label: String,
+        #[serde(default)]
+        colour: ButtonColour,
+        #[serde(default)]
+        emoji: Option<String>,
+        #[serde(default)]
+        reply: Option<String>,
+    },
+    /// Has to be alone in its row.
+    Select {
+        custom_id: String,
+        #[serde(default)]
+        placeholder: Option<String>,
+        options: Vec<SelectOption>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SelectOption {
+    pub label: String,
+    pub value: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub reply: Option<String>,
+}
+
+/// Turns rows of components into what Discord expects.
+pub fn build_components(rows: &[Vec<MessageComponent>]) -> CreateComponents {
+    let mut components = CreateComponents::default();
+    for row in rows {
+        let mut action_row = CreateActionRow::default();
+        for component in row {
+            match component {
+                MessageComponent::Link { label, url, emoji } => {
+                    action_row.create_button(|button| {
+                        button.style(ButtonStyle::Link).label(label).url(url);
+                        if let Some(emoji) = parse_emoji(emoji) {
+                            button.emoji(emoji);
+                        }
+                        button
+                    });
+                }
+                MessageComponent::Button {
+                    custom_id,
+                    label,
+                    colour,
+                    emoji,
+                    ..
+                } => {
+                    action_row.create_button(|button| {
+                        button
+                            .style((*colour).into())
+                            .label(label)
+                            .custom_id(custom_id);
+                        if let Some(emoji) = parse_emoji(emoji) {
+                            button.emoji(emoji);
+                        }
+                        button
+                    });
+                }
+                MessageComponent::Select {
+                    custom_id,
+                    placeholder,
+                    options,
+                } => {
+                    action_row.create_select_menu(|menu| {
+                        menu.custom_id(custom_id);
+                        if let Some(placeholder) = placeholder {
+                            menu.placeholder(placeholder);
+                        }
+                        menu.options(|menu_options| {
+                            menu_options.set_options(
+                                options
+                                    .iter()
+                                    .map(|option| {
+                                        let mut menu_option = CreateSelectMenuOption::new(
+                                            &option.label,
+                                            &option.value,
+                                        );
+                                        if let Some(description) = &option.description {
+                                            menu_option.description(description);
+                                        }
+                                        menu_option
+                                    })
+                                    .collect(),
+                            )
+                        })
+                    });
+                }
+            }
+        }
+        components.add_action_row(action_row);
+    }
+    components
+}
+
+fn parse_emoji(emoji: &Option<String>) -> Option<ReactionType> {
+    emoji.as_deref().and_then(|emoji| emoji.parse().ok())
+}
+
+/// Whether any button or select menu option has a reply to answer with.
+pub fn has_replies(rows: &[Vec<MessageComponent>]) -> bool {
+    rows.iter().flatten().any(|component| match component {
+        MessageComponent::Button { reply, .. } => reply.is_some(),
+        MessageComponent::Select { options, .. } => {
+            options.iter().any(|option| option.reply.is_some())
+        }
+        MessageComponent::Link { .. } => false,
+    })
+}
+
+/// Checks the components fit within Discord's limits, so that sending the message won't be
+/// rejected.
+pub fn validate_components(rows: &[Vec<MessageComponent>]) -> AnyResult<()> {
+    let invalid = |why: String| -> AnyResult<()> {
+        Err(IoError::new(IoErrorKind::InvalidInput, why.as_str()).into())
+    };
+    if rows.len() > MAX_ROWS {
+        return invalid(format!(
+            "A message can have at most {MAX_ROWS} component rows."
+        ));
+    }
+    let mut custom_ids = HashSet::new();
+    for row in rows {
+        let has_select = row
+            .iter()
+            .any(|component| matches!(component, MessageComponent::Select { .. }));
+        if row.is_empty() {
+            return invalid("Component rows can't be empty.".into());
+        }
+        if has_select && row.len() > 1 {
+            return invalid("A select menu has to be alone in its row.".into());
+        }
+        if row.len() > MAX_BUTTONS_PER_ROW {
+            return invalid(format!(
+                "A row can have at most {MAX_BUTTONS_PER_ROW} buttons."
+            ));
+        }
+        for component in row {
+            let (custom_id, label) = match component {
+                MessageComponent::Link { label, url, .. } => {
+                    if !url.starts_with("https://") && !url.starts_with("http://") {
+                        return invalid(format!("'{url}' isn't a web link."));
+                    }
+                    (None, Some(label))
+                }
+                MessageComponent::Button {
+                    custom_id, label, ..
+                } => (Some(custom_id), Some(label)),
+                MessageComponent::Select {
+                    custom_id, options, ..
+                } => {
+                    if options.is_empty() || options.len() > MAX_SELECT_OPTIONS {
+                        return invalid(format!(
+                            "Select menu '{custom_id}' needs between 1 and {MAX_SELECT_OPTIONS} \
+                             options."
+                        ));
+                    }
+                    (Some(custom_id), None)
+                }
+            };
+            if let Some(label) = label {
+                if label.is_empty() || label.chars().count() > MAX_LABEL_LEN {
+                    return invalid(format!(
+                        "Label '{label}' has to be between 1 and {MAX_LABEL_LEN} characters."
+                    ));
+                }
+            }
+            if let Some(custom_id) = custom_id {
+                if custom_id.is_empty() || custom_id.chars().count() > MAX_CUSTOM_ID_LEN {
+                    return invalid(format!(
+                        "Custom ID '{custom_id}' has to be between 1 and {MAX_CUSTOM_ID_LEN} \
+                         characters."
+                    ));
+                }
+                if !custom_ids.insert(custom_id) {
+                    return invalid(format!("Custom ID '{custom_id}' is used more than once."));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The reply for a button or select menu option.
+fn find_reply(
+    rows: &[Vec<MessageComponent>],
+    custom_id: &str,
+    values: &[String],
+) -> Option<String> {
+    rows.iter().flatten().find_map(|component| match component {
+        MessageComponent::Button {
+            custom_id: id,
+            reply,
+            ..
+        } if id == custom_id => reply.clone(),
+        MessageComponent::Select {
+            custom_id: id,
+            options,
+            ..
+        } if id == custom_id => {
+            let replies = options
+                .iter()
+                .filter(|option| values.contains(&option.value))
+                .filter_map(|option| option.reply.clone())
+                .collect::<Vec<_>>();
+            (!replies.is_empty()).then(|| replies.join("\n"))
+        }
+        _ => None,
+    })
+}
+
+/// Answers someone clicking a button or picking from a select menu on a scheduled message.
+/// Components without a reply are just acknowledged, so Discord doesn't show them as failed.
+pub async fn handle_component(
+    ctx: &Context,
+    interaction: &MessageComponentInteraction,
+) -> AnyResult<()> {
+    let custom_id = &interaction.data.custom_id;
+    println!(
+        "CO | User {} used component '{custom_id}'.",
+        interaction.user.id
+    );
+    let storage = ctx.data.read().await.get::<StorageKey>().unwrap().clone();
+    let reply = storage
+        .components(interaction.message.id)?
+        .and_then(|rows| find_reply(&rows, custom_id, &interaction.data.values));
+    match reply {
+        Some(reply) => {
+            interaction
+                .create_interaction_response(&ctx.http, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|data| data.content(reply).ephemeral(true))
+                })
+                .await?;
+        }
+        None => {
+            interaction
+                .create_interaction_response(&ctx.http, |response| {
+                    response.kind(InteractionResponseType::DeferredUpdateMessage)
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        find_reply, has_replies, validate_components, ButtonColour, MessageComponent, SelectOption,
+    };
+
+    #[test]
+    fn test_components() {
+        let button = |custom_id: &str| MessageComponent::Button {
+            custom_id: custom_id.into(),
+            label: "Click".into(),
+            colour: ButtonColour::Primary,
+            emoji: None,
+            reply: Some(format!("Clicked {custom_id}")),
+        };
+        let select = MessageComponent::Select {
+            custom_id: "pick".into(),
+            placeholder: None,
+            options: vec![
+                SelectOption {
+                    label: "A".into(),
+                    value: "a".into(),
+                    description: None,
+                    reply: Some("Picked A".into()),
+                },
+                SelectOption {
+                    label: "B".into(),
+                    value: "b".into(),
+                    description: None,
+                    reply: None,
+                },
+            ],
+        };
+        let rows = vec![vec![button("one"), button("two")], vec![select.clone()]];
+        assert!(validate_components(&rows).is_ok());
+        assert!(has_replies(&rows));
+        let link = MessageComponent::Link {
+            label: "Read".into(),
+            url: "https://example.com".into(),
+            emoji: None,
+        };
+        assert!(!has_replies(&[vec![link]]));
+        assert_eq!(
+            find_reply(&rows, "two", &[]).as_deref(),
+            Some("Clicked two")
+        );
+        assert_eq!(
+            find_reply(&rows, "pick", &["a".into()]).as_deref(),
+            Some("Picked A")
+        );
+        assert_eq!(find_reply(&rows, "pick", &["b".into()]), None);
+        assert!(validate_components(&[vec![button("one"), button("one")]]).is_err());
+        assert!(validate_components(&[vec![button("one"), select]]).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use super::{misfire::MisfirePolicy, task::Task};
+use crate::{
+    cache_keys::ConfigKey,
+    misc::{fmt_local_and_utc, local_to_utc},
+};
+use anyhow::Result as AnyResult;
+use chrono::{prelude::*, Duration};
+use chrono_tz::Tz;
+use clap::{ColorChoice, Parser};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::client::Http,
+    prelude::{RwLock, TypeMap},
+};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    io::{Error as IoError, ErrorKind},
+    str::FromStr,
+    sync::Arc,
+};
+
+const MONTH_NAMES: &[&str] = &[
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+const WEEKDAY_NAMES: &[&str] = &["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// Some schedules only match rarely (the 29th of February on a Monday, say), so look this many days
+// ahead before deciding a schedule never matches. 28 years covers every weekday/leap year combo.
+const MAX_DAYS_AHEAD: usize = 28 * 366;
+
+fn invalid(msg: String) -> IoError {
+    IoError::new(ErrorKind::InvalidInput, msg.as_str())
+}
+
+/// A cron expression, with fields for (optionally) the second, then the minute, hour, day of the
+/// month, month, and day of the week. On top of the usual lists, ranges and steps, the day of the
+/// month can be `L` (last day) or `L-n` (n days before the last day), and the day of the week can be
+/// `nL` (last weekday n of the month) or `n#k` (k-th weekday n of the month).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    // Offsets back from the last day of the month, from `L` and `L-n`.
+    last_days_of_month: Vec<u32>,
+    months: u64,
+    // Bit 0 is Sunday.
+    weekdays: u64,
+    // (weekday, k) pairs from `n#k`.
+    nth_weekdays: Vec<(u32, u32)>,
+    // Weekdays from `nL`.
+    last_weekdays: u64,
+    dom_restricted: bool,
+    dow_restricted: bool,
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+fn parse_value(s: &str, min: u32, max: u32, names: &[&str]) -> AnyResult<u32> {
+    let upper = s.to_uppercase();
+    let value = match names.iter().position(|&name| name == upper) {
+        // Names are for fields that start at 0 (weekdays) or 1 (months).
+        Some(position) => position as u32 + min,
+        None => s
+            .parse::<u32>()
+            .map_err(|_| invalid(format!("'{s}' isn't a number or name")))?,
+    };
+    if value < min || value > max {
+        Err(invalid(format!("{value} is outside of {min}-{max}")).into())
+    } else {
+        Ok(value)
+    }
+}
+
+/// Parses one comma-separated item of a field (`*`, `a`, `a-b`, with an optional `/step`) into a
+/// bit set.
+fn parse_item(item: &str, min: u32, max: u32, names: &[&str]) -> AnyResult<u64> {
+    let (range, step) = match item.split_once('/') {
+        Some((range, step)) => {
+            let step = step
+                .parse::<u32>()
+                .map_err(|_| invalid(format!("Invalid step '{step}'")))?;
+            if step == 0 {
+                return Err(invalid("Steps can't be 0".into()).into());
+            }
+            (range, step)
+        }
+        None => (item, 1),
+    };
+    let (start, end) = if range == "*" || range == "?" {
+        (min, max)
+    } else if let Some((start, end)) = range.split_once('-') {
+        (
+            parse_value(start, min, max, names)?,
+            parse_value(end, min, max, names)?,
+        )
+    } else {
+        let start = parse_value(range, min, max, names)?;
+        // `a/n` means every n starting from a.
+        (start, if item.contains('/') { max } else { start })
+    };
+    if start > end {
+        return Err(invalid(format!("Range '{range}' goes backwards")).into());
+    }
+    Ok((start..=end)
+        .step_by(step as usize)
+        .fold(0, |set, n| set | (1 << n)))
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> AnyResult<u64> {
+    field
+        .split(',')
+        .map(|item| parse_item(item, min, max, names))
+        .try_fold(0, |set, item| Ok(set | item?))
+}
+
+fn is_unrestricted(field: &str) -> bool {
+    field == "*" || field == "?"
+}
+
+fn last_day_of_month(date: NaiveDate) -> u32 {
+    let (year, month) = if date.month() == 12 {
+        (date.year() + 1, 1)
+    } else {
+        (date.year(), date.month() + 1)
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .unwrap()
+        .pred_opt()
+        .unwrap()
+        .day()
+}
+
+impl CronSchedule {
+    fn day_of_month_matches(&self, date: NaiveDate) -> bool {
+        let last = last_day_of_month(date);
+        bit(self.days_of_month, date.day())
+            || self
+                .last_days_of_month
+                .iter()
+                .any(|&offset| last.checked_sub(offset) == Some(date.day()))
+    }
+
+    fn day_of_week_matches(&self, date: NaiveDate) -> bool {
+        let weekday = date.weekday().num_days_from_sunday();
+        bit(self.weekdays, weekday)
+            || self
+                .nth_weekdays
+                .iter()
+                .any(|&(wd, k)| wd == weekday && (date.day() - 1) / 7 + 1 == k)
+            || (bit(self.last_weekdays, weekday) && date.day() + 7 > last_day_of_month(date))
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        if !bit(self.months, date.month()) {
+            return false;
+        }
+        // Like regular cron, a day matches if either day field matches when both are restricted.
+        match (self.dom_restricted, self.dow_restricted) {
+            (true, true) => self.day_of_month_matches(date) || self.day_of_week_matches(date),
+            (true, false) => self.day_of_month_matches(date),
+            (false, true) => self.day_of_week_matches(date),
+            (false, false) => true,
+        }
+    }
+
+    fn first_time_from(&self, from: NaiveTime) -> Option<NaiveTime> {
+        for hour in from.hour()..24 {
+            if !bit(self.hours, hour) {
+                continue;
+            }
+            let minute_start = if hour == from.hour() {
+                from.minute()
+            } else {
+                0
+            };
+            for minute in minute_start..60 {
+                if !bit(self.minutes, minute) {
+                    continue;
+                }
+                let second_start = if hour == from.hour() && minute == from.minute() {
+                    from.second()
+                } else {
+                    0
+                };
+                if let Some(second) = (second_start..60).find(|&s| bit(self.seconds, s)) {
+                    return NaiveTime::from_hms_opt(hour, minute, second);
+                }
+            }
+        }
+        None
+    }
+
+    /// The first time strictly after `after` that matches the schedule, if there is one.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_nanosecond(0)? + Duration::seconds(1);
+        let mut date = start.date();
+        for _ in 0..MAX_DAYS_AHEAD {
+            if self.day_matches(date) {
+                let from = if date == start.date() {
+                    start.time()
+                } else {
+                    NaiveTime::MIN
+                };
+                if let Some(time) = self.first_time_from(from) {
+                    return Some(date.and_time(time));
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AnyResult<Self> {
+        let expanded = match s.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields = expanded.split_whitespace().collect::<Vec<_>>();
+        let (seconds, fields) = match fields.len() {
+            5 => (1, &fields[..]),
+            6 => (parse_field(fields[0], 0, 59, &[])?, &fields[1..]),
+            n => {
+                return Err(invalid(format!(
+                    "Expected 5 or 6 fields in the cron expression but got {n}"
+                ))
+                .into())
+            }
+        };
+        let (dom_field, dow_field) = (fields[2], fields[4]);
+        let mut days_of_month = 0;
+        let mut last_days_of_month = Vec::new();
+        if !is_unrestricted(dom_field) {
+            for item in dom_field.split(',') {
+                let upper = item.to_uppercase();
+                if upper == "L" {
+                    last_days_of_month.push(0);
+                } else if let Some(offset) = upper.strip_prefix("L-") {
+                    last_days_of_month.push(parse_value(offset, 0, 30, &[])?);
+                } else {
+                    days_of_month |= parse_item(item, 1, 31, &[])?;
+                }
+            }
+        }
+        let mut weekdays = 0;
+        let mut nth_weekdays = Vec::new();
+        let mut last_weekdays = 0;
+        if !is_unrestricted(dow_field) {
+            for item in dow_field.split(',') {
+                let upper = item.to_uppercase();
+                if let Some((weekday, k)) = upper.split_once('#') {
+                    let weekday = parse_value(weekday, 0, 7, WEEKDAY_NAMES)? % 7;
+                    nth_weekdays.push((weekday, parse_value(k, 1, 5, &[])?));
+                } else if let Some(weekday) = upper.strip_suffix('L') {
+                    last_weekdays |= 1 << (parse_value(weekday, 0, 7, WEEKDAY_NAMES)? % 7);
+                } else {
+                    weekdays |= parse_item(item, 0, 7, WEEKDAY_NAMES)?;
+                }
+            }
+            // Both 0 and 7 mean Sunday.
+            if bit(weekdays, 7) {
+                weekdays = (weekdays | 1) & !(1 << 7);
+            }
+        }
+        Ok(CronSchedule {
+            expression: s.trim().into(),
+            seconds,
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days_of_month,
+            last_days_of_month,
+            months: parse_field(fields[3], 1, 12, MONTH_NAMES)?,
+            weekdays,
+            nth_weekdays,
+            last_weekdays,
+            dom_restricted: !is_unrestricted(dom_field),
+            dow_restricted: !is_unrestricted(dow_field),
+        })
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> AnyResult<Self> {
+        s.parse()
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(schedule: CronSchedule) -> Self {
+        schedule.expression
+    }
+}
+
+impl Display for CronSchedule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CronTask {
+    pub task: Task,
+    pub schedule: CronSchedule,
+    pub next: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub time_zone: Option<Tz>,
+    #[serde(default)]
+    pub misfire_policy: MisfirePolicy,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+impl CronTask {
+    pub fn new(
+        task: Task,
+        schedule: CronSchedule,
+        time_zone: Option<Tz>,
+        default_tz: Tz,
+    ) -> AnyResult<Self> {
+        let mut cron_task = CronTask {
+            task,
+            schedule,
+            next: None,
+            time_zone,
+            misfire_policy: MisfirePolicy::default(),
+            paused: false,
+        };
+        cron_task.advance(default_tz);
+        if cron_task.next.is_none() {
+            Err(invalid(format!("'{}' never matches any date", cron_task.schedule)).into())
+        } else {
+            Ok(cron_task)
+        }
+    }
+
+    /// Works out the next time to act from the present time.
+    pub fn advance(&mut self, default_tz: Tz) {
+        self.next = self.next_after(Utc::now(), default_tz);
+    }
+
+    /// The schedule is matched against local time, so it follows the clocks when they change.
+    pub fn next_after(&self, after: DateTime<Utc>, default_tz: Tz) -> Option<DateTime<Utc>> {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        self.schedule
+            .next_after(after.with_timezone(&tz).naive_local())
+            .map(|next| local_to_utc(tz, next))
+    }
+
+    pub fn set_paused(&mut self, paused: bool, default_tz: Tz) {
+        if self.paused && !paused {
+            // Don't make up for the times that passed while paused.
+            self.advance(default_tz);
+        }
+        self.paused = paused;
+    }
+
+    pub async fn act(
+        &mut self,
+        data: &Arc<RwLock<TypeMap>>,
+        http: &impl AsRef<Http>,
+    ) -> AnyResult<()> {
+        let result = self.task.act(data, http).await;
+        // Move on regardless of how acting went, otherwise a failing task acts every second.
+        self.advance(data.read().await.get::<ConfigKey>().unwrap().time_zone);
+        result
+    }
+
+    pub fn list_fmt(&self, default_tz: Tz) -> String {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        format!(
+            "CRN | {} | {} ({tz}) | Next: {} | Misfire: {}{}",
+            self.task.list_fmt(),
+            self.schedule,
+            self.next
+                .map_or_else(|| "never".into(), |next| fmt_local_and_utc(next, tz)),
+            self.misfire_policy,
+            if self.paused { " | PAUSED" } else { "" },
+        )
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(name = "Cron Task", color(ColorChoice::Never), no_binary_name(true))]
+pub struct CreateCronTask {
+    #[arg(skip)]
+    pub task: Task,
+    /// Cron expression: [second] minute hour day-of-month month day-of-week
+    #[arg(short = 'c', long = "schedule", name = "schedule", num_args(1..=6), required(true))]
+    pub schedule: Vec<String>,
+    /// IANA time zone to match the schedule in instead of the configured one
+    #[arg(long = "tz", name = "time_zone")]
+    pub time_zone: Option<Tz>,
+    /// What to do about runs missed while offline: skip, once, all, or grace:<time> to run once
+    /// if the last one was missed by at most that long (e.g. grace:30m)
+    #[arg(long = "misfire", name = "misfire_policy", default_value = "once")]
+    pub misfire_policy: MisfirePolicy,
+}
+
+impl CreateCronTask {
+    pub fn create(self, default_tz: Tz) -> AnyResult<CronTask> {
+        let mut cron_task = CronTask::new(
+            self.task,
+            self.schedule.join(" ").parse()?,
+            self.time_zone,
+            default_tz,
+        )?;
+        cron_task.misfire_policy = self.misfire_policy;
+        Ok(cron_task)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CronSchedule;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<NaiveDateTime> {
+        expression
+            .parse::<CronSchedule>()
+            .unwrap()
+            .next_after(at(after))
+    }
+
+    #[test]
+    fn test_lists_ranges_and_steps() {
+        assert_eq!(
+            next("0 9 * * MON", "2024-01-01 09:00:00"),
+            Some(at("2024-01-08 09:00:00"))
+        );
+        assert_eq!(
+            next("*/15 8-10 * * *", "2024-01-01 10:46:00"),
+            Some(at("2024-01-02 08:00:00"))
+        );
+        assert_eq!(
+            next("30 0,12 1 JAN,JUL *", "2024-01-01 00:30:00"),
+            Some(at("2024-01-01 12:30:00"))
+        );
+        assert_eq!(
+            next("*/20 * * * * *", "2024-01-01 00:00:41"),
+            Some(at("2024-01-01 00:01:00"))
+        );
+    }
+
+    #[test]
+    fn test_last_and_nth() {
+        assert_eq!(
+            next("0 0 L * *", "2024-02-01 00:00:00"),
+            Some(at("2024-02-29 00:00:00"))
+        );
+        assert_eq!(
+            next("0 0 L-1 * *", "2023-02-01 00:00:00"),
+            Some(at("2023-02-27 00:00:00"))
+        );
+        // Second Tuesday and last Friday of the month.
+        assert_eq!(
+            next("0 12 * * 2#2", "2024-03-01 00:00:00"),
+            Some(at("2024-03-12 12:00:00"))
+        );
+        assert_eq!(
+            next("0 12 * * 5L", "2024-03-01 00:00:00"),
+            Some(at("2024-03-29 12:00:00"))
+        );
+    }
+
+    #[test]
+    fn test_day_fields_are_ored() {
+        // The 15th or any Sunday, whichever comes first.
+        assert_eq!(
+            next("0 0 15 * 0", "2024-03-01 00:00:00"),
+            Some(at("2024-03-03 00:00:00"))
+        );
+    }
+
+    #[test]
+    fn test_rare_and_impossible() {
+        assert_eq!(
+            next("0 0 29 2 1", "2024-03-01 00:00:00").map(|dt| dt.date()),
+            // The next Monday in February, not the next Monday the 29th of February.
+            NaiveDate::from_ymd_opt(2025, 2, 3)
+        );
+        assert_eq!(next("0 0 30 2 *", "2024-01-01 00:00:00"), None);
+        assert!("0 0 * *".parse::<CronSchedule>().is_err());
+        assert!("61 * * * *".parse::<CronSchedule>().is_err());
+        assert!("5-1 * * * *".parse::<CronSchedule>().is_err());
+    }
+}
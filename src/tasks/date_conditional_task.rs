@@ -1,6 +1,8 @@
-use super::task::Task;
+use super::{misfire::MisfirePolicy, task::Task};
+use crate::misc::local_to_utc;
 use anyhow::Result as AnyResult;
-use chrono::prelude::*;
+use chrono::{prelude::*, Duration};
+use chrono_tz::Tz;
 use clap::{ColorChoice, Parser};
 use serde::{Deserialize, Serialize};
 use serenity::{
@@ -8,15 +10,13 @@ use serenity::{
     prelude::{RwLock, TypeMap},
 };
 use std::{
-    cmp::PartialEq,
     fmt::Write,
     io::{Error as IoError, ErrorKind},
     sync::Arc,
 };
 
-fn time_eq_to_secs(t1: NaiveTime, t2: NaiveTime) -> bool {
-    t1.hour() == t2.hour() && t1.minute() == t2.minute() && t1.second() == t2.second()
-}
+// Conditions like the 29th of February only come around every few years.
+const MAX_DAYS_AHEAD: usize = 8 * 366;
 
 fn parse_weekday(s: &str) -> AnyResult<Weekday> {
     match s.to_lowercase().as_str() {
@@ -37,8 +37,10 @@ fn parse_weekday(s: &str) -> AnyResult<Weekday> {
 
 #[derive(Clone, Debug, Deserialize, Serialize, Parser)]
 pub struct DateCondition {
+    /// Time of day to act at
     #[arg(short = 't', long = "time", name = "time")]
     pub time: Option<NaiveTime>,
+    /// Day of the week to act on
     #[arg(
         short = 'w',
         long = "weekday",
@@ -47,6 +49,7 @@ pub struct DateCondition {
         value_parser = parse_weekday,
     )]
     pub weekday: Option<Weekday>,
+    /// Day of the month to act on
     #[arg(
         short = 'd',
         long = "day_of_month",
@@ -54,6 +57,7 @@ pub struct DateCondition {
         name = "day_of_month"
     )]
     pub day_of_month: Option<u32>,
+    /// Month of the year to act in
     #[arg(
         short = 'm',
         long = "month_of_year",
@@ -63,23 +67,48 @@ pub struct DateCondition {
     pub month_of_year: Option<u32>,
 }
 
-impl<'a> PartialEq<DateTime<Utc>> for &'a DateCondition {
-    fn eq(&self, other: &DateTime<Utc>) -> bool {
-        let time = self
-            .time
-            .map_or(true, |time| time_eq_to_secs(time, other.time()));
-        let wd = self.weekday.map_or(true, |wd| wd == other.weekday());
-        let dom = self.day_of_month.map_or(true, |dom| dom == other.day());
-        let moy = self.month_of_year.map_or(true, |moy| moy == other.month());
-        time && wd && dom && moy
+impl DateCondition {
+    fn date_matches(&self, date: NaiveDate) -> bool {
+        let wd = self.weekday.is_none_or(|wd| wd == date.weekday());
+        let dom = self.day_of_month.is_none_or(|dom| dom == date.day());
+        let moy = self.month_of_year.is_none_or(|moy| moy == date.month());
+        wd && dom && moy
     }
-}
 
-impl DateCondition {
-    pub fn list_fmt(&self) -> String {
+    /// The first local time strictly after `after` that meets the conditions, if there is one in
+    /// the next few years.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = after.with_nanosecond(0)? + Duration::seconds(1);
+        let mut date = start.date();
+        for _ in 0..MAX_DAYS_AHEAD {
+            if self.date_matches(date) {
+                match self.time {
+                    Some(time) => {
+                        let time = time.with_nanosecond(0)?;
+                        if date != start.date() || time >= start.time() {
+                            return Some(date.and_time(time));
+                        }
+                    }
+                    // Every second of the day matches.
+                    None if date == start.date() => return Some(start),
+                    None => return Some(date.and_time(NaiveTime::MIN)),
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    pub fn list_fmt(&self, tz: Tz) -> String {
         let mut out = String::new();
         if let Some(time) = self.time {
             write!(out, "T {time} ").unwrap();
+            if tz != Tz::UTC {
+                // The UTC time changes with DST, so show what it is today.
+                let today = Utc::now().with_timezone(&tz).date_naive();
+                let utc = local_to_utc(tz, today.and_time(time)).time();
+                write!(out, "({utc} UTC) ").unwrap();
+            }
         }
         if let Some(wd) = self.weekday {
             if !out.is_empty() {
@@ -114,22 +143,59 @@ pub struct DateConditionalTask {
     pub task: Task,
     #[command(flatten)]
     pub condition: DateCondition,
+    /// IANA time zone to check the conditions in instead of the configured one
+    #[arg(long = "tz", name = "time_zone")]
+    #[serde(default)]
+    pub time_zone: Option<Tz>,
+    /// What to do about runs missed while offline: skip, once, all, or grace:<time> to run once
+    /// if the last one was missed by at most that long (e.g. grace:30m)
+    #[arg(long = "misfire", name = "misfire_policy", default_value = "once")]
+    #[serde(default)]
+    pub misfire_policy: MisfirePolicy,
+    #[arg(skip)]
+    #[serde(default)]
+    pub paused: bool,
+    #[arg(skip)]
+    #[serde(default)]
+    pub last_acted: Option<DateTime<Utc>>,
 }
 
 impl DateConditionalTask {
-    pub fn time_to_act(&self) -> bool {
-        &self.condition == Utc::now()
+    /// The next time the conditions are met, counting the current second if the task hasn't
+    /// acted in it yet. Times come after the last one acted at in local time, so that when the
+    /// clocks go back and the same local time comes around twice, it only counts once.
+    pub fn next_fire(&self, default_tz: Tz) -> Option<DateTime<Utc>> {
+        let just_before_now = Utc::now() - Duration::seconds(1);
+        let after = self
+            .last_acted
+            .map_or(just_before_now, |last| last.max(just_before_now));
+        self.next_after(after, default_tz)
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>, default_tz: Tz) -> Option<DateTime<Utc>> {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        self.condition
+            .next_after(after.with_timezone(&tz).naive_local())
+            .map(|next| local_to_utc(tz, next))
     }
 
-    pub async fn act(&self, data: &Arc<RwLock<TypeMap>>, http: &impl AsRef<Http>) -> AnyResult<()> {
+    pub async fn act(
+        &mut self,
+        data: &Arc<RwLock<TypeMap>>,
+        http: &impl AsRef<Http>,
+    ) -> AnyResult<()> {
+        self.last_acted = Some(Utc::now());
         self.task.act(data, http).await
     }
 
-    pub fn list_fmt(&self) -> String {
+    pub fn list_fmt(&self, default_tz: Tz) -> String {
+        let tz = self.time_zone.unwrap_or(default_tz);
         format!(
-            "DCT | {} | {}",
+            "DCT | {} | {} | {tz} | Misfire: {}{}",
             self.task.list_fmt(),
-            self.condition.list_fmt(),
+            self.condition.list_fmt(tz).trim_end(),
+            self.misfire_policy,
+            if self.paused { " | PAUSED" } else { "" },
         )
     }
 }
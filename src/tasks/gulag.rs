@@ -1,11 +1,17 @@
-use crate::{cache_keys::ConfigKey, config::Config};
+use crate::{
+    cache_keys::{ConfigKey, StorageKey},
+    config::Config,
+    escalation::is_permanent,
+    mod_log::{ModLogEntry, ModLogKind},
+};
 use anyhow::Result as AnyResult;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use serenity::{
-    http::client::Http,
+    http::{client::Http, HttpError},
     model::id::{RoleId, UserId},
     prelude::{RwLock, TypeMap},
+    Error as SerenityError,
 };
 use std::{
     fmt::{self, Debug, Display, Result as FmtResult},
@@ -13,20 +19,33 @@ use std::{
     time::Instant,
 };
 
+// Discord's error code for a user that isn't in the server.
+const UNKNOWN_MEMBER: isize = 10007;
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Gulag {
     pub user: (String, UserId),
     pub roles: Vec<(String, RoleId)>,
     pub end: DateTime<Utc>,
+    /// Number of the case on record for this sentence. Sentences from before cases were kept
+    /// don't have one.
+    #[serde(default)]
+    pub case: Option<u64>,
 }
 
 impl Gulag {
-    pub fn new(user: (String, UserId), roles: Vec<(String, RoleId)>, end: DateTime<Utc>) -> Self {
-        Gulag { user, roles, end }
-    }
-
-    pub fn time_to_act(&self) -> bool {
-        self.end <= Utc::now()
+    pub fn new(
+        user: (String, UserId),
+        roles: Vec<(String, RoleId)>,
+        end: DateTime<Utc>,
+        case: Option<u64>,
+    ) -> Self {
+        Gulag {
+            user,
+            roles,
+            end,
+            case,
+        }
     }
 
     pub async fn act(&self, data: &Arc<RwLock<TypeMap>>, http: &impl AsRef<Http>) -> AnyResult<()> {
@@ -37,29 +56,80 @@ impl Gulag {
         let Config {
             guild_id,
             prisoner_role_id,
+            mod_log_channel,
             ..
         } = context_data.get::<ConfigKey>().unwrap();
         let guild_id = *guild_id.as_u64();
         let gulag_id = *prisoner_role_id.as_u64();
+        let mod_log_channel = *mod_log_channel;
+        let storage = context_data.get::<StorageKey>().unwrap().clone();
+        drop(context_data);
         println!(
             "TL | GL | Got guild ID {} and prisoner role ID {}",
             guild_id, gulag_id
         );
-        println!("TL | GL | Getting member information.");
-        let mut member = http
-            .as_ref()
-            .get_member(guild_id, self.user.1.into())
-            .await?;
-        println!("TL | GL | Removing prisoner role.");
-        member.remove_role(http, gulag_id).await?;
-        println!("TL | GL | Getting list of role IDs to add back to user.");
-        let role_ids = self
-            .roles
-            .iter()
-            .map(|&(_, role_id)| role_id)
-            .collect::<Vec<_>>();
-        println!("TL | GL | Adding roles back to user.");
-        member.add_roles(http.as_ref(), &role_ids).await?;
+        // A moderator who ended the sentence early is on the case.
+        let released_by = match self.case {
+            Some(case) => storage.case(case)?.and_then(|case| case.released_by),
+            None => None,
+        };
+        let kind = if released_by.is_some() {
+            ModLogKind::ReleasedEarly
+        } else {
+            ModLogKind::Released
+        };
+        let mut entry = ModLogEntry::new(kind, self.user.clone());
+        entry.moderator = released_by;
+        entry.case = self.case;
+        entry.roles = self.roles.clone();
+        let released = async {
+            println!("TL | GL | Getting member information.");
+            let mut member = match http.as_ref().get_member(guild_id, self.user.1.into()).await {
+                Ok(member) => member,
+                // They left, and the prisoner role went with them. The sentence is over all the
+                // same, and their saved roles are kept for if they come back.
+                Err(err) if is_unknown_member(&err) => {
+                    println!("TL | GL | User isn't in the server any more. Keeping their roles.");
+                    if !self.roles.is_empty() {
+                        storage.owe_roles(self.user.1, self.case, &self.roles)?;
+                    }
+                    return Ok(false);
+                }
+                Err(err) => return Err(err.into()),
+            };
+            println!("TL | GL | Removing prisoner role.");
+            member.remove_role(http, gulag_id).await?;
+            println!("TL | GL | Getting list of role IDs to add back to user.");
+            let role_ids = self
+                .roles
+                .iter()
+                .map(|&(_, role_id)| role_id)
+                .collect::<Vec<_>>();
+            println!("TL | GL | Adding roles back to user.");
+            member.add_roles(http.as_ref(), &role_ids).await?;
+            AnyResult::<bool>::Ok(true)
+        }
+        .await;
+        match released {
+            Ok(true) => {}
+            Ok(false) => {
+                entry.roles.clear();
+                entry.failure = Some(
+                    "Not in the server, so their roles will be restored when they come back."
+                        .into(),
+                );
+            }
+            Err(err) => {
+                entry.failure = Some(err.to_string());
+                entry.post(http.as_ref(), mod_log_channel).await;
+                return Err(err);
+            }
+        }
+        if let Some(case) = self.case {
+            println!("TL | GL | Recording release in case {case}.");
+            storage.update_case(case, |case| case.released_at = Some(Utc::now()))?;
+        }
+        entry.post(http.as_ref(), mod_log_channel).await;
         println!(
             "TL | GL | Successfully un-gulagged user in {:?}.",
             start.elapsed()
@@ -68,13 +138,32 @@ impl Gulag {
     }
 
     pub fn list_fmt(&self) -> String {
+        let case = self
+            .case
+            .map_or_else(String::new, |case| format!(" (case {case})"));
+        let end = if is_permanent(self.end) {
+            "permanently".into()
+        } else {
+            format!("until {}", self.end)
+        };
         format!(
-            "  G | User \"{}\" ({}) until {}",
-            self.user.0, self.user.1, self.end
+            "  G | User \"{}\" ({}) {end}{case}",
+            self.user.0, self.user.1
         )
     }
 }
 
+/// Whether a request failed because the user isn't a member of the server.
+fn is_unknown_member(err: &SerenityError) -> bool {
+    match err {
+        SerenityError::Http(http_err) => matches!(
+            http_err.as_ref(),
+            HttpError::UnsuccessfulRequest(response) if response.error.code == UNKNOWN_MEMBER
+        ),
+        _ => false,
+    }
+}
+
 impl Debug for Gulag {
     fn fmt(&self, f: &mut fmt::Formatter) -> FmtResult {
         writeln!(
@@ -87,18 +176,28 @@ impl Debug for Gulag {
             writeln!(f, "        - '{role_name}' (ID: {role_id})")?;
         }
         writeln!(f, "    End of sentence: {}", self.end)?;
+        if let Some(case) = self.case {
+            writeln!(f, "    Case: {case}")?;
+        }
         Ok(())
     }
 }
 
 impl Display for Gulag {
     fn fmt(&self, f: &mut fmt::Formatter) -> FmtResult {
-        writeln!(
-            f,
-            "{} (ID: {}), release at <t:{end}> (<t:{end}:R>)",
-            self.user.0,
-            self.user.1,
-            end = self.end.timestamp(),
-        )
+        write!(f, "{} (ID: {}), ", self.user.0, self.user.1)?;
+        if is_permanent(self.end) {
+            write!(f, "held permanently")?;
+        } else {
+            write!(
+                f,
+                "release at <t:{end}> (<t:{end}:R>)",
+                end = self.end.timestamp()
+            )?;
+        }
+        match self.case {
+            Some(case) => writeln!(f, ", case {case}"),
+            None => writeln!(f),
+        }
     }
 }
@@ -1,6 +1,23 @@
+use super::{
+    components::{build_components, validate_components, MessageComponent},
+    template::{self, TemplateContext},
+};
+use anyhow::Result as AnyResult;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use serenity::{builder::CreateMessage, utils::Colour};
+use serenity::{
+    builder::{CreateEmbed, CreateMessage, ParseValue},
+    model::id::{RoleId, UserId},
+    utils::Colour,
+};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::Path,
+};
+
+// Limits Discord puts on a single message.
+const MAX_EMBEDS: usize = 10;
+const MAX_ATTACHMENTS: usize = 10;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EmbedMessage {
@@ -18,61 +35,238 @@ pub struct EmbedMessage {
     pub(crate) title_url: Option<String>,
 }
 
+impl EmbedMessage {
+    fn templates(&self) -> Vec<&str> {
+        let mut templates = [
+            &self.author_name,
+            &self.description,
+            &self.footer_text,
+            &self.title,
+        ]
+        .iter()
+        .filter_map(|text| text.as_deref())
+        .collect::<Vec<_>>();
+        for (name, value, _) in self.fields.iter().flatten() {
+            templates.push(name);
+            templates.push(value);
+        }
+        templates
+    }
+
+    fn build(&self, context: &TemplateContext, footer_icon_url: Option<&str>) -> CreateEmbed {
+        let render = |text: &String| template::render(text, context);
+        let EmbedMessage {
+            author_icon_url,
+            author_name,
+            author_url,
+            colour: (r, g, b),
+            description,
+            fields,
+            footer_text,
+            image_url,
+            thumbnail_url,
+            timestamp,
+            title,
+            title_url,
+        } = self;
+        let mut e = CreateEmbed::default();
+        if author_icon_url.is_some() || author_name.is_some() || author_url.is_some() {
+            e.author(|auth| {
+                author_icon_url.as_ref().map(|url| auth.icon_url(url));
+                author_name.as_ref().map(|name| auth.name(render(name)));
+                author_url.as_ref().map(|url| auth.url(url));
+                auth
+            });
+        }
+        e.colour(Colour::from_rgb(*r, *g, *b));
+        description.as_ref().map(|desc| e.description(render(desc)));
+        fields.as_ref().map(|vec| {
+            e.fields(
+                vec.iter()
+                    .map(|(name, value, inline)| (render(name), render(value), *inline)),
+            )
+        });
+        if footer_text.is_some() || footer_icon_url.is_some() {
+            e.footer(|f| {
+                footer_text.as_ref().map(|text| f.text(render(text)));
+                footer_icon_url.map(|url| f.icon_url(url));
+                f
+            });
+        }
+        image_url.as_ref().map(|url| e.image(url));
+        thumbnail_url.as_ref().map(|url| e.thumbnail(url));
+        if *timestamp {
+            e.timestamp(Utc::now().to_rfc3339());
+        }
+        title.as_ref().map(|title| e.title(render(title)));
+        title_url.as_ref().map(|url| e.url(url));
+        e
+    }
+}
+
+/// Who a message is allowed to ping. Without one, everything mentioned gets pinged.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MentionPolicy {
+    #[serde(default)]
+    pub(crate) everyone: bool,
+    /// Ping every user mentioned. Otherwise only the ones in `users` are.
+    #[serde(default)]
+    pub(crate) all_users: bool,
+    /// Ping every role mentioned. Otherwise only the ones in `roles` are.
+    #[serde(default)]
+    pub(crate) all_roles: bool,
+    #[serde(default)]
+    pub(crate) users: Vec<UserId>,
+    #[serde(default)]
+    pub(crate) roles: Vec<RoleId>,
+}
+
+/// Text with any number of embeds, files and components, like admins can post by hand.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RichMessage {
+    #[serde(default)]
+    pub(crate) content: Option<String>,
+    #[serde(default)]
+    pub(crate) embeds: Vec<EmbedMessage>,
+    /// Names of files in the files directory to attach.
+    #[serde(default)]
+    pub(crate) attachments: Vec<String>,
+    /// Rows of buttons and select menus.
+    #[serde(default)]
+    pub(crate) components: Vec<Vec<MessageComponent>>,
+    #[serde(default)]
+    pub(crate) allowed_mentions: Option<MentionPolicy>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum MessageType {
     Plain { content: String },
     Embed(Box<EmbedMessage>),
+    Rich(Box<RichMessage>),
 }
 
 impl MessageType {
-    pub fn is_embed(&self) -> bool {
-        matches!(self, MessageType::Embed { .. })
+    /// The text in the message that can have placeholders.
+    pub fn templates(&self) -> Vec<&str> {
+        match self {
+            MessageType::Plain { content } => vec![content.as_str()],
+            MessageType::Embed(msg) => msg.templates(),
+            MessageType::Rich(msg) => msg
+                .content
+                .iter()
+                .map(String::as_str)
+                .chain(msg.embeds.iter().flat_map(EmbedMessage::templates))
+                .collect(),
+        }
     }
 
-    pub fn build(&self) -> CreateMessage {
+    /// Files from the files directory to attach.
+    pub fn attachments(&self) -> &[String] {
+        match self {
+            MessageType::Rich(msg) => &msg.attachments,
+            _ => &[],
+        }
+    }
+
+    pub fn validate(&self) -> AnyResult<()> {
+        self.templates()
+            .into_iter()
+            .try_for_each(template::validate)?;
+        if let MessageType::Rich(msg) = self {
+            let invalid = |why: String| IoError::new(IoErrorKind::InvalidInput, why.as_str());
+            if msg.content.is_none() && msg.embeds.is_empty() && msg.attachments.is_empty() {
+                return Err(invalid("The message has nothing in it.".into()).into());
+            }
+            if msg.embeds.len() > MAX_EMBEDS {
+                return Err(
+                    invalid(format!("A message can have at most {MAX_EMBEDS} embeds.")).into(),
+                );
+            }
+            if msg.attachments.len() > MAX_ATTACHMENTS {
+                return Err(invalid(format!(
+                    "A message can have at most {MAX_ATTACHMENTS} attachments."
+                ))
+                .into());
+            }
+            if let Some(attachment) = msg
+                .attachments
+                .iter()
+                .find(|attachment| Path::new(attachment).components().count() != 1)
+            {
+                return Err(invalid(format!(
+                    "'{attachment}' has to be the name of a file in the files directory."
+                ))
+                .into());
+            }
+            validate_components(&msg.components)?;
+            if let Some(mentions) = &msg.allowed_mentions {
+                if (mentions.all_users && !mentions.users.is_empty())
+                    || (mentions.all_roles && !mentions.roles.is_empty())
+                {
+                    return Err(invalid(
+                        "Allowed mentions can't list users or roles while allowing all of them."
+                            .into(),
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn uses_member_count(&self) -> bool {
+        self.templates()
+            .into_iter()
+            .any(template::uses_member_count)
+    }
+
+    /// Builds the message with its placeholders filled in. Embeds get the icon in their footer.
+    pub fn build<'a>(
+        &self,
+        context: &TemplateContext,
+        footer_icon_url: Option<&str>,
+    ) -> CreateMessage<'a> {
+        let render = |text: &String| template::render(text, context);
         let mut message = CreateMessage::default();
         match self {
             MessageType::Plain { content } => {
-                message.content(content);
+                message.content(render(content));
             }
             MessageType::Embed(msg) => {
-                let EmbedMessage {
-                    author_icon_url,
-                    author_name,
-                    author_url,
-                    colour: (r, g, b),
-                    description,
-                    fields,
-                    footer_text,
-                    image_url,
-                    thumbnail_url,
-                    timestamp,
-                    title,
-                    title_url,
-                } = msg.as_ref();
-                message.embed(|e| {
-                    author_icon_url
-                        .as_ref()
-                        .map(|url| e.author(|auth| auth.icon_url(url)));
-                    author_name
-                        .as_ref()
-                        .map(|name| e.author(|auth| auth.name(name)));
-                    author_url
-                        .as_ref()
-                        .map(|url| e.author(|auth| auth.url(url)));
-                    e.colour(Colour::from_rgb(*r, *g, *b));
-                    description.as_ref().map(|desc| e.description(desc));
-                    fields.as_ref().map(|vec| e.fields(vec.clone()));
-                    footer_text.as_ref().map(|text| e.footer(|f| f.text(text)));
-                    image_url.as_ref().map(|url| e.image(url));
-                    thumbnail_url.as_ref().map(|url| e.thumbnail(url));
-                    if *timestamp {
-                        e.timestamp(Utc::now().to_string());
-                    }
-                    title.as_ref().map(|title| e.title(title));
-                    title_url.as_ref().map(|url| e.url(url));
-                    e
-                });
+                message.set_embed(msg.build(context, footer_icon_url));
+            }
+            MessageType::Rich(msg) => {
+                if let Some(content) = &msg.content {
+                    message.content(render(content));
+                }
+                message.set_embeds(
+                    msg.embeds
+                        .iter()
+                        .map(|embed| embed.build(context, footer_icon_url))
+                        .collect(),
+                );
+                if !msg.components.is_empty() {
+                    message.set_components(build_components(&msg.components));
+                }
+                if let Some(mentions) = &msg.allowed_mentions {
+                    message.allowed_mentions(|allowed| {
+                        allowed.empty_parse();
+                        if mentions.everyone {
+                            allowed.parse(ParseValue::Everyone);
+                        }
+                        if mentions.all_users {
+                            allowed.parse(ParseValue::Users);
+                        } else {
+                            allowed.users(mentions.users.iter().copied());
+                        }
+                        if mentions.all_roles {
+                            allowed.parse(ParseValue::Roles);
+                        } else {
+                            allowed.roles(mentions.roles.iter().copied());
+                        }
+                        allowed
+                    });
+                }
             }
         }
         message
@@ -0,0 +1,204 @@
+use crate::{
+    cache_keys::{ConfigKey, TasksKey},
+    storage::Storage,
+    tasks::TaskId,
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serenity::prelude::{RwLock, TypeMap};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io::{Error as IoError, ErrorKind},
+    str::FromStr,
+    sync::Arc,
+};
+
+// Running every missed occurrence of a task that fires every few seconds after a long outage would
+// flood whichever channel it sends to, so catching up stops after this many runs.
+pub const MAX_CATCH_UP_RUNS: usize = 24;
+
+const UNITS: &[(char, i64)] = &[('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// What a task does about the times it should have acted while the bot was offline.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MisfirePolicy {
+    /// Forget about them.
+    Skip,
+    /// Act once, however many times were missed.
+    #[default]
+    FireOnce,
+    /// Act once for every missed time, up to `MAX_CATCH_UP_RUNS`.
+    FireAll,
+    /// Act once, but only if the latest missed time was at most this many seconds ago.
+    Grace(i64),
+}
+
+impl MisfirePolicy {
+    /// How many times to act given the missed times, oldest first.
+    pub fn runs(&self, missed: &[DateTime<Utc>], now: DateTime<Utc>) -> usize {
+        match (self, missed.last()) {
+            (_, None) | (MisfirePolicy::Skip, _) => 0,
+            (MisfirePolicy::FireOnce, _) => 1,
+            (MisfirePolicy::FireAll, _) => missed.len().min(MAX_CATCH_UP_RUNS),
+            (MisfirePolicy::Grace(secs), Some(&latest)) => Duration::try_seconds(*secs)
+                .map_or(0, |window| {
+                    usize::from(now.signed_duration_since(latest) <= window)
+                }),
+        }
+    }
+}
+
+impl FromStr for MisfirePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AnyResult<Self> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(MisfirePolicy::Skip),
+            "once" => Ok(MisfirePolicy::FireOnce),
+            "all" => Ok(MisfirePolicy::FireAll),
+            other => {
+                let window = other.strip_prefix("grace:").ok_or_else(|| {
+                    IoError::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid misfire policy '{s}'. Expected skip, once, all or \
+                            grace:<time> (e.g. grace:30m)"
+                        )
+                        .as_str(),
+                    )
+                })?;
+                let (number, multiplier) =
+                    match UNITS.iter().find(|(unit, _)| window.ends_with(*unit)) {
+                        Some(&(_, multiplier)) => (&window[..window.len() - 1], multiplier),
+                        None => (window, 1),
+                    };
+                let invalid = || {
+                    IoError::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid grace window '{window}'").as_str(),
+                    )
+                };
+                let secs = number
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|number| number.checked_mul(multiplier))
+                    // `runs` turns the window into a `Duration`, whose range is smaller than i64.
+                    .filter(|&secs| secs >= 0 && Duration::try_seconds(secs).is_some())
+                    .ok_or_else(invalid)?;
+                Ok(MisfirePolicy::Grace(secs))
+            }
+        }
+    }
+}
+
+impl Display for MisfirePolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MisfirePolicy::Skip => f.write_str("skip"),
+            MisfirePolicy::FireOnce => f.write_str("once"),
+            MisfirePolicy::FireAll => f.write_str("all"),
+            MisfirePolicy::Grace(secs) => {
+                let &(unit, multiplier) = UNITS
+                    .iter()
+                    .find(|(_, multiplier)| secs % multiplier == 0)
+                    .unwrap();
+                write!(f, "grace:{}{unit}", secs / multiplier)
+            }
+        }
+    }
+}
+
+/// Works out how many times each task should act for the times it missed between the last time
+/// the task list was checked and now, according to its misfire policy. Has to happen before the
+/// task list is checked as normal, since that would act once for any task that's overdue. Tasks
+/// that shouldn't act at all are moved past the missed times here. The runs themselves are left to
+/// the scheduler, so that they get the same timeout and retries as any other run.
+pub async fn plan_catch_up(
+    data: &Arc<RwLock<TypeMap>>,
+    storage: &Storage,
+) -> HashMap<TaskId, usize> {
+    let mut catch_up = HashMap::new();
+    let since = match storage.last_evaluated() {
+        Ok(Some(since)) => since,
+        Ok(None) => {
+            println!("MF | Task list has never been checked before. Nothing was missed.");
+            return catch_up;
+        }
+        Err(e) => {
+            println!("MF | error: {e}");
+            return catch_up;
+        }
+    };
+    let now = Utc::now();
+    println!("MF | Checking for runs missed since {since}.");
+    let mut context_data = data.write().await;
+    let default_tz = context_data.get::<ConfigKey>().unwrap().time_zone;
+    for (&id, task) in context_data.get_mut::<TasksKey>().unwrap().iter_mut() {
+        let policy = match task.misfire_policy() {
+            Some(policy) if !task.is_paused() => policy,
+            _ => continue,
+        };
+        let missed = task.missed_between(since, now, default_tz);
+        if missed.is_empty() {
+            continue;
+        }
+        let runs = policy.runs(&missed, now);
+        println!(
+            "MF | Task {id} missed {}{} runs. Policy is '{policy}', so acting {runs} times.",
+            missed.len(),
+            if missed.len() > MAX_CATCH_UP_RUNS {
+                "+"
+            } else {
+                ""
+            },
+        );
+        if runs > 0 {
+            let _ = catch_up.insert(id, runs);
+            continue;
+        }
+        if let Err(e) = task
+            .skip_missed(default_tz)
+            .and_then(|()| storage.update_task(id, task))
+        {
+            println!("MF | error: {e}");
+        }
+    }
+    catch_up
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MisfirePolicy, MAX_CATCH_UP_RUNS};
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_misfire_policy_parse_and_runs() {
+        for s in ["skip", "once", "all", "grace:30m", "grace:1d", "grace:45s"] {
+            assert_eq!(s.parse::<MisfirePolicy>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "grace:90".parse::<MisfirePolicy>().unwrap(),
+            MisfirePolicy::Grace(90)
+        );
+        assert!("sometimes".parse::<MisfirePolicy>().is_err());
+        assert!("grace:-5m".parse::<MisfirePolicy>().is_err());
+        assert!("grace:10000000000000000s".parse::<MisfirePolicy>().is_err());
+        assert!("grace:9223372036854775807d"
+            .parse::<MisfirePolicy>()
+            .is_err());
+        let now = Utc::now();
+        let missed = (1..=30)
+            .rev()
+            .map(|hours| now - Duration::hours(hours))
+            .collect::<Vec<_>>();
+        assert_eq!(MisfirePolicy::Skip.runs(&missed, now), 0);
+        assert_eq!(MisfirePolicy::FireOnce.runs(&missed, now), 1);
+        assert_eq!(MisfirePolicy::FireAll.runs(&missed, now), MAX_CATCH_UP_RUNS);
+        assert_eq!(MisfirePolicy::Grace(3600).runs(&missed, now), 1);
+        assert_eq!(MisfirePolicy::Grace(60).runs(&missed, now), 0);
+        assert_eq!(MisfirePolicy::FireOnce.runs(&[], now), 0);
+    }
+}
@@ -1,19 +1,30 @@
+pub mod components;
+pub mod cron_task;
 pub mod date_conditional_task;
 pub mod gulag;
 pub mod message;
+pub mod misfire;
 pub mod periodic_task;
+pub mod pool;
 pub mod task;
+pub mod template;
 
 use crate::{
-    cache_keys::TaskSenderKey,
+    cache_keys::{ConfigKey, TaskSenderKey},
     help::CREATE_TASK_HELP_MSG,
+    invocation::{find_string_option, Invocation},
     misc::{insufficient_perms, is_administrator, ClapResult},
+    scheduler::TaskMessage,
 };
 use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use clap::{error::ErrorKind, ColorChoice, Parser, Subcommand};
+use cron_task::{CreateCronTask, CronTask};
 use date_conditional_task::DateConditionalTask;
 use gulag::Gulag;
 use lazy_static::lazy_static;
+use misfire::{MisfirePolicy, MAX_CATCH_UP_RUNS};
 use periodic_task::{CreatePeriodicTask, PeriodicTask};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -24,22 +35,36 @@ use serenity::{
     model::channel::Message,
     prelude::{RwLock, TypeMap},
 };
-use std::{ffi::OsString, sync::Arc, time::Instant};
+use std::{
+    ffi::OsString,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    sync::Arc,
+    time::Instant,
+};
 use task::Task;
 
+pub type TaskId = u64;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum TaskType {
+    CronTask(CronTask),
     DateConditionalTask(DateConditionalTask),
     Gulag(Gulag),
     PeriodicTask(PeriodicTask),
 }
 
 impl TaskType {
-    pub fn time_to_act(&self) -> bool {
+    /// When the task should next act, or `None` if it shouldn't. `default_tz` is the configured
+    /// time zone, for tasks that don't have their own.
+    pub fn next_fire(&self, default_tz: Tz) -> Option<DateTime<Utc>> {
+        if self.is_paused() {
+            return None;
+        }
         match self {
-            TaskType::DateConditionalTask(task) => task.time_to_act(),
-            TaskType::Gulag(task) => task.time_to_act(),
-            TaskType::PeriodicTask(task) => task.time_to_act(),
+            TaskType::CronTask(ct) => ct.next,
+            TaskType::DateConditionalTask(dct) => dct.next_fire(default_tz),
+            TaskType::Gulag(gulag) => Some(gulag.end),
+            TaskType::PeriodicTask(pt) => pt.next_time(default_tz),
         }
     }
 
@@ -53,6 +78,7 @@ impl TaskType {
         http: &impl AsRef<Http>,
     ) -> AnyResult<()> {
         match self {
+            TaskType::CronTask(task) => task.act(data, http).await,
             TaskType::DateConditionalTask(task) => task.act(data, http).await,
             TaskType::Gulag(task) => task.act(data, http.as_ref()).await,
             TaskType::PeriodicTask(task) => task.act(data, http).await,
@@ -73,18 +99,163 @@ impl TaskType {
         }
     }
 
-    pub fn list_fmt(&self) -> String {
+    /// What the task does when it acts. Gulags don't have one.
+    pub fn task_ref(&self) -> Option<&Task> {
+        match self {
+            TaskType::CronTask(CronTask { task, .. })
+            | TaskType::DateConditionalTask(DateConditionalTask { task, .. })
+            | TaskType::PeriodicTask(PeriodicTask { task, .. }) => Some(task),
+            TaskType::Gulag(_) => None,
+        }
+    }
+
+    pub fn task_mut(&mut self) -> Option<&mut Task> {
+        match self {
+            TaskType::CronTask(CronTask { task, .. })
+            | TaskType::DateConditionalTask(DateConditionalTask { task, .. })
+            | TaskType::PeriodicTask(PeriodicTask { task, .. }) => Some(task),
+            TaskType::Gulag(_) => None,
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool, default_tz: Tz) -> AnyResult<()> {
+        match self {
+            TaskType::CronTask(ct) => {
+                ct.set_paused(paused, default_tz);
+                Ok(())
+            }
+            TaskType::DateConditionalTask(dct) => {
+                dct.paused = paused;
+                Ok(())
+            }
+            TaskType::PeriodicTask(pt) => pt.set_paused(paused, default_tz),
+            TaskType::Gulag(_) => Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "Gulag sentences can't be paused.",
+            )
+            .into()),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        match self {
+            TaskType::CronTask(CronTask { paused, .. })
+            | TaskType::DateConditionalTask(DateConditionalTask { paused, .. })
+            | TaskType::PeriodicTask(PeriodicTask { paused, .. }) => *paused,
+            TaskType::Gulag(_) => false,
+        }
+    }
+
+    /// Whether the task is done for good and should be removed from the task list. Gulags are
+    /// removed once they've acted instead.
+    pub fn is_finished(&self, default_tz: Tz) -> bool {
+        match self {
+            TaskType::PeriodicTask(pt) => pt.is_finished(default_tz),
+            TaskType::CronTask(_) | TaskType::DateConditionalTask(_) | TaskType::Gulag(_) => false,
+        }
+    }
+
+    /// Gulags are released whenever they're overdue, so they don't have one.
+    pub fn misfire_policy(&self) -> Option<MisfirePolicy> {
+        match self {
+            TaskType::CronTask(CronTask { misfire_policy, .. })
+            | TaskType::DateConditionalTask(DateConditionalTask { misfire_policy, .. })
+            | TaskType::PeriodicTask(PeriodicTask { misfire_policy, .. }) => Some(*misfire_policy),
+            TaskType::Gulag(_) => None,
+        }
+    }
+
+    pub fn misfire_policy_mut(&mut self) -> Option<&mut MisfirePolicy> {
+        match self {
+            TaskType::CronTask(CronTask { misfire_policy, .. })
+            | TaskType::DateConditionalTask(DateConditionalTask { misfire_policy, .. })
+            | TaskType::PeriodicTask(PeriodicTask { misfire_policy, .. }) => Some(misfire_policy),
+            TaskType::Gulag(_) => None,
+        }
+    }
+
+    /// The first time strictly after `after` that the task should act.
+    pub fn next_after(&self, after: DateTime<Utc>, default_tz: Tz) -> Option<DateTime<Utc>> {
+        match self {
+            TaskType::CronTask(ct) => ct.next_after(after, default_tz),
+            TaskType::DateConditionalTask(dct) => dct.next_after(after, default_tz),
+            TaskType::PeriodicTask(pt) => pt.next_after(after, default_tz),
+            TaskType::Gulag(_) => None,
+        }
+    }
+
+    /// Times the task should have acted after `since` and before `now`, oldest first. Stops one
+    /// past `MAX_CATCH_UP_RUNS` so that it's possible to tell that there were more.
+    pub fn missed_between(
+        &self,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+        default_tz: Tz,
+    ) -> Vec<DateTime<Utc>> {
+        let mut missed = Vec::new();
+        let mut after = since;
+        while let Some(next) = self.next_after(after, default_tz) {
+            // Times that happen twice when the clocks go back can resolve to the same UTC time.
+            if next <= after || next >= now || missed.len() > MAX_CATCH_UP_RUNS {
+                break;
+            }
+            missed.push(next);
+            after = next;
+        }
+        missed
+    }
+
+    /// Moves the task past any times it missed so that it doesn't act for them.
+    pub fn skip_missed(&mut self, default_tz: Tz) -> AnyResult<()> {
         match self {
-            TaskType::DateConditionalTask(dct) => dct.list_fmt(),
+            TaskType::CronTask(ct) => {
+                ct.advance(default_tz);
+                Ok(())
+            }
+            TaskType::PeriodicTask(pt) => pt.elapse_past_periods(default_tz),
+            // Date conditions only look for times after now, and overdue sentences are always
+            // released.
+            TaskType::DateConditionalTask(_) | TaskType::Gulag(_) => Ok(()),
+        }
+    }
+
+    /// Carries over what acting moved on, like when the task last acted, from a copy of this task
+    /// that acted while this one was being edited. Everything else about this one is kept.
+    pub fn take_progress(&mut self, acted: &TaskType, default_tz: Tz) {
+        match (&mut *self, acted) {
+            // An edited schedule has already worked out its next time from the present.
+            (TaskType::CronTask(ct), TaskType::CronTask(_))
+                if ct.next.is_some_and(|next| next <= Utc::now()) =>
+            {
+                ct.advance(default_tz);
+            }
+            (TaskType::DateConditionalTask(dct), TaskType::DateConditionalTask(acted)) => {
+                dct.last_acted = dct.last_acted.max(acted.last_acted);
+            }
+            (TaskType::PeriodicTask(pt), TaskType::PeriodicTask(acted)) => {
+                pt.last_sent = pt.last_sent.max(acted.last_sent);
+                pt.runs = pt.runs.max(acted.runs);
+            }
+            _ => {}
+        }
+        if let (Some(task), Some(acted)) = (self.task_mut(), acted.task_ref()) {
+            task.take_progress(acted);
+        }
+    }
+
+    pub fn list_fmt(&self, default_tz: Tz) -> String {
+        match self {
+            TaskType::CronTask(ct) => ct.list_fmt(default_tz),
+            TaskType::DateConditionalTask(dct) => dct.list_fmt(default_tz),
             TaskType::Gulag(g) => g.list_fmt(),
-            TaskType::PeriodicTask(pt) => pt.list_fmt(),
+            TaskType::PeriodicTask(pt) => pt.list_fmt(default_tz),
         }
     }
 }
 
 lazy_static! {
     static ref CTREGEX: Regex =
-        Regex::new(r"=>create_task (.+)(\n\`\`\`json\n(.+\n)+\`\`\`)?").unwrap();
+        Regex::new(r"=>create_task (.+)(\n\`\`\`json\n((?:.+\n)+)\`\`\`)?").unwrap();
 }
 
 #[derive(Parser)]
@@ -94,21 +265,28 @@ pub struct CreateTask {
     cttype: CreateTaskType,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug, Subcommand)]
 #[command(color(ColorChoice::Never), no_binary_name(true))]
 pub enum CreateTaskType {
+    /// Act whenever the date and time match the given conditions
     #[command(name = "date_conditional_task")]
     DateConditionalTask(DateConditionalTask),
+    /// Act repeatedly with a fixed period
     #[command(name = "periodic_task")]
     PeriodicTask(CreatePeriodicTask),
+    /// Act whenever the date and time match a cron expression
+    #[command(name = "cron_task")]
+    CronTask(CreateCronTask),
 }
 
 impl CreateTaskType {
-    pub fn create(self) -> TaskType {
-        match self {
+    pub fn create(self, default_tz: Tz) -> AnyResult<TaskType> {
+        Ok(match self {
             CreateTaskType::DateConditionalTask(dct) => TaskType::DateConditionalTask(dct),
-            CreateTaskType::PeriodicTask(pt) => TaskType::PeriodicTask(pt.create()),
-        }
+            CreateTaskType::PeriodicTask(pt) => TaskType::PeriodicTask(pt.create(default_tz)?),
+            CreateTaskType::CronTask(ct) => TaskType::CronTask(ct.create(default_tz)?),
+        })
     }
 }
 
@@ -129,36 +307,50 @@ where
     I: Iterator,
     <I as Iterator>::Item: Clone + Into<OsString>,
 {
-    CreateTask::try_parse_from(iter)
-        .map_err(Into::into)
-        .map(|ct| ct.cttype)
+    CreateTask::try_parse_from(iter).map(|ct| ct.cttype)
 }
 
 #[command]
 pub async fn create_task(ctx: &Context, message: &Message) -> CommandResult {
+    run_create_task(ctx, &message.into()).await
+}
+
+pub async fn run_create_task(ctx: &Context, invocation: &Invocation<'_>) -> CommandResult {
     println!("CT | Begin handling create task command.");
     let start = Instant::now();
-    if is_administrator(&ctx.http, ctx.data.read().await, message).await? {
+    if is_administrator(&ctx.http, ctx.data.read().await, invocation).await? {
         println!("CT | User has sufficient permissions. Trying to match subcommand.");
-        let matches = get_ctt_matches(message.content.as_str());
+        let matches = match invocation {
+            Invocation::Message(message) => get_ctt_matches(message.content.as_str())
+                .into_iter()
+                .map(|(subcommand_inv, json)| {
+                    let args = subcommand_inv
+                        .split_whitespace()
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+                    (args, json.to_string())
+                })
+                .collect::<Vec<_>>(),
+            Invocation::Interaction { command, .. } => vec![(
+                invocation.args("=>create_task"),
+                find_string_option(&command.data.options, "task").unwrap_or_default(),
+            )],
+        };
         println!("CT | Regex matches: {matches:?}");
         if matches.is_empty() {
             println!("CT | User didn't provide all arguments, or failed to match format.");
-            match message.content.to_lowercase().as_str() {
-                "=>create_task -h" | "=>create_task --help" => {
+            match invocation.text("=>create_task", "").to_lowercase().as_str() {
+                "-h" | "--help" => {
                     let msg = format!(
                         "Error parsing command. Details:\n{}",
                         CREATE_TASK_HELP_MSG.as_str()
                     );
-                    message
-                        .channel_id
-                        .send_message(&ctx.http, |message| message.content(&msg))
-                        .await?;
+                    invocation.say(&ctx.http, &msg).await?;
                     return Ok(());
                 }
                 _ => {
-                    message
-                        .reply(
+                    invocation
+                        .reply_ephemeral(
                             &ctx.http,
                             "Aye, I'll be sure to do nothing.\n\
                             \n\
@@ -170,37 +362,48 @@ pub async fn create_task(ctx: &Context, message: &Message) -> CommandResult {
             }
         } else if matches.len() == 1 {
             println!("CT | User input matched regex once.");
-            let (subcommand_inv, json) = matches[0];
+            let (subcommand_inv, json) = &matches[0];
             println!("CT | Parsing input: {subcommand_inv:?}");
-            let mut subcommand = match try_get_createtask(subcommand_inv.split_whitespace()) {
-                Ok(subcommand) => subcommand.create(),
+            let subcommand = match try_get_createtask(subcommand_inv.iter()) {
+                Ok(subcommand) => subcommand,
                 Err(err) if err.kind() == ErrorKind::DisplayHelp => {
                     println!("CT | User requested help.");
                     let msg = format!("```{err}```");
-                    message.reply(&ctx.http, msg).await?;
+                    invocation.reply_ephemeral(&ctx.http, msg).await?;
                     println!("CT | Elapsed: {:?}", start.elapsed());
                     return Ok(());
                 }
                 Err(err) => {
                     println!("CT | Failed to parse user input. Sending error back.");
                     let msg = format!("Error parsing command. Details:\n```{err}```");
-                    message.reply(&ctx.http, msg).await?;
+                    invocation.reply_ephemeral(&ctx.http, msg).await?;
+                    println!("CT | Elapsed: {:?}", start.elapsed());
+                    return Err(err.into());
+                }
+            };
+            let default_tz = ctx.data.read().await.get::<ConfigKey>().unwrap().time_zone;
+            let mut subcommand = match subcommand.create(default_tz) {
+                Ok(subcommand) => subcommand,
+                Err(err) => {
+                    println!("CT | Failed to create task from user input. Sending error back.");
+                    let msg = format!("Error creating task. Details:\n```{err}```");
+                    invocation.reply_ephemeral(&ctx.http, msg).await?;
                     println!("CT | Elapsed: {:?}", start.elapsed());
                     return Err(err.into());
                 }
             };
             println!(
                 "CT | PS | Successfully parsed task type: {}",
-                subcommand.list_fmt()
+                subcommand.list_fmt(default_tz)
             );
             let task = match serde_json::from_str::<Task>(json) {
                 Ok(task) => task,
                 Err(err) => {
                     println!("CT | Failed to parse JSON into task.");
-                    message
-                        .reply(
+                    invocation
+                        .reply_ephemeral(
                             &ctx.http,
-                            &format!(
+                            format!(
                                 "Failed to parse task from input. Error details:\n```{err}```",
                             ),
                         )
@@ -209,33 +412,31 @@ pub async fn create_task(ctx: &Context, message: &Message) -> CommandResult {
                     return Err(err.into());
                 }
             };
-            println!("CT | PS | Successfully parsed task JSON.");
-            match &mut subcommand {
-                TaskType::DateConditionalTask(DateConditionalTask {
-                    task: default_task, ..
-                })
-                | TaskType::PeriodicTask(PeriodicTask {
-                    task: default_task, ..
-                }) => {
-                    *default_task = task;
-                }
-                TaskType::Gulag(_) => unreachable!(),
+            let checked = task.check(ctx.data.read().await.get::<ConfigKey>().unwrap());
+            if let Err(err) = checked {
+                println!("CT | Task failed validation.");
+                invocation
+                    .reply_ephemeral(&ctx.http, format!("Invalid task: {err}"))
+                    .await?;
+                println!("CT | Elapsed: {:?}", start.elapsed());
+                return Err(err.into());
             }
+            println!("CT | PS | Successfully parsed task JSON.");
+            *subcommand.task_mut().unwrap() = task;
             println!("CT | Assigned task to tasktype.");
-            let _ = &ctx
-                .data
-                .write()
+            ctx.data
+                .read()
                 .await
-                .get_mut::<TaskSenderKey>()
+                .get::<TaskSenderKey>()
                 .unwrap()
-                .send(subcommand)?;
+                .send(TaskMessage::New(Box::new(subcommand)))?;
             println!("CT | Sent task to executor.");
         } else {
             println!("CT | What the fuck: {matches:?}");
         }
     } else {
         println!("CT | User has insufficient permissions.");
-        insufficient_perms(ctx, message).await?;
+        insufficient_perms(ctx, invocation).await?;
     }
     println!("CT | Elapsed: {:?}", start.elapsed());
     Ok(())
@@ -1,8 +1,12 @@
-use super::task::Task;
-use crate::misc::CreateTimePeriod;
+use super::{misfire::MisfirePolicy, task::Task};
+use crate::{
+    cache_keys::ConfigKey,
+    misc::{fmt_local_and_utc, local_to_utc, parse_date_time, CreateTimePeriod, DateTimeArg},
+};
 use anyhow::Result as AnyResult;
 use chrono::{prelude::*, Duration};
-use clap::{ColorChoice, Parser};
+use chrono_tz::Tz;
+use clap::{value_parser, ArgAction, ColorChoice, Parser};
 use serde::{Deserialize, Serialize};
 use serenity::{
     http::client::Http,
@@ -18,22 +22,119 @@ pub struct PeriodicTask {
     pub task: Task,
     pub diff: i64,
     pub last_sent: NaiveDateTime,
+    #[serde(default)]
+    pub time_zone: Option<Tz>,
+    #[serde(default)]
+    pub misfire_policy: MisfirePolicy,
+    #[serde(default)]
+    pub paused: bool,
+    /// How many times the task has acted.
+    #[serde(default)]
+    pub runs: u32,
+    #[serde(default)]
+    pub max_runs: Option<u32>,
+    #[serde(default)]
+    pub until: Option<DateTime<Utc>>,
 }
 
 impl PeriodicTask {
-    pub fn elapse_period(&mut self) -> AnyResult<()> {
+    /// Moves `from` by `diff`. Periods of whole days are counted in local time so that something
+    /// that happens at 9am keeps happening at 9am when the clocks change. Anything shorter is
+    /// counted in absolute time so that an hourly task stays hourly.
+    fn shift(&self, from: NaiveDateTime, diff: Duration, default_tz: Tz) -> Option<DateTime<Utc>> {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        if self.diff % Duration::days(1).num_seconds() == 0 {
+            let local = tz.from_utc_datetime(&from).naive_local();
+            local
+                .checked_add_signed(diff)
+                .map(|shifted| local_to_utc(tz, shifted))
+        } else {
+            from.checked_add_signed(diff)
+                .map(|shifted| Utc.from_utc_datetime(&shifted))
+        }
+    }
+
+    /// When the current period ends.
+    pub fn next_time(&self, default_tz: Tz) -> Option<DateTime<Utc>> {
+        self.shift(self.last_sent, Duration::seconds(self.diff), default_tz)
+    }
+
+    /// Whether the task has used up its runs or gone past its end date. Finished tasks get removed
+    /// from the task list.
+    pub fn is_finished(&self, default_tz: Tz) -> bool {
+        self.max_runs.is_some_and(|max_runs| self.runs >= max_runs)
+            || self
+                .until
+                .is_some_and(|until| self.next_time(default_tz).is_none_or(|next| next > until))
+    }
+
+    pub fn elapse_period(&mut self, default_tz: Tz) -> AnyResult<()> {
         self.last_sent = self
-            .last_sent
-            .checked_add_signed(Duration::seconds(self.diff))
+            .next_time(default_tz)
             .ok_or(IoError::new(
                 ErrorKind::InvalidData,
                 "Advancing a periodic task's dates produced an invalid date.",
-            ))?;
+            ))?
+            .naive_utc();
+        Ok(())
+    }
+
+    /// The first end of a period strictly after `after`, as long as it isn't past the end date.
+    pub fn next_after(&self, after: DateTime<Utc>, default_tz: Tz) -> Option<DateTime<Utc>> {
+        self.next_after_ignoring_end(after, default_tz)
+            .filter(|&next| self.until.is_none_or(|until| next <= until))
+    }
+
+    fn next_after_ignoring_end(
+        &self,
+        after: DateTime<Utc>,
+        default_tz: Tz,
+    ) -> Option<DateTime<Utc>> {
+        if self.diff <= 0 {
+            return None;
+        }
+        if self.diff % Duration::days(1).num_seconds() == 0 {
+            let mut pt = self.clone();
+            loop {
+                let next = pt.next_time(default_tz)?;
+                if next > after {
+                    return Some(next);
+                }
+                pt.last_sent = next.naive_utc();
+            }
+        } else {
+            let elapsed = after
+                .naive_utc()
+                .signed_duration_since(self.last_sent)
+                .num_seconds();
+            let periods = if elapsed < 0 {
+                1
+            } else {
+                elapsed / self.diff + 1
+            };
+            self.last_sent
+                .checked_add_signed(Duration::seconds(self.diff.checked_mul(periods)?))
+                .map(|next| Utc.from_utc_datetime(&next))
+        }
+    }
+
+    pub fn elapse_past_periods(&mut self, default_tz: Tz) -> AnyResult<()> {
+        while self
+            .next_time(default_tz)
+            .is_some_and(|next| next <= Utc::now())
+        {
+            self.elapse_period(default_tz)?;
+        }
         Ok(())
     }
 
-    pub fn time_to_act(&self) -> bool {
-        Utc::now().naive_utc().signed_duration_since(self.last_sent) >= Duration::seconds(self.diff)
+    pub fn set_paused(&mut self, paused: bool, default_tz: Tz) -> AnyResult<()> {
+        if self.paused && !paused {
+            // Don't make up for the periods that passed while paused.
+            self.elapse_past_periods(default_tz)?;
+        }
+        self.paused = paused;
+        Ok(())
     }
 
     pub async fn act(
@@ -41,17 +142,29 @@ impl PeriodicTask {
         data: &Arc<RwLock<TypeMap>>,
         http: &impl AsRef<Http>,
     ) -> AnyResult<()> {
+        self.runs = self.runs.saturating_add(1);
         self.task.act(data, http).await?;
-        while Utc::now().naive_utc().signed_duration_since(self.last_sent)
-            >= Duration::seconds(self.diff)
-        {
-            self.elapse_period()?;
-        }
-        Ok(())
+        let default_tz = data.read().await.get::<ConfigKey>().unwrap().time_zone;
+        self.elapse_past_periods(default_tz)
     }
 
-    pub fn list_fmt(&self) -> String {
-        format!(" PT | {} | Last: {}", self.task.list_fmt(), self.last_sent)
+    pub fn list_fmt(&self, default_tz: Tz) -> String {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        let mut limits = String::new();
+        if let Some(max_runs) = self.max_runs {
+            limits.push_str(&format!(" | Runs: {}/{max_runs}", self.runs));
+        }
+        if let Some(until) = self.until {
+            limits.push_str(&format!(" | Until: {}", fmt_local_and_utc(until, tz)));
+        }
+        format!(
+            " PT | {} | Next: {}{limits} | Misfire: {}{}",
+            self.task.list_fmt(),
+            self.next_time(default_tz)
+                .map_or_else(|| "never".into(), |next| fmt_local_and_utc(next, tz)),
+            self.misfire_policy,
+            if self.paused { " | PAUSED" } else { "" },
+        )
     }
 }
 
@@ -59,23 +172,115 @@ impl PeriodicTask {
 #[command(
     name = "Create Periodic Task",
     color(ColorChoice::Never),
-    no_binary_name(true)
+    no_binary_name(true),
+    disable_help_flag(true)
 )]
 pub struct CreatePeriodicTask {
     #[arg(skip)]
     pub task: Task,
-    #[arg(long = "start", name = "start_sending")]
-    pub start: NaiveDateTime,
+    /// Date and time of the first run, local unless an offset is given. Defaults to one period
+    /// from now
+    #[arg(long = "start", name = "start_sending", value_parser = parse_date_time)]
+    pub start: Option<DateTimeArg>,
     #[command(flatten)]
     pub duration: CreateTimePeriod,
+    /// Date and time after which the task stops and is removed, local unless an offset is given
+    #[arg(long = "until", name = "until", value_parser = parse_date_time)]
+    pub until: Option<DateTimeArg>,
+    /// Number of runs after which the task stops and is removed
+    #[arg(long = "count", name = "count", value_parser = value_parser!(u32).range(1..))]
+    pub count: Option<u32>,
+    /// IANA time zone to count whole days in instead of the configured one
+    #[arg(long = "tz", name = "time_zone")]
+    pub time_zone: Option<Tz>,
+    /// What to do about runs missed while offline: skip, once, all, or grace:<time> to run once
+    /// if the last one was missed by at most that long (e.g. grace:30m)
+    #[arg(long = "misfire", name = "misfire_policy", default_value = "once")]
+    pub misfire_policy: MisfirePolicy,
+    // `-h` is taken by `--hours`.
+    /// Print help
+    #[arg(long = "help", action = ArgAction::Help)]
+    help: Option<bool>,
 }
 
 impl CreatePeriodicTask {
-    pub fn create(self) -> PeriodicTask {
-        PeriodicTask {
+    pub fn create(self, default_tz: Tz) -> AnyResult<PeriodicTask> {
+        let tz = self.time_zone.unwrap_or(default_tz);
+        let diff = self.duration.to_duration(tz);
+        if diff <= Duration::zero() {
+            return Err(
+                IoError::new(ErrorKind::InvalidInput, "The period has to be positive.").into(),
+            );
+        }
+        let mut pt = PeriodicTask {
             task: self.task,
-            diff: self.duration.to_duration().num_seconds(),
+            diff: diff.num_seconds(),
             last_sent: Utc::now().naive_utc(),
+            time_zone: self.time_zone,
+            misfire_policy: self.misfire_policy,
+            paused: false,
+            runs: 0,
+            max_runs: self.count,
+            until: self.until.map(|until| until.to_utc(tz)),
+        };
+        if let Some(start) = self.start {
+            // Tasks act one period after `last_sent`, so the first run is at the start time if
+            // `last_sent` is one period before it.
+            let start = start.to_utc(tz).naive_utc();
+            pt.last_sent = pt
+                .shift(start, -diff, default_tz)
+                .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Invalid start time."))?
+                .naive_utc();
+        }
+        if pt.is_finished(default_tz) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "The end date is before the first run, so the task would never act.",
+            )
+            .into());
         }
+        Ok(pt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CreatePeriodicTask;
+    use chrono::{Duration, Utc};
+    use chrono_tz::Tz;
+    use clap::Parser;
+
+    #[test]
+    fn test_start_and_limits() {
+        let start = (Utc::now() + Duration::days(2)).naive_utc().date();
+        let create = |args: &str| {
+            CreatePeriodicTask::try_parse_from(args.split_whitespace())
+                .unwrap()
+                .create(Tz::Europe__London)
+        };
+        let mut pt = create(&format!("-d 1 --start {start}T09:00:00 --count 2")).unwrap();
+        let first = pt.next_time(Tz::UTC).unwrap();
+        assert_eq!(
+            first.with_timezone(&Tz::Europe__London).naive_local(),
+            start.and_hms_opt(9, 0, 0).unwrap()
+        );
+        assert!(!pt.is_finished(Tz::UTC));
+        pt.runs = 2;
+        assert!(pt.is_finished(Tz::UTC));
+        let pt = create(&format!(
+            "-h 1 --start {start}T09:00:00Z --until {start}T11:30:00Z"
+        ))
+        .unwrap();
+        // Runs at 09:00, 10:00 and 11:00, but not 12:00.
+        let third = pt.next_time(Tz::UTC).unwrap() + Duration::hours(2);
+        assert_eq!(
+            pt.next_after(third - Duration::seconds(1), Tz::UTC),
+            Some(third)
+        );
+        assert_eq!(pt.next_after(third, Tz::UTC), None);
+        assert!(create(&format!(
+            "-d 1 --start {start}T09:00:00 --until {start}T08:00:00"
+        ))
+        .is_err());
     }
 }
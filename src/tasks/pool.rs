@@ -0,0 +1,94 @@
+use super::message::MessageType;
+use anyhow::Result as AnyResult;
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// One of the messages a pool task can send.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PoolMessage {
+    /// How likely this message is to be picked compared to the others.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub message: MessageType,
+    #[serde(default)]
+    pub upload_file: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+pub fn validate_pool(pool: &[PoolMessage]) -> AnyResult<()> {
+    if pool.iter().all(|entry| entry.weight == 0) {
+        return Err(IoError::new(
+            IoErrorKind::InvalidInput,
+            "The pool needs at least one message with a weight above 0.",
+        )
+        .into());
+    }
+    pool.iter().try_for_each(|entry| entry.message.validate())
+}
+
+/// Picks the index of the next message to send, avoiding the last `no_repeat` picks in `history`
+/// (most recent last). If that would rule out every message, the oldest of those picks become
+/// allowed again, so a window as big as the pool still never repeats the last pick.
+pub fn pick(
+    pool: &[PoolMessage],
+    history: &[usize],
+    no_repeat: usize,
+    rng: &mut impl Rng,
+) -> Option<usize> {
+    let pickable = pool.iter().filter(|entry| entry.weight > 0).count();
+    let window = no_repeat.min(pickable.saturating_sub(1));
+    let recent = &history[history.len().saturating_sub(window)..];
+    let weights = pool.iter().enumerate().map(
+        |(i, entry)| {
+            if recent.contains(&i) {
+                0
+            } else {
+                entry.weight
+            }
+        },
+    );
+    let index = WeightedIndex::new(weights).ok()?;
+    Some(index.sample(rng))
+}
+
+/// Adds a pick to the history, keeping only as much of it as the no-repeat window needs.
+pub fn remember(history: &mut Vec<usize>, picked: usize, no_repeat: usize) {
+    history.push(picked);
+    let excess = history.len().saturating_sub(no_repeat.max(1));
+    let _ = history.drain(..excess);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pick, remember, PoolMessage};
+    use crate::tasks::message::MessageType;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_pick_without_repeats() {
+        let entry = |weight| PoolMessage {
+            weight,
+            message: MessageType::Plain {
+                content: "Hi, Reginald".into(),
+            },
+            upload_file: None,
+        };
+        let pool = vec![entry(1), entry(5), entry(0), entry(2)];
+        let mut history = Vec::new();
+        for _ in 0..50 {
+            let picked = pick(&pool, &history, 2, &mut thread_rng()).unwrap();
+            // Never the zero weight one, and never one of the last two.
+            assert_ne!(picked, 2);
+            assert!(!history.contains(&picked));
+            remember(&mut history, picked, 2);
+            assert!(history.len() <= 2);
+        }
+        // With a window bigger than the pool, whichever was sent longest ago is next.
+        assert_eq!(pick(&pool, &[3, 0, 1], 10, &mut thread_rng()), Some(3));
+        assert_eq!(pick(&[entry(0)], &[], 1, &mut thread_rng()), None);
+    }
+}
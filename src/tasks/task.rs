@@ -1,14 +1,29 @@
-use super::message::MessageType;
-use crate::cache_keys::ConfigKey;
+use super::{
+    components::has_replies,
+    message::MessageType,
+    pool::{self, PoolMessage},
+    template::TemplateContext,
+};
+use crate::{
+    activity::{post_report, DEFAULT_TEMPLATE},
+    appearance::{apply_theme, check_image, check_themes, set_avatar, AvatarChange, Presence},
+    cache_keys::{ConfigKey, StorageKey},
+    config::Config,
+    leaderboard::{update_leaderboard, LeaderboardId},
+};
 use anyhow::Result as AnyResult;
+use rand::thread_rng;
 use serde::{Deserialize, Serialize};
 use serenity::{
     http::client::Http,
     model::id::ChannelId,
     prelude::{RwLock, TypeMap},
-    utils::read_image,
 };
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Task {
@@ -17,99 +32,288 @@ pub enum Task {
         message: MessageType,
         upload_file: Option<String>,
     },
+    /// Sends one of several messages, picked at random by weight.
+    SendFromPool {
+        send_to: ChannelId,
+        pool: Vec<PoolMessage>,
+        /// How many of the most recently sent messages can't be sent again yet.
+        #[serde(default)]
+        no_repeat: usize,
+        /// Which messages were sent, most recent last. Kept with the task so it survives
+        /// restarts.
+        #[serde(default)]
+        history: Vec<usize>,
+    },
     UpdateAppearance {
         new_name: String,
         new_icon_filename: String,
     },
     ResetAppearance,
+    /// Changes the bot to look like one of the themes in the config.
+    ApplyTheme {
+        theme: String,
+    },
+    /// Applies the next of several themes from the config each time, starting over after the
+    /// last one.
+    RotateThemes {
+        themes: Vec<String>,
+        /// Position in `themes` of the one to apply next.
+        #[serde(default)]
+        next: usize,
+    },
+    /// Changes the bot's online status and activity. The activity text can have placeholders.
+    SetPresence(Presence),
+    /// Recomputes and posts a leaderboard.
+    UpdateLeaderboard {
+        id: LeaderboardId,
+    },
+    /// Posts how many messages were sent in each channel and by each user since the last report,
+    /// and starts counting again.
+    ActivityReport {
+        send_to: ChannelId,
+        /// Report text, with placeholders for the counts. Uses a default report if not given.
+        #[serde(default)]
+        template: Option<String>,
+        /// How many channels and users to list.
+        #[serde(default = "default_report_top")]
+        top: usize,
+    },
+}
+
+fn default_report_top() -> usize {
+    5
 }
 
 impl Task {
-    pub async fn act(&self, data: &Arc<RwLock<TypeMap>>, http: &impl AsRef<Http>) -> AnyResult<()> {
+    pub async fn act(
+        &mut self,
+        data: &Arc<RwLock<TypeMap>>,
+        http: &impl AsRef<Http>,
+    ) -> AnyResult<()> {
         match self {
             Task::SendMessage {
                 send_to,
                 message,
                 upload_file,
             } => {
-                let mut create_message = message.build();
-                if message.is_embed() {
-                    let icon_url = http
-                        .as_ref()
-                        .get_current_user()
-                        .await?
-                        .avatar_url()
-                        .unwrap();
-                    create_message.embed(|embed| embed.footer(|footer| footer.icon_url(icon_url)));
-                }
-                if let Some(filename) = upload_file.as_ref() {
-                    let files_dir = data
-                        .read()
-                        .await
-                        .get::<ConfigKey>()
-                        .unwrap()
-                        .files_dir
-                        .clone();
-                    let mut base = PathBuf::from(&files_dir);
-                    base.push(filename.as_str());
-                    create_message.add_file(base.as_path());
-                    let _ = send_to.send_message(&http, |_| &mut create_message).await?;
-                } else {
-                    let _ = send_to.send_message(&http, |_| &mut create_message).await?;
-                }
+                send_message(data, http.as_ref(), *send_to, message, upload_file).await?;
+            }
+            Task::SendFromPool {
+                send_to,
+                pool,
+                no_repeat,
+                history,
+            } => {
+                let picked =
+                    pool::pick(pool, history, *no_repeat, &mut thread_rng()).ok_or_else(|| {
+                        IoError::new(IoErrorKind::InvalidData, "The pool has nothing to send.")
+                    })?;
+                println!("TL | PL | Picked message {picked} from the pool.");
+                let PoolMessage {
+                    message,
+                    upload_file,
+                    ..
+                } = &pool[picked];
+                send_message(data, http.as_ref(), *send_to, message, upload_file).await?;
+                pool::remember(history, picked, *no_repeat);
             }
             Task::UpdateAppearance {
                 new_name,
                 new_icon_filename,
             } => {
-                let (guild_id, files_dir) = {
-                    let context = data.read().await;
-                    let config = context.get::<ConfigKey>().unwrap();
-                    (config.guild_id, config.files_dir.clone())
-                };
+                let guild_id = data.read().await.get::<ConfigKey>().unwrap().guild_id;
                 http.as_ref()
                     .edit_nickname(guild_id.into(), Some(new_name.as_str()))
                     .await?;
-                let avatar_b64 = read_image(format!(
-                    "{}/{new_icon_filename}",
-                    files_dir.trim_end_matches('/'),
-                ))?;
-                http.as_ref()
-                    .get_current_user()
-                    .await?
-                    .edit(&http, |user| user.avatar(Some(&avatar_b64)))
-                    .await?;
+                set_avatar(data, http.as_ref(), new_icon_filename).await?;
             }
             Task::ResetAppearance => {
-                let (guild_id, files_dir, filename) = {
+                let (guild_id, filename) = {
                     let context = data.read().await;
                     let config = context.get::<ConfigKey>().unwrap();
-                    (
-                        config.guild_id,
-                        config.files_dir.clone(),
-                        config.icon_filename.clone(),
-                    )
+                    (config.guild_id, config.icon_filename.clone())
                 };
                 http.as_ref().edit_nickname(guild_id.into(), None).await?;
-                let avatar_b64 =
-                    read_image(format!("{}/{filename}", files_dir.trim_end_matches('/'),))?;
-                http.as_ref()
-                    .get_current_user()
-                    .await?
-                    .edit(&http, |user| user.avatar(Some(&avatar_b64)))
-                    .await?;
+                set_avatar(data, http.as_ref(), &filename).await?;
+            }
+            Task::ApplyTheme { theme } => {
+                apply_theme(data, http.as_ref(), theme).await?;
+            }
+            Task::RotateThemes { themes, next } => {
+                let theme = themes.get(*next % themes.len().max(1)).ok_or_else(|| {
+                    IoError::new(IoErrorKind::InvalidData, "The rotation has no themes.")
+                })?;
+                // The same theme is applied again next time if its avatar has to wait, rather than
+                // skipping the avatar altogether.
+                if apply_theme(data, http.as_ref(), theme).await? == AvatarChange::Deferred {
+                    println!("TL | Avatar of theme '{theme}' put off. Staying on it.");
+                } else {
+                    *next = (*next + 1) % themes.len();
+                }
+            }
+            Task::SetPresence(presence) => {
+                presence.apply(data, http.as_ref()).await?;
+            }
+            Task::UpdateLeaderboard { id } => {
+                update_leaderboard(data, http.as_ref(), *id).await?;
+            }
+            Task::ActivityReport {
+                send_to,
+                template,
+                top,
+            } => {
+                let template = template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+                post_report(data, http.as_ref(), *send_to, template, *top).await?;
             }
         }
         Ok(())
     }
 
+    /// Carries over what acting moved on from a copy of this task that acted. Nothing is carried
+    /// over if the task was changed into something the progress doesn't apply to.
+    pub fn take_progress(&mut self, acted: &Task) {
+        match (self, acted) {
+            (
+                Task::SendFromPool { pool, history, .. },
+                Task::SendFromPool {
+                    pool: acted_pool,
+                    history: acted_history,
+                    ..
+                },
+            ) if pool.len() == acted_pool.len() => history.clone_from(acted_history),
+            (
+                Task::RotateThemes { themes, next },
+                Task::RotateThemes {
+                    themes: acted_themes,
+                    next: acted_next,
+                },
+            ) if themes == acted_themes => *next = *acted_next,
+            _ => {}
+        }
+    }
+
+    /// Checks the task can act as written, as far as that can be told without acting.
+    pub fn validate(&self) -> AnyResult<()> {
+        match self {
+            Task::SendMessage { message, .. } => message.validate(),
+            Task::SendFromPool { pool, .. } => pool::validate_pool(pool),
+            Task::SetPresence(presence) => presence.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs every check that can be made on a task before it acts, so that creating and editing
+    /// tasks catch the same mistakes.
+    pub fn check(&self, config: &Config) -> AnyResult<()> {
+        self.validate()?;
+        let missing = self.missing_files(&config.files_dir);
+        if !missing.is_empty() {
+            return Err(IoError::new(
+                IoErrorKind::NotFound,
+                format!(
+                    "These files aren't in the files directory: {}",
+                    missing.join(", ")
+                )
+                .as_str(),
+            )
+            .into());
+        }
+        self.validate_appearance(config)
+    }
+
+    /// Files the task needs from the files directory that aren't there.
+    pub fn missing_files(&self, files_dir: &str) -> Vec<String> {
+        let filenames: Vec<&String> = match self {
+            Task::SendMessage {
+                message,
+                upload_file,
+                ..
+            } => upload_file.iter().chain(message.attachments()).collect(),
+            Task::SendFromPool { pool, .. } => pool
+                .iter()
+                .flat_map(|entry| entry.upload_file.iter().chain(entry.message.attachments()))
+                .collect(),
+            Task::UpdateAppearance {
+                new_icon_filename, ..
+            } => vec![new_icon_filename],
+            _ => Vec::new(),
+        };
+        filenames
+            .into_iter()
+            .filter(|filename| !Path::new(files_dir).join(filename).is_file())
+            .cloned()
+            .collect()
+    }
+
+    /// Checks that the images the task would change the bot's appearance to are usable, and that
+    /// the themes it names are in the config.
+    pub fn validate_appearance(&self, config: &Config) -> AnyResult<()> {
+        match self {
+            Task::UpdateAppearance {
+                new_icon_filename, ..
+            } => check_image(&config.files_dir, new_icon_filename),
+            Task::ApplyTheme { theme } => check_themes(&config.themes, std::slice::from_ref(theme)),
+            Task::RotateThemes { themes, .. } => {
+                if themes.is_empty() {
+                    return Err(IoError::new(
+                        IoErrorKind::InvalidInput,
+                        "The rotation needs at least one theme.",
+                    )
+                    .into());
+                }
+                check_themes(&config.themes, themes)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn list_fmt(&self) -> &str {
         match self {
             Task::SendMessage { .. } => "  SEND",
+            Task::SendFromPool { .. } => "  POOL",
             Task::UpdateAppearance { .. } => "UPDATE",
             Task::ResetAppearance => " RESET",
+            Task::ApplyTheme { .. } => " THEME",
+            Task::RotateThemes { .. } => "ROTATE",
+            Task::SetPresence(_) => "STATUS",
+            Task::UpdateLeaderboard { .. } => " BOARD",
+            Task::ActivityReport { .. } => "REPORT",
+        }
+    }
+}
+
+/// Sends a message with its placeholders filled in and its files attached.
+async fn send_message(
+    data: &Arc<RwLock<TypeMap>>,
+    http: &Http,
+    send_to: ChannelId,
+    message: &MessageType,
+    upload_file: &Option<String>,
+) -> AnyResult<()> {
+    let context = TemplateContext::gather(data, http, message.uses_member_count()).await?;
+    let icon_url = http.get_current_user().await?.avatar_url();
+    let mut create_message = message.build(&context, icon_url.as_deref());
+    let files_dir = data
+        .read()
+        .await
+        .get::<ConfigKey>()
+        .unwrap()
+        .files_dir
+        .clone();
+    let paths = upload_file
+        .iter()
+        .chain(message.attachments())
+        .map(|filename| Path::new(&files_dir).join(filename))
+        .collect::<Vec<_>>();
+    create_message.add_files(paths.iter().map(PathBuf::as_path));
+    let sent = send_to.send_message(http, |_| &mut create_message).await?;
+    if let MessageType::Rich(message) = message {
+        if has_replies(&message.components) {
+            let storage = data.read().await.get::<StorageKey>().unwrap().clone();
+            storage.insert_components(sent.id, &message.components)?;
         }
     }
+    Ok(())
 }
 
 impl Default for Task {
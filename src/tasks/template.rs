@@ -0,0 +1,266 @@
+use crate::{
+    cache_keys::{ConfigKey, StorageKey, TasksKey},
+    tasks::TaskType,
+};
+use anyhow::Result as AnyResult;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rand::{seq::SliceRandom, thread_rng};
+use serenity::{
+    http::Http,
+    model::id::{ChannelId, RoleId, UserId},
+    prelude::{RwLock, TypeMap},
+};
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    sync::Arc,
+};
+
+// Message text can have placeholders in braces that are filled in when the message is sent:
+//
+// - `{date}` and `{weekday}`: today, in the configured time zone
+// - `{member_count}`: how many members the server has
+// - `{gulag_count}`: how many people are currently in the gulag
+// - `{random:a|b|c}`: one of the options, picked at random each time
+// - `{activity.<channel>}`: messages sent in the channel since the last activity report
+// - `{user:<id>}`, `{role:<id>}`, `{channel:<id>}`: mentions
+//
+// `{{` and `}}` are literal braces.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Date,
+    Weekday,
+    MemberCount,
+    GulagCount,
+    Random(Vec<String>),
+    Activity(ChannelId),
+    User(UserId),
+    Role(RoleId),
+    Channel(ChannelId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// Everything placeholders can be filled in with.
+#[derive(Clone, Debug)]
+pub struct TemplateContext {
+    pub now: DateTime<Tz>,
+    pub member_count: Option<u64>,
+    pub gulag_count: usize,
+    pub activity: HashMap<ChannelId, u64>,
+}
+
+impl TemplateContext {
+    /// Looks up what the placeholders need. The member count takes a request to Discord, so it's
+    /// only fetched if `with_member_count` is set.
+    pub async fn gather(
+        data: &Arc<RwLock<TypeMap>>,
+        http: &Http,
+        with_member_count: bool,
+    ) -> AnyResult<Self> {
+        let (guild_id, tz, gulag_count, storage) = {
+            let context_data = data.read().await;
+            let config = context_data.get::<ConfigKey>().unwrap();
+            let gulag_count = context_data
+                .get::<TasksKey>()
+                .unwrap()
+                .values()
+                .filter(|task| matches!(task, TaskType::Gulag(_)))
+                .count();
+            (
+                config.guild_id,
+                config.time_zone,
+                gulag_count,
+                context_data.get::<StorageKey>().unwrap().clone(),
+            )
+        };
+        let member_count = if with_member_count {
+            http.get_guild_with_counts(guild_id.into())
+                .await?
+                .approximate_member_count
+        } else {
+            None
+        };
+        Ok(TemplateContext {
+            now: Utc::now().with_timezone(&tz),
+            member_count,
+            gulag_count,
+            activity: storage.activity()?.channels.into_iter().collect(),
+        })
+    }
+}
+
+/// Checks that every placeholder in the template is one that can be filled in.
+pub fn validate(template: &str) -> AnyResult<()> {
+    parse(template)
+        .map(|_| ())
+        .map_err(|why| IoError::new(IoErrorKind::InvalidInput, why.as_str()).into())
+}
+
+/// Whether the template needs the server's member count.
+pub fn uses_member_count(template: &str) -> bool {
+    parse(template)
+        .is_ok_and(|pieces| pieces.contains(&Piece::Placeholder(Placeholder::MemberCount)))
+}
+
+/// Fills in the template's placeholders. Text that doesn't parse as a template, like a message
+/// written before templates existed, is sent as it is.
+pub fn render(template: &str, context: &TemplateContext) -> String {
+    let pieces = match parse(template) {
+        Ok(pieces) => pieces,
+        Err(_) => return template.to_string(),
+    };
+    pieces
+        .into_iter()
+        .map(|piece| match piece {
+            Piece::Text(text) => text,
+            Piece::Placeholder(placeholder) => fill(&placeholder, context),
+        })
+        .collect()
+}
+
+fn fill(placeholder: &Placeholder, context: &TemplateContext) -> String {
+    match placeholder {
+        Placeholder::Date => context.now.format("%Y-%m-%d").to_string(),
+        Placeholder::Weekday => context.now.format("%A").to_string(),
+        Placeholder::MemberCount => context
+            .member_count
+            .map_or_else(|| "?".to_string(), |count| count.to_string()),
+        Placeholder::GulagCount => context.gulag_count.to_string(),
+        Placeholder::Random(options) => options
+            .choose(&mut thread_rng())
+            .cloned()
+            .unwrap_or_default(),
+        Placeholder::Activity(channel) => context
+            .activity
+            .get(channel)
+            .copied()
+            .unwrap_or(0)
+            .to_string(),
+        Placeholder::User(user) => format!("<@{user}>"),
+        Placeholder::Role(role) => format!("<@&{role}>"),
+        Placeholder::Channel(channel) => format!("<#{channel}>"),
+    }
+}
+
+fn parse(template: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                let _ = chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                let _ = chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("'{{{name}' is missing its closing brace.")),
+                    }
+                }
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(Piece::Placeholder(parse_placeholder(&name)?));
+            }
+            '}' => {
+                return Err(
+                    "There's a '}' without a '{' before it. Use '}}' for a literal one.".into(),
+                )
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+fn parse_placeholder(name: &str) -> Result<Placeholder, String> {
+    let id_error = |kind: &str, id: &str| format!("'{id}' in '{{{name}}}' isn't a {kind}.");
+    if let Some(options) = name.strip_prefix("random:") {
+        return Ok(Placeholder::Random(
+            options.split('|').map(String::from).collect(),
+        ));
+    }
+    if let Some(channel) = name.strip_prefix("activity.") {
+        return channel
+            .parse()
+            .map(Placeholder::Activity)
+            .map_err(|_| id_error("channel", channel));
+    }
+    if let Some(user) = name.strip_prefix("user:") {
+        return user
+            .parse()
+            .map(Placeholder::User)
+            .map_err(|_| id_error("user", user));
+    }
+    if let Some(role) = name.strip_prefix("role:") {
+        return role
+            .parse()
+            .map(Placeholder::Role)
+            .map_err(|_| id_error("role", role));
+    }
+    if let Some(channel) = name.strip_prefix("channel:") {
+        return channel
+            .parse()
+            .map(Placeholder::Channel)
+            .map_err(|_| id_error("channel", channel));
+    }
+    match name {
+        "date" => Ok(Placeholder::Date),
+        "weekday" => Ok(Placeholder::Weekday),
+        "member_count" => Ok(Placeholder::MemberCount),
+        "gulag_count" => Ok(Placeholder::GulagCount),
+        _ => Err(format!("There's no placeholder called '{{{name}}}'.")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, validate, TemplateContext};
+    use chrono::TimeZone;
+    use chrono_tz::Tz;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_render_template() {
+        let context = TemplateContext {
+            now: Tz::UTC.with_ymd_and_hms(2022, 3, 4, 12, 0, 0).unwrap(),
+            member_count: Some(120),
+            gulag_count: 2,
+            activity: HashMap::from([(5.into(), 40)]),
+        };
+        assert_eq!(
+            render(
+                "{weekday} {date}: {member_count} members, {gulag_count} jailed, {activity.5} \
+                 messages in {channel:5}, {activity.6} in {channel:6}. Hi {user:7} {role:8} {{ok}}",
+                &context
+            ),
+            "Friday 2022-03-04: 120 members, 2 jailed, 40 messages in <#5>, 0 in <#6>. Hi <@7> \
+             <@&8> {ok}"
+        );
+        assert!(["a", "b"].contains(&render("{random:a|b}", &context).as_str()));
+        assert!(validate("{random:a|b} {activity.<#5>}").is_ok());
+        assert!(validate("{nope}").is_err());
+        assert!(validate("{date").is_err());
+        assert!(validate("{activity.general}").is_err());
+        // Anything that isn't a valid template is sent unchanged.
+        assert_eq!(render("{nope}", &context), "{nope}");
+    }
+}